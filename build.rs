@@ -0,0 +1,27 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        let file_descriptors = protox::compile(["bip300301_service.proto"], ["proto"])?;
+        // The messages are hand-written in `src/proto.rs`, only the service
+        // and its requests and responses are generated.
+        let mut builder = tonic_build::configure();
+        for message in [
+            "CoinbaseMessage",
+            "M1ProposeSidechain",
+            "M2AckSidechain",
+            "M3ProposeBundle",
+            "M4AckBundles",
+            "M4Kind",
+            "M7BmmAccept",
+            "M8BmmRequest",
+        ] {
+            builder = builder.extern_path(
+                format!(".bip300301.{message}"),
+                format!("crate::proto::{message}"),
+            );
+        }
+        builder.compile_fds(file_descriptors)?;
+    }
+    Ok(())
+}
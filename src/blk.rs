@@ -0,0 +1,332 @@
+//! Scanning Bitcoin Core `blk*.dat` files for drivechain messages without
+//! deserializing whole blocks.
+//!
+//! Since Bitcoin Core 28, block files are XORed with the 8-byte key in
+//! `blocks/xor.dat`. The file scanners read the key from next to the block file
+//! and undo it; [`raw_blocks`] and [`scan_raw_block`] take plain bytes, see
+//! [`deobfuscate`].
+
+use std::{ops::Range, path::Path};
+
+use bitcoin::{hashes::Hash, Amount, BlockHash, Script};
+use byteorder::{ByteOrder, LittleEndian};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    messages::{match_tag, sha256d, M8BmmRequest, MessageKind},
+    parser::{parse_m8_bmm_request, Bip300Error, SpecVersion},
+    scan::{scan_coinbase_output, CoinbaseScanResult},
+};
+
+const HEADER_LEN: usize = 80;
+
+/// The file in Bitcoin Core's `blocks` directory holding the block file XOR key.
+pub const XOR_KEY_FILE: &str = "xor.dat";
+
+/// Bytes in a block file XOR key.
+pub const XOR_KEY_LEN: usize = 8;
+
+#[derive(Debug, Diagnostic, Error)]
+pub enum BlkError {
+    #[error("failed to read block file")]
+    Io(#[from] std::io::Error),
+    #[error(
+        "unexpected network magic at offset {offset}; files from Bitcoin Core 28 or \
+         later are XORed with the key in blocks/xor.dat"
+    )]
+    BadMagic { offset: usize },
+    #[error("xor.dat holds {len} bytes, expected 8")]
+    BadXorKey { len: usize },
+    #[error("data ends unexpectedly at offset {offset}")]
+    Truncated { offset: usize },
+    #[error("invalid segwit flag at offset {offset}")]
+    InvalidSegwitFlag { offset: usize },
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BlkError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(BlkError::Truncated { offset: self.pos })?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), BlkError> {
+        self.take(len).map(|_| ())
+    }
+
+    fn compact_size(&mut self) -> Result<u64, BlkError> {
+        let compact_size = match self.take(1)?[0] {
+            0xFD => LittleEndian::read_u16(self.take(2)?) as u64,
+            0xFE => LittleEndian::read_u32(self.take(4)?) as u64,
+            0xFF => LittleEndian::read_u64(self.take(8)?),
+            len => len as u64,
+        };
+        Ok(compact_size)
+    }
+
+    fn length(&mut self) -> Result<usize, BlkError> {
+        let offset = self.pos;
+        usize::try_from(self.compact_size()?).map_err(|_| BlkError::Truncated { offset })
+    }
+}
+
+/// The XOR key in `blocks_dir`, or `None` if there is no key file, as before
+/// Bitcoin Core 28. An all-zero key means the files aren't obfuscated.
+pub fn read_xor_key(blocks_dir: impl AsRef<Path>) -> Result<Option<[u8; XOR_KEY_LEN]>, BlkError> {
+    let key = match std::fs::read(blocks_dir.as_ref().join(XOR_KEY_FILE)) {
+        Ok(key) => key,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let len = key.len();
+    key.try_into()
+        .map(Some)
+        .map_err(|_| BlkError::BadXorKey { len })
+}
+
+/// Undo the XOR obfuscation of a whole block file read into `data`, in place.
+pub fn deobfuscate(data: &mut [u8], key: [u8; XOR_KEY_LEN]) {
+    deobfuscate_at(data, key, 0);
+}
+
+/// Undo the XOR obfuscation of the bytes of a block file starting at `offset`,
+/// in place. The key cycles from the start of the file, so a part read on its own
+/// needs its offset.
+pub fn deobfuscate_at(data: &mut [u8], key: [u8; XOR_KEY_LEN], offset: usize) {
+    if key == [0; XOR_KEY_LEN] {
+        return;
+    }
+    let key = key.iter().cycle().skip(offset % XOR_KEY_LEN);
+    for (byte, key) in data.iter_mut().zip(key) {
+        *byte ^= key;
+    }
+}
+
+/// The XOR key for the block file at `path`, read from the directory it is in.
+fn xor_key_for(path: &Path) -> Result<Option<[u8; XOR_KEY_LEN]>, BlkError> {
+    read_xor_key(path.parent().unwrap_or(Path::new("")))
+}
+
+/// Split the contents of a block file into raw blocks. `magic` is the network
+/// magic each block is prefixed with, e.g. `Network::Bitcoin.magic().to_bytes()`.
+/// `data` must already be de-obfuscated, see [`deobfuscate`].
+pub fn raw_blocks(data: &[u8], magic: [u8; 4]) -> Result<Vec<&[u8]>, BlkError> {
+    let ranges = raw_block_ranges(data, magic, None)?;
+    Ok(ranges.into_iter().map(|range| &data[range]).collect())
+}
+
+/// Where each block is in the contents of a block file. With `key`, `data` is
+/// still obfuscated, and only the 8 bytes framing each block are de-obfuscated
+/// to find it; the blocks themselves are left for the caller to de-obfuscate, see
+/// [`deobfuscate_at`].
+pub fn raw_block_ranges(
+    data: &[u8],
+    magic: [u8; 4],
+    key: Option<[u8; XOR_KEY_LEN]>,
+) -> Result<Vec<Range<usize>>, BlkError> {
+    let mut cursor = Cursor::new(data);
+    let mut blocks = vec![];
+    while cursor.remaining() >= 8 {
+        let offset = cursor.pos;
+        let mut frame = [0; 8];
+        frame.copy_from_slice(cursor.take(8)?);
+        if let Some(key) = key {
+            deobfuscate_at(&mut frame, key, offset);
+        }
+        let (found, len) = frame.split_at(4);
+        // Core preallocates block files, so they end in zeroes.
+        if found == [0; 4] {
+            break;
+        }
+        if found != magic {
+            return Err(BlkError::BadMagic { offset });
+        }
+        let start = cursor.pos;
+        cursor.skip(LittleEndian::read_u32(len) as usize)?;
+        blocks.push(start..cursor.pos);
+    }
+    Ok(blocks)
+}
+
+/// An output of a raw block, borrowing its script from the block bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct RawOutput<'a> {
+    pub tx_index: usize,
+    pub vout: usize,
+    pub value: Amount,
+    pub script: &'a Script,
+}
+
+/// Every output in a serialized block, in order.
+pub fn raw_block_outputs(block: &[u8]) -> Result<Vec<RawOutput<'_>>, BlkError> {
+    let mut cursor = Cursor::new(block);
+    cursor.skip(HEADER_LEN)?;
+    let tx_count = cursor.compact_size()?;
+    let mut outputs = vec![];
+    for tx_index in 0..tx_count as usize {
+        // version
+        cursor.skip(4)?;
+        let mut input_count = cursor.compact_size()?;
+        // A zero input count is the segwit marker, followed by the flag.
+        let segwit = input_count == 0;
+        if segwit {
+            let offset = cursor.pos;
+            if cursor.take(1)?[0] != 1 {
+                return Err(BlkError::InvalidSegwitFlag { offset });
+            }
+            input_count = cursor.compact_size()?;
+        }
+        for _ in 0..input_count {
+            // previous outpoint
+            cursor.skip(36)?;
+            let script_sig_len = cursor.length()?;
+            cursor.skip(script_sig_len)?;
+            // sequence
+            cursor.skip(4)?;
+        }
+        let output_count = cursor.compact_size()?;
+        for vout in 0..output_count as usize {
+            let value = Amount::from_sat(LittleEndian::read_u64(cursor.take(8)?));
+            let script_len = cursor.length()?;
+            let script = Script::from_bytes(cursor.take(script_len)?);
+            outputs.push(RawOutput {
+                tx_index,
+                vout,
+                value,
+                script,
+            });
+        }
+        if segwit {
+            for _ in 0..input_count {
+                let item_count = cursor.compact_size()?;
+                for _ in 0..item_count {
+                    let item_len = cursor.length()?;
+                    cursor.skip(item_len)?;
+                }
+            }
+        }
+        // lock time
+        cursor.skip(4)?;
+    }
+    Ok(outputs)
+}
+
+/// An output with an M8 tag, decoded or not, with its transaction index and
+/// output index.
+pub type BmmRequestScanResult = Result<(usize, usize, M8BmmRequest), (usize, usize, Bip300Error)>;
+
+/// The drivechain messages found in one raw block. Unlike
+/// [`crate::ext::BlockMessages`], they are owned and the block can be dropped.
+#[derive(Clone, Debug)]
+pub struct RawBlockMessages {
+    pub block_hash: BlockHash,
+    /// Coinbase messages with their output index. Outputs that carry a coinbase
+    /// message tag but fail to decode are kept as errors.
+    pub coinbase_messages: Vec<CoinbaseScanResult>,
+    /// M8 requests, and likewise the outputs with an M8 tag that fail to decode.
+    pub bmm_requests: Vec<BmmRequestScanResult>,
+}
+
+pub fn scan_raw_block(block: &[u8]) -> Result<RawBlockMessages, BlkError> {
+    let header = block.get(..HEADER_LEN).ok_or(BlkError::Truncated {
+        offset: block.len(),
+    })?;
+    let mut messages = RawBlockMessages {
+        block_hash: BlockHash::from_byte_array(sha256d(header)),
+        coinbase_messages: vec![],
+        bmm_requests: vec![],
+    };
+    for output in raw_block_outputs(block)? {
+        if output.tx_index == 0 {
+            messages.coinbase_messages.extend(scan_coinbase_output(
+                output.vout,
+                output.script,
+                SpecVersion::default(),
+            ));
+        } else if match_tag(output.script.as_bytes()) == Some(MessageKind::M8BmmRequest) {
+            let script = output.script.as_bytes();
+            let result = match parse_m8_bmm_request(script) {
+                Ok((_, request)) => Ok((output.tx_index, output.vout, request)),
+                Err(err) => Err((output.tx_index, output.vout, err)),
+            };
+            messages.bmm_requests.push(result);
+        }
+    }
+    Ok(messages)
+}
+
+/// Scan every block in a block file, reading the whole file into memory and
+/// undoing the XOR obfuscation if its directory has a key.
+pub fn scan_blk_file(
+    path: impl AsRef<Path>,
+    magic: [u8; 4],
+) -> Result<Vec<RawBlockMessages>, BlkError> {
+    let path = path.as_ref();
+    let mut data = std::fs::read(path)?;
+    if let Some(key) = xor_key_for(path)? {
+        deobfuscate(&mut data, key);
+    }
+    raw_blocks(&data, magic)?
+        .into_iter()
+        .map(scan_raw_block)
+        .collect()
+}
+
+/// Scan every block in a block file through a memory map, splitting the blocks
+/// into `threads` chunks that are scanned in parallel. The file is mapped
+/// read-only. If it is obfuscated, each block is copied out and de-obfuscated
+/// just before it is scanned, so only the blocks being scanned are in memory
+/// twice.
+#[cfg(feature = "mmap")]
+pub fn scan_blk_file_mmap(
+    path: impl AsRef<Path>,
+    magic: [u8; 4],
+    threads: std::num::NonZeroUsize,
+) -> Result<Vec<RawBlockMessages>, BlkError> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path)?;
+    // Safety: Core only ever appends to block files. As with any memory map, the
+    // file must not be truncated while it is mapped.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    let key = xor_key_for(path)?.filter(|key| *key != [0; XOR_KEY_LEN]);
+    let blocks = raw_block_ranges(&mmap, magic, key)?;
+    let chunk_size = blocks.len().div_ceil(threads.get()).max(1);
+    let scan = |range: &Range<usize>| match key {
+        None => scan_raw_block(&mmap[range.clone()]),
+        Some(key) => {
+            let mut block = mmap[range.clone()].to_vec();
+            deobfuscate_at(&mut block, key, range.start);
+            scan_raw_block(&block)
+        }
+    };
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = blocks
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(scan).collect::<Result<Vec<_>, _>>()))
+            .collect();
+        let mut results = Vec::with_capacity(blocks.len());
+        for handle in handles {
+            results.extend(handle.join().expect("block file scan thread panicked")?);
+        }
+        Ok(results)
+    })
+}
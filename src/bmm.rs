@@ -0,0 +1,213 @@
+//! Blind merged mining (BIP301) transactions.
+
+use bitcoin::{
+    absolute::LockTime, block::Header, hashes::Hash, transaction, Amount, Block, OutPoint,
+    ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness,
+};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    ext::TransactionExt,
+    messages::{M8BmmRequest, SidechainBlockHash, SidechainNumber},
+    parser::{detect_m8_bmm_request, Bip300Error},
+};
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum FindBmmRequestError {
+    #[error("output {vout} carries a malformed M8")]
+    Malformed {
+        vout: usize,
+        #[source]
+        error: Bip300Error,
+    },
+    #[error("transaction carries more than one M8: {vouts:?}")]
+    MultipleRequests { vouts: Vec<usize> },
+}
+
+/// The M8 in `tx` with its output index, or `None` if there is none. A
+/// transaction may carry at most one, and an output with the M8 tag has to
+/// decode.
+pub fn find_bmm_request(
+    tx: &Transaction,
+) -> Result<Option<(usize, M8BmmRequest)>, FindBmmRequestError> {
+    let mut requests = vec![];
+    for (vout, output) in tx.output.iter().enumerate() {
+        match detect_m8_bmm_request(output.script_pubkey.as_bytes()) {
+            None => (),
+            Some(Ok(request)) => requests.push((vout, request)),
+            Some(Err(error)) => return Err(FindBmmRequestError::Malformed { vout, error }),
+        }
+    }
+    if requests.len() > 1 {
+        return Err(FindBmmRequestError::MultipleRequests {
+            vouts: requests.iter().map(|(vout, _)| *vout).collect(),
+        });
+    }
+    Ok(requests.pop())
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum BmmTxError {
+    #[error("funding of {available} can't cover a bid of {bid}")]
+    InsufficientFunds { available: Amount, bid: Amount },
+    #[error("funding value overflows")]
+    ValueOverflow,
+}
+
+/// An unsigned transaction carrying `request` in its first output, spending
+/// `funding` and returning everything but `bid` to `change_script` in the second
+/// output. The bid is left as the fee, which is what the miner collects for
+/// including the request. The change output is omitted if there is no change.
+/// Inputs signal replaceability, so the bid can be raised later.
+pub fn build_bmm_request_tx(
+    request: &M8BmmRequest,
+    funding: &[(OutPoint, Amount)],
+    bid: Amount,
+    change_script: ScriptBuf,
+) -> Result<Transaction, BmmTxError> {
+    let available = funding
+        .iter()
+        .try_fold(Amount::ZERO, |total, (_, value)| total.checked_add(*value))
+        .ok_or(BmmTxError::ValueOverflow)?;
+    let change = available
+        .checked_sub(bid)
+        .ok_or(BmmTxError::InsufficientFunds { available, bid })?;
+    let input = funding
+        .iter()
+        .map(|(previous_output, _)| TxIn {
+            previous_output: *previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        })
+        .collect();
+    let mut output = vec![TxOut {
+        value: Amount::ZERO,
+        script_pubkey: request.encode(),
+    }];
+    if change > Amount::ZERO {
+        output.push(TxOut {
+            value: change,
+            script_pubkey: change_script,
+        });
+    }
+    Ok(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    })
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum BmmProofError {
+    #[error("proof transaction is not a coinbase")]
+    NotCoinbase,
+    #[error("merkle branch leads to {computed}, but the header commits to {expected}")]
+    MerkleRootMismatch {
+        expected: TxMerkleNode,
+        computed: TxMerkleNode,
+    },
+    #[error("coinbase has no M7 for sidechain {sidechain_number}")]
+    NoAccept { sidechain_number: SidechainNumber },
+    #[error("coinbase has more than one M7 for sidechain {sidechain_number}")]
+    MultipleAccepts { sidechain_number: SidechainNumber },
+}
+
+/// The hashes needed to link the coinbase of `block` to its merkle root, from the
+/// bottom of the tree up. Empty for a block with only a coinbase, whose txid is
+/// the root.
+pub fn coinbase_merkle_branch(block: &Block) -> Vec<TxMerkleNode> {
+    let mut level: Vec<TxMerkleNode> = block
+        .txdata
+        .iter()
+        .map(|tx| TxMerkleNode::from_byte_array(tx.compute_txid().to_byte_array()))
+        .collect();
+    let mut branch = vec![];
+    while level.len() > 1 {
+        branch.push(level[1]);
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(pair[0], *pair.last().expect("chunks are not empty")))
+            .collect();
+    }
+    branch
+}
+
+fn merkle_parent(left: TxMerkleNode, right: TxMerkleNode) -> TxMerkleNode {
+    let mut preimage = [0; 64];
+    preimage[..32].copy_from_slice(left.as_byte_array());
+    preimage[32..].copy_from_slice(right.as_byte_array());
+    TxMerkleNode::hash(&preimage)
+}
+
+/// Check that `coinbase` is committed to by `header` through `merkle_branch` (see
+/// [`coinbase_merkle_branch`]) and carries an M7 for `sidechain_number`, and
+/// return the sidechain block hash it accepts. Whether `header` is in the best
+/// mainchain is up to the caller.
+pub fn verify_bmm_accept(
+    header: &Header,
+    merkle_branch: &[TxMerkleNode],
+    coinbase: &Transaction,
+    sidechain_number: SidechainNumber,
+) -> Result<SidechainBlockHash, BmmProofError> {
+    if !coinbase.is_coinbase() {
+        return Err(BmmProofError::NotCoinbase);
+    }
+    let computed = merkle_branch.iter().fold(
+        TxMerkleNode::from_byte_array(coinbase.compute_txid().to_byte_array()),
+        |node, sibling| merkle_parent(node, *sibling),
+    );
+    if computed != header.merkle_root {
+        return Err(BmmProofError::MerkleRootMismatch {
+            expected: header.merkle_root,
+            computed,
+        });
+    }
+    let mut accepts = coinbase
+        .coinbase_messages()
+        .into_iter()
+        .filter(|(_, message)| message.sidechain_number() == Some(sidechain_number))
+        .filter_map(|(_, message)| message.sidechain_block_hash());
+    let sidechain_block_hash = accepts
+        .next()
+        .ok_or(BmmProofError::NoAccept { sidechain_number })?;
+    if accepts.next().is_some() {
+        return Err(BmmProofError::MultipleAccepts { sidechain_number });
+    }
+    Ok(sidechain_block_hash)
+}
+
+/// Everything a sidechain node needs to check that a mainchain block accepted
+/// one of its blocks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BmmProof {
+    pub header: Header,
+    pub merkle_branch: Vec<TxMerkleNode>,
+    pub coinbase: Transaction,
+}
+
+impl BmmProof {
+    /// The proof for `block`, or `None` if it has no transactions.
+    pub fn from_block(block: &Block) -> Option<Self> {
+        Some(BmmProof {
+            header: block.header,
+            merkle_branch: coinbase_merkle_branch(block),
+            coinbase: block.txdata.first()?.clone(),
+        })
+    }
+
+    /// See [`verify_bmm_accept`].
+    pub fn verify(
+        &self,
+        sidechain_number: SidechainNumber,
+    ) -> Result<SidechainBlockHash, BmmProofError> {
+        verify_bmm_accept(
+            &self.header,
+            &self.merkle_branch,
+            &self.coinbase,
+            sidechain_number,
+        )
+    }
+}
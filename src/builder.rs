@@ -0,0 +1,231 @@
+//! Construction of coinbase message outputs.
+
+use alloc::{vec, vec::Vec};
+
+use bitcoin::{Amount, TxOut};
+
+use crate::{
+    declaration::DeclarationError,
+    messages::{
+        CoinbaseMessage, HashBytes, M1Data, M4AckBundles, M4EncodeError, MessageKind,
+        SidechainDeclaration, SidechainNumber, SidechainProposalId,
+    },
+};
+
+#[derive(Default)]
+pub struct CoinbaseBuilder {
+    messages: Vec<CoinbaseMessage>,
+}
+
+impl CoinbaseBuilder {
+    pub fn new() -> Self {
+        CoinbaseBuilder { messages: vec![] }
+    }
+
+    /// Fails if an M4 has an upvote that does not fit its encoding, see
+    /// [`M4EncodeError`].
+    pub fn build(self) -> Result<Vec<TxOut>, M4EncodeError> {
+        self.messages
+            .into_iter()
+            .map(|message| {
+                Ok(TxOut {
+                    value: Amount::ZERO,
+                    script_pubkey: message.to_script()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Append any message, e.g. an [`M4AckBundles`] or an
+    /// [`crate::M8BmmRequest`]'s accept.
+    pub fn push(mut self, message: impl Into<CoinbaseMessage>) -> Self {
+        self.add_message(message);
+        self
+    }
+
+    pub fn propose_sidechain(
+        mut self,
+        sidechain_number: SidechainNumber,
+        data: impl Into<M1Data>,
+    ) -> Self {
+        self.add_propose_sidechain(sidechain_number, data);
+        self
+    }
+
+    /// Append the M1 for `declaration`, also returning the id that M2s acking it
+    /// will carry, so callers don't have to hash the payload themselves. Fails if
+    /// the declaration does not encode, see [`SidechainDeclaration::to_bytes`].
+    pub fn propose_and_track(
+        mut self,
+        declaration: &SidechainDeclaration,
+    ) -> Result<(Self, SidechainProposalId), DeclarationError> {
+        let proposal_id = self.add_propose_and_track(declaration)?;
+        Ok((self, proposal_id))
+    }
+
+    pub fn ack_sidechain(
+        mut self,
+        sidechain_number: SidechainNumber,
+        data_hash: impl HashBytes,
+    ) -> Self {
+        self.add_ack_sidechain(sidechain_number, data_hash);
+        self
+    }
+
+    /// Append the M2 acking the M1 that proposes `data` for `sidechain_number`,
+    /// hashing the payload.
+    pub fn ack_sidechain_from_proposal(
+        mut self,
+        sidechain_number: SidechainNumber,
+        data: &M1Data,
+    ) -> Self {
+        self.add_ack_sidechain_from_proposal(sidechain_number, data);
+        self
+    }
+
+    /// Append the M2 acking the M1 for `declaration`. Fails if the declaration
+    /// does not encode.
+    pub fn ack_declaration(
+        mut self,
+        declaration: &SidechainDeclaration,
+    ) -> Result<Self, DeclarationError> {
+        self.add_ack_sidechain(declaration.sidechain_number, declaration.proposal_id()?);
+        Ok(self)
+    }
+
+    pub fn propose_bundle(
+        mut self,
+        sidechain_number: SidechainNumber,
+        bundle_hash: impl HashBytes,
+    ) -> Self {
+        self.add_propose_bundle(sidechain_number, bundle_hash);
+        self
+    }
+
+    pub fn ack_bundles(self, m4_ack_bundles: M4AckBundles) -> Self {
+        self.push(m4_ack_bundles)
+    }
+
+    pub fn bmm_accept(
+        mut self,
+        sidechain_number: SidechainNumber,
+        bmm_hash: impl HashBytes,
+    ) -> Self {
+        self.add_bmm_accept(sidechain_number, bmm_hash);
+        self
+    }
+
+    /// Drop every message of `kind`. M8 BMM requests never appear in a
+    /// coinbase, so removing them is a no-op.
+    pub fn remove_kind(mut self, kind: MessageKind) -> Self {
+        self.messages.retain(|message| message.kind() != kind);
+        self
+    }
+
+    /// Drop every message that names `sidechain_number`. M4 acks cover all
+    /// sidechains at once and are left in place.
+    pub fn remove_for_sidechain(mut self, sidechain_number: SidechainNumber) -> Self {
+        self.messages
+            .retain(|message| message.sidechain_number() != Some(sidechain_number));
+        self
+    }
+
+    /// Replace the first M4 in place, keeping its output position, or append
+    /// `m4_ack_bundles` if there is none yet. Any further M4s are dropped since a
+    /// coinbase carries at most one.
+    pub fn replace_m4(mut self, m4_ack_bundles: M4AckBundles) -> Self {
+        let mut replacement = Some(m4_ack_bundles);
+        self.messages.retain_mut(|message| {
+            let CoinbaseMessage::M4AckBundles(existing) = message else {
+                return true;
+            };
+            match replacement.take() {
+                Some(m4_ack_bundles) => {
+                    *existing = m4_ack_bundles;
+                    true
+                }
+                None => false,
+            }
+        });
+        if let Some(m4_ack_bundles) = replacement {
+            self.messages
+                .push(CoinbaseMessage::M4AckBundles(m4_ack_bundles));
+        }
+        self
+    }
+}
+
+// Borrowing counterparts of the methods above, for code that adds messages in a
+// loop and would otherwise have to reassign the builder on every iteration.
+impl CoinbaseBuilder {
+    pub fn add_message(&mut self, message: impl Into<CoinbaseMessage>) -> &mut Self {
+        self.messages.push(message.into());
+        self
+    }
+
+    pub fn add_propose_sidechain(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        data: impl Into<M1Data>,
+    ) -> &mut Self {
+        self.add_message(CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number,
+            data: data.into(),
+        })
+    }
+
+    pub fn add_propose_and_track(
+        &mut self,
+        declaration: &SidechainDeclaration,
+    ) -> Result<SidechainProposalId, DeclarationError> {
+        let data = declaration.to_bytes()?;
+        let proposal_id = SidechainProposalId::from_data(&data);
+        self.add_propose_sidechain(declaration.sidechain_number, data);
+        Ok(proposal_id)
+    }
+
+    pub fn add_ack_sidechain(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        data_hash: impl HashBytes,
+    ) -> &mut Self {
+        self.add_message(CoinbaseMessage::M2AckSidechain {
+            sidechain_number,
+            data_hash: data_hash.hash_bytes(),
+        })
+    }
+
+    pub fn add_ack_sidechain_from_proposal(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        data: &M1Data,
+    ) -> &mut Self {
+        self.add_ack_sidechain(sidechain_number, SidechainProposalId::from_data(data))
+    }
+
+    pub fn add_propose_bundle(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        bundle_hash: impl HashBytes,
+    ) -> &mut Self {
+        self.add_message(CoinbaseMessage::M3ProposeBundle {
+            sidechain_number,
+            bundle_txid: bundle_hash.hash_bytes(),
+        })
+    }
+
+    pub fn add_ack_bundles(&mut self, m4_ack_bundles: M4AckBundles) -> &mut Self {
+        self.add_message(m4_ack_bundles)
+    }
+
+    pub fn add_bmm_accept(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        bmm_hash: impl HashBytes,
+    ) -> &mut Self {
+        self.add_message(CoinbaseMessage::M7BmmAccept {
+            sidechain_number,
+            sidechain_block_hash: bmm_hash.hash_bytes(),
+        })
+    }
+}
@@ -0,0 +1,420 @@
+//! Withdrawal bundles and the votes on them (BIP300's D2).
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use bitcoin::{hex::DisplayHex, Block};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    ext::BlockExt,
+    messages::{
+        resolve_leading_by_50, resolve_repeat_previous, CoinbaseMessage, M4AckBundles,
+        SidechainNumber, SidechainSet, Vote, Votes,
+    },
+    params::Params,
+    state::{changed_entries, restore_entries, Retention},
+};
+
+/// A bundle proposed by an M3 and not yet paid out or expired.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingBundle {
+    pub sidechain_number: SidechainNumber,
+    /// The blinded txid of the M6, see [`crate::m6_to_id`].
+    pub bundle_txid: [u8; 32],
+    /// Height of the block carrying the M3.
+    pub proposal_height: u32,
+    pub score: u16,
+}
+
+impl PendingBundle {
+    /// Blocks connected on top of the one proposing this, as of `height`.
+    pub fn age(&self, height: u32) -> u32 {
+        height.saturating_sub(self.proposal_height)
+    }
+
+    /// Whether the bundle's score reached the ack threshold of `params`.
+    pub fn is_spendable(&self, params: &Params) -> bool {
+        self.score >= params.bundle_ack_threshold
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BundleEvent {
+    /// The bundle reached its ack threshold, so its M6 may be included.
+    Spendable {
+        sidechain_number: SidechainNumber,
+        bundle_txid: [u8; 32],
+    },
+    /// The bundle reached its maximum age without becoming spendable and was
+    /// dropped.
+    Expired {
+        sidechain_number: SidechainNumber,
+        bundle_txid: [u8; 32],
+    },
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum BundleVoteError {
+    #[error("M3 proposes a bundle for sidechain {sidechain_number}, which is not active")]
+    InactiveSidechain { sidechain_number: SidechainNumber },
+    #[error("M3 proposes bundle {} for sidechain {sidechain_number}, which is already pending", .bundle_txid.as_hex())]
+    DuplicateBundle {
+        sidechain_number: SidechainNumber,
+        bundle_txid: [u8; 32],
+    },
+    #[error("block has more than one M4")]
+    MultipleM4,
+    #[error("M4 repeats the previous block's votes, which can't be resolved")]
+    NothingToRepeat,
+    #[error("M4 has {got} votes for {expected} active sidechains")]
+    VoteCount { expected: usize, got: usize },
+    #[error(
+        "M4 upvotes bundle {index} of sidechain {sidechain_number}, which has {pending} pending"
+    )]
+    UnknownBundle {
+        sidechain_number: SidechainNumber,
+        index: u16,
+        pending: usize,
+    },
+}
+
+/// Score a bundle at `score` still needs to become spendable.
+pub fn bundle_acks_needed(score: u16, params: &Params) -> u16 {
+    params.bundle_ack_threshold.saturating_sub(score)
+}
+
+/// Blocks after `height` a bundle proposed at `proposal_height` has left to
+/// become spendable in.
+pub fn bundle_blocks_remaining(proposal_height: u32, height: u32, params: &Params) -> u32 {
+    params
+        .bundle_max_age
+        .saturating_sub(height.saturating_sub(proposal_height))
+}
+
+/// Whether a bundle at `score` as of `height` is dropped: it reached its
+/// maximum age without being spendable.
+pub fn bundle_is_expired(score: u16, proposal_height: u32, height: u32, params: &Params) -> bool {
+    score < params.bundle_ack_threshold
+        && bundle_blocks_remaining(proposal_height, height, params) == 0
+}
+
+/// Whether a bundle at `score` as of `height` can still become spendable if
+/// every remaining block upvotes it. A block moves a score by at most one.
+pub fn bundle_can_still_pass(
+    score: u16,
+    proposal_height: u32,
+    height: u32,
+    params: &Params,
+) -> bool {
+    let remaining = bundle_blocks_remaining(proposal_height, height, params);
+    u32::from(bundle_acks_needed(score, params)) <= remaining
+}
+
+/// Whether a bundle whose score after each block was `scores`, starting with the
+/// block proposing it, may be paid out in the next block: it never expired and
+/// its latest score is at the threshold.
+pub fn score_history_is_spendable(scores: &[u16], params: &Params) -> bool {
+    let Some(latest) = scores.last() else {
+        return false;
+    };
+    *latest >= params.bundle_ack_threshold
+        && scores
+            .iter()
+            .enumerate()
+            .all(|(age, score)| !bundle_is_expired(*score, 0, age as u32, params))
+}
+
+/// What one block changed in a [`BundleVotes`], enough to undo it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BundleUndo {
+    pub height: u32,
+    /// The pending bundles of every sidechain the block voted on, proposed for
+    /// or paid out, as they were before the block. `None` if the sidechain had
+    /// none.
+    pub pending: BTreeMap<SidechainNumber, Option<Vec<PendingBundle>>>,
+    /// The previous block's resolved votes.
+    pub previous_votes: Option<Votes>,
+}
+
+/// Tracks the pending bundles of every sidechain and their scores across blocks.
+/// Paid out and expired bundles are dropped, so only undo data for the blocks
+/// the [`Retention`] keeps refers to them.
+#[derive(Clone, Debug, Default)]
+pub struct BundleVotes {
+    params: Params,
+    /// Each sidechain's pending bundles in proposal order, which is the order M4
+    /// upvotes index them in.
+    pending: BTreeMap<SidechainNumber, Vec<PendingBundle>>,
+    /// The votes the last connected block's M4 resolved to.
+    previous_votes: Option<Votes>,
+    retention: Retention,
+    history: VecDeque<BundleUndo>,
+}
+
+impl BundleVotes {
+    pub fn new(params: Params) -> Self {
+        BundleVotes {
+            params,
+            ..Self::default()
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Change the retention policy, dropping any history it no longer allows.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.retention.prune(&mut self.history);
+    }
+
+    /// Undo data for the retained blocks, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &BundleUndo> {
+        self.history.iter()
+    }
+
+    /// The pending bundles of `sidechain_number`, in proposal order.
+    pub fn bundles(&self, sidechain_number: SidechainNumber) -> &[PendingBundle] {
+        self.pending
+            .get(&sidechain_number)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn bundle(
+        &self,
+        sidechain_number: SidechainNumber,
+        bundle_txid: &[u8; 32],
+    ) -> Option<&PendingBundle> {
+        self.bundles(sidechain_number)
+            .iter()
+            .find(|bundle| bundle.bundle_txid == *bundle_txid)
+    }
+
+    /// Whether the bundle of `sidechain_number` is pending and reached its ack
+    /// threshold.
+    pub fn is_spendable(&self, sidechain_number: SidechainNumber, bundle_txid: &[u8; 32]) -> bool {
+        self.bundle(sidechain_number, bundle_txid)
+            .is_some_and(|bundle| bundle.is_spendable(&self.params))
+    }
+
+    /// The votes the last connected block's M4 resolved to, which is what a
+    /// `RepeatPrevious` in the next block stands for.
+    pub fn previous_votes(&self) -> Option<&Votes> {
+        self.previous_votes.as_ref()
+    }
+
+    /// The scores of each active sidechain's pending bundles, in vote vector
+    /// order, as [`resolve_leading_by_50`] takes them.
+    pub fn standings(&self, active_sidechains: &SidechainSet) -> Vec<Vec<u16>> {
+        active_sidechains
+            .iter()
+            .map(|sidechain_number| {
+                self.bundles(sidechain_number)
+                    .iter()
+                    .map(|bundle| bundle.score)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The concrete votes `m4` stands for in the next block. A block without an
+    /// M4 abstains for every sidechain.
+    pub fn resolve(
+        &self,
+        m4: Option<&M4AckBundles>,
+        active_sidechains: &SidechainSet,
+    ) -> Result<Votes, BundleVoteError> {
+        let votes = match m4 {
+            None => vec![Vote::Abstain; active_sidechains.len()].into(),
+            Some(M4AckBundles::LeadingBy50) => {
+                resolve_leading_by_50(&self.standings(active_sidechains))
+            }
+            Some(m4) => resolve_repeat_previous(m4, self.previous_votes.as_ref())
+                .ok_or(BundleVoteError::NothingToRepeat)?,
+        };
+        if votes.len() != active_sidechains.len() {
+            return Err(BundleVoteError::VoteCount {
+                expected: active_sidechains.len(),
+                got: votes.len(),
+            });
+        }
+        for (sidechain_number, vote) in active_sidechains.iter().zip(votes.iter()) {
+            if let Vote::Upvote(index) = vote {
+                let pending = self.bundles(sidechain_number).len();
+                if usize::from(*index) >= pending {
+                    return Err(BundleVoteError::UnknownBundle {
+                        sidechain_number,
+                        index: *index,
+                        pending,
+                    });
+                }
+            }
+        }
+        Ok(votes)
+    }
+
+    /// Apply the M3s and M4 in the coinbase of `block` at `height`, with
+    /// `active_sidechains` the sidechains active before it.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+        active_sidechains: &SidechainSet,
+    ) -> Result<Vec<BundleEvent>, BundleVoteError> {
+        let messages = block
+            .coinbase_messages()
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        self.connect_messages(&messages, height, active_sidechains)
+    }
+
+    /// Apply the coinbase `messages` of the block at `height`, ignoring anything
+    /// but M3s and M4s. The M4 votes on the bundles pending before the block: an
+    /// upvote adds one to the chosen bundle and takes one from the sidechain's
+    /// others, an alarm takes one from all of them. Bundles proposed in the block
+    /// are added after. Nothing is applied if the block breaks a D2 rule.
+    pub fn connect_messages(
+        &mut self,
+        messages: &[CoinbaseMessage],
+        height: u32,
+        active_sidechains: &SidechainSet,
+    ) -> Result<Vec<BundleEvent>, BundleVoteError> {
+        let mut m4s = messages.iter().filter_map(|message| match message {
+            CoinbaseMessage::M4AckBundles(m4) => Some(m4),
+            _ => None,
+        });
+        let m4 = m4s.next();
+        if m4s.next().is_some() {
+            return Err(BundleVoteError::MultipleM4);
+        }
+        let votes = self.resolve(m4, active_sidechains)?;
+
+        let mut proposed = HashSet::new();
+        for message in messages {
+            if let CoinbaseMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid,
+            } = message
+            {
+                if !active_sidechains.contains(*sidechain_number) {
+                    return Err(BundleVoteError::InactiveSidechain {
+                        sidechain_number: *sidechain_number,
+                    });
+                }
+                if self.bundle(*sidechain_number, bundle_txid).is_some()
+                    || !proposed.insert((*sidechain_number, *bundle_txid))
+                {
+                    return Err(BundleVoteError::DuplicateBundle {
+                        sidechain_number: *sidechain_number,
+                        bundle_txid: *bundle_txid,
+                    });
+                }
+            }
+        }
+
+        let pending = self.pending.clone();
+        let mut events = Vec::new();
+        for (sidechain_number, vote) in active_sidechains.iter().zip(votes.iter()) {
+            let Some(bundles) = self.pending.get_mut(&sidechain_number) else {
+                continue;
+            };
+            for (index, bundle) in bundles.iter_mut().enumerate() {
+                let was_spendable = bundle.is_spendable(&self.params);
+                bundle.score = match vote {
+                    Vote::Upvote(upvoted) if usize::from(*upvoted) == index => {
+                        bundle.score.saturating_add(1)
+                    }
+                    Vote::Upvote(_) | Vote::Alarm => bundle.score.saturating_sub(1),
+                    Vote::Abstain => bundle.score,
+                };
+                if !was_spendable && bundle.is_spendable(&self.params) {
+                    events.push(BundleEvent::Spendable {
+                        sidechain_number,
+                        bundle_txid: bundle.bundle_txid,
+                    });
+                }
+            }
+        }
+        for message in messages {
+            if let CoinbaseMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid,
+            } = message
+            {
+                self.pending
+                    .entry(*sidechain_number)
+                    .or_default()
+                    .push(PendingBundle {
+                        sidechain_number: *sidechain_number,
+                        bundle_txid: *bundle_txid,
+                        proposal_height: height,
+                        score: 0,
+                    });
+            }
+        }
+        let params = self.params;
+        for bundles in self.pending.values_mut() {
+            bundles.retain(|bundle| {
+                let expired =
+                    bundle_is_expired(bundle.score, bundle.proposal_height, height, &params);
+                if expired {
+                    events.push(BundleEvent::Expired {
+                        sidechain_number: bundle.sidechain_number,
+                        bundle_txid: bundle.bundle_txid,
+                    });
+                }
+                !expired
+            });
+        }
+        self.pending.retain(|_, bundles| !bundles.is_empty());
+        self.history.push_back(BundleUndo {
+            height,
+            pending: changed_entries(&pending, &self.pending),
+            previous_votes: self.previous_votes.replace(votes),
+        });
+        self.retention.prune(&mut self.history);
+        Ok(events)
+    }
+
+    /// Undo the block at `height`, which must be the last connected one. Returns
+    /// `false`, changing nothing, if the retention policy dropped its history.
+    pub fn disconnect_messages(&mut self, height: u32) -> bool {
+        if self.history.back().map(|undo| undo.height) != Some(height) {
+            return false;
+        }
+        let undo = self.history.pop_back().expect("history is not empty");
+        restore_entries(&mut self.pending, undo.pending);
+        self.previous_votes = undo.previous_votes;
+        true
+    }
+
+    /// Drop the bundle of `sidechain_number` an M6 paid out, returning it. The
+    /// payout is undone along with the last connected block.
+    pub fn spend(
+        &mut self,
+        sidechain_number: SidechainNumber,
+        bundle_txid: &[u8; 32],
+    ) -> Option<PendingBundle> {
+        let bundles = self.pending.get_mut(&sidechain_number)?;
+        let index = bundles
+            .iter()
+            .position(|bundle| bundle.bundle_txid == *bundle_txid)?;
+        if let Some(undo) = self.history.back_mut() {
+            undo.pending
+                .entry(sidechain_number)
+                .or_insert_with(|| Some(bundles.clone()));
+        }
+        let bundle = bundles.remove(index);
+        if bundles.is_empty() {
+            self.pending.remove(&sidechain_number);
+        }
+        Some(bundle)
+    }
+}
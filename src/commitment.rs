@@ -0,0 +1,117 @@
+//! Merkle commitments over the drivechain messages in a coinbase.
+//!
+//! The leaves are the coinbase's message outputs in output order. A leaf is the
+//! sha256d of `0x00`, the output index as a little-endian `u32` and the output
+//! script; an inner node is the sha256d of `0x01` and its two children. The
+//! prefixes keep a leaf from being passed off as an inner node. As in Bitcoin's
+//! transaction tree, the last node of an odd-length level is paired with itself.
+
+use bitcoin::{Script, Transaction};
+
+use crate::{ext::ScriptExt, messages::sha256d};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn message_leaf(vout: u32, script: &Script) -> [u8; 32] {
+    let mut preimage = Vec::with_capacity(1 + 4 + script.len());
+    preimage.push(LEAF_PREFIX);
+    preimage.extend_from_slice(&vout.to_le_bytes());
+    preimage.extend_from_slice(script.as_bytes());
+    sha256d(&preimage)
+}
+
+fn node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut preimage = [0; 1 + 32 + 32];
+    preimage[0] = NODE_PREFIX;
+    preimage[1..33].copy_from_slice(left);
+    preimage[33..].copy_from_slice(right);
+    sha256d(&preimage)
+}
+
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| node(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// The leaves of `coinbase`'s message tree, with the output index of each.
+fn message_leaves(coinbase: &Transaction) -> Vec<(u32, [u8; 32])> {
+    coinbase
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.script_pubkey.parse_drivechain().is_some())
+        .map(|(vout, output)| {
+            let vout = vout as u32;
+            (vout, message_leaf(vout, &output.script_pubkey))
+        })
+        .collect()
+}
+
+/// The root of the tree over `coinbase`'s drivechain messages, or `None` if it has
+/// none.
+pub fn coinbase_message_root(coinbase: &Transaction) -> Option<[u8; 32]> {
+    let mut level: Vec<[u8; 32]> = message_leaves(coinbase)
+        .into_iter()
+        .map(|(_, leaf)| leaf)
+        .collect();
+    if level.is_empty() {
+        return None;
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    Some(level[0])
+}
+
+/// Proof that one message output is committed to by a message root.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MessageProof {
+    pub vout: u32,
+    /// Position of the leaf among the coinbase's messages.
+    pub index: usize,
+    /// Sibling hashes from the leaf up to the root.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MessageProof {
+    /// Build the proof for the message at output `vout` of `coinbase`, or `None`
+    /// if that output is not a drivechain message.
+    pub fn new(coinbase: &Transaction, vout: u32) -> Option<Self> {
+        let leaves = message_leaves(coinbase);
+        let index = leaves
+            .iter()
+            .position(|(leaf_vout, _)| *leaf_vout == vout)?;
+        let mut level: Vec<[u8; 32]> = leaves.into_iter().map(|(_, leaf)| leaf).collect();
+        let mut position = index;
+        let mut siblings = vec![];
+        while level.len() > 1 {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+            siblings.push(*sibling);
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(MessageProof {
+            vout,
+            index,
+            siblings,
+        })
+    }
+
+    /// Whether `script` at this proof's output index is committed to by `root`.
+    pub fn verify(&self, script: &Script, root: &[u8; 32]) -> bool {
+        let mut hash = message_leaf(self.vout, script);
+        let mut position = self.index;
+        for sibling in &self.siblings {
+            hash = if position.is_multiple_of(2) {
+                node(&hash, sibling)
+            } else {
+                node(sibling, &hash)
+            };
+            position /= 2;
+        }
+        position == 0 && hash == *root
+    }
+}
@@ -0,0 +1,55 @@
+//! Bitcoin consensus encoding of messages, so they can be embedded in wire
+//! formats next to other `bitcoin` types. A message is encoded as its script,
+//! with a compact size length prefix.
+
+use bitcoin::{
+    consensus::{encode, Decodable, Encodable},
+    io::{BufRead, Write},
+    ScriptBuf,
+};
+
+use crate::{
+    messages::{CoinbaseMessage, M8BmmRequest},
+    parser::{parse_coinbase_script_strict, parse_m8_bmm_request_strict},
+};
+
+impl Encodable for CoinbaseMessage {
+    fn consensus_encode<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let script = self
+            .to_script()
+            .map_err(|_| bitcoin::io::Error::from(bitcoin::io::ErrorKind::InvalidInput))?;
+        script.consensus_encode(writer)
+    }
+}
+
+impl Decodable for CoinbaseMessage {
+    fn consensus_decode_from_finite_reader<R: BufRead + ?Sized>(
+        reader: &mut R,
+    ) -> Result<Self, encode::Error> {
+        let script = ScriptBuf::consensus_decode_from_finite_reader(reader)?;
+        parse_coinbase_script_strict(&script)
+            .map_err(|_| encode::Error::ParseFailed("invalid coinbase message"))
+    }
+}
+
+impl Encodable for M8BmmRequest {
+    fn consensus_encode<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        self.encode().consensus_encode(writer)
+    }
+}
+
+impl Decodable for M8BmmRequest {
+    fn consensus_decode_from_finite_reader<R: BufRead + ?Sized>(
+        reader: &mut R,
+    ) -> Result<Self, encode::Error> {
+        let script = ScriptBuf::consensus_decode_from_finite_reader(reader)?;
+        parse_m8_bmm_request_strict(script.as_bytes())
+            .map_err(|_| encode::Error::ParseFailed("invalid M8 BMM request"))
+    }
+}
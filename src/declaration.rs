@@ -0,0 +1,154 @@
+//! Decoding M1 payloads into sidechain declarations.
+//!
+//! The first byte of an M1 payload is a format version. This crate decodes
+//! version 0 into a [`SidechainDeclaration`]; sidechains with their own formats
+//! implement [`DeclarationDecoder`] and get the same framing and proposal ids.
+
+use alloc::string::String;
+
+#[cfg(feature = "std")]
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::messages::{
+    CoinbaseMessage, SidechainDeclaration, SidechainNumber, SidechainProposalId,
+};
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[cfg_attr(feature = "std", derive(Diagnostic))]
+pub enum DeclarationError {
+    #[error("M1 payload is empty, it has no version byte")]
+    Empty,
+    #[error("unsupported declaration version {0}")]
+    UnsupportedVersion(u8),
+    #[error("declaration ends unexpectedly at byte {offset}")]
+    Truncated { offset: usize },
+    #[error("declaration {field} is not valid UTF-8")]
+    InvalidUtf8 { field: &'static str },
+    #[error("declaration has {len} unexpected trailing bytes")]
+    TrailingBytes { len: usize },
+    #[error("declaration {field} is {len} bytes, longer than the 65535 a declaration can hold")]
+    FieldTooLong { field: &'static str, len: usize },
+}
+
+pub trait DeclarationDecoder {
+    type Declaration;
+    type Error: From<DeclarationError>;
+
+    /// Decode the payload after the version byte.
+    fn decode(
+        &self,
+        sidechain_number: SidechainNumber,
+        version: u8,
+        body: &[u8],
+    ) -> Result<Self::Declaration, Self::Error>;
+}
+
+/// Decodes the version 0 format described on [`SidechainDeclaration::to_bytes`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultDeclarationDecoder;
+
+impl DeclarationDecoder for DefaultDeclarationDecoder {
+    type Declaration = SidechainDeclaration;
+    type Error = DeclarationError;
+
+    fn decode(
+        &self,
+        sidechain_number: SidechainNumber,
+        version: u8,
+        body: &[u8],
+    ) -> Result<SidechainDeclaration, DeclarationError> {
+        if version != SidechainDeclaration::VERSION {
+            return Err(DeclarationError::UnsupportedVersion(version));
+        }
+        // Offsets in errors count the version byte.
+        let mut offset = 1;
+        let mut take = |len: usize| {
+            let start = offset - 1;
+            let bytes = body
+                .get(start..start + len)
+                .ok_or(DeclarationError::Truncated { offset })?;
+            offset += len;
+            Ok(bytes)
+        };
+        let mut string = |field| {
+            let len = u16::from_be_bytes(take(2)?.try_into().unwrap());
+            String::from_utf8(take(len as usize)?.to_vec())
+                .map_err(|_| DeclarationError::InvalidUtf8 { field })
+        };
+        let title = string("title")?;
+        let description = string("description")?;
+        let hash_id_1 = take(32)?.try_into().unwrap();
+        let hash_id_2 = take(20)?.try_into().unwrap();
+        let trailing = body.len() + 1 - offset;
+        if trailing != 0 {
+            return Err(DeclarationError::TrailingBytes { len: trailing });
+        }
+        Ok(SidechainDeclaration {
+            sidechain_number,
+            title,
+            description,
+            hash_id_1,
+            hash_id_2,
+        })
+    }
+}
+
+impl SidechainDeclaration {
+    /// Decode an M1 payload in the format written by [`Self::to_bytes`].
+    pub fn from_bytes(
+        sidechain_number: SidechainNumber,
+        data: &[u8],
+    ) -> Result<Self, DeclarationError> {
+        let (version, body) = data.split_first().ok_or(DeclarationError::Empty)?;
+        DefaultDeclarationDecoder.decode(sidechain_number, *version, body)
+    }
+}
+
+impl CoinbaseMessage {
+    /// The declaration in an M1's payload. `None` if this is not an M1.
+    pub fn sidechain_declaration(&self) -> Option<Result<SidechainDeclaration, DeclarationError>> {
+        match self {
+            Self::M1ProposeSidechain {
+                sidechain_number,
+                data,
+            } => Some(SidechainDeclaration::from_bytes(*sidechain_number, data)),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded M1 with the id M2s acking it carry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodedProposal<T> {
+    pub sidechain_number: SidechainNumber,
+    pub proposal_id: SidechainProposalId,
+    pub declaration: T,
+}
+
+/// Decode the payload of an M1 with `decoder`. `None` if `message` is not an M1.
+pub fn decode_proposal<D: DeclarationDecoder>(
+    decoder: &D,
+    message: &CoinbaseMessage,
+) -> Option<Result<DecodedProposal<D::Declaration>, D::Error>> {
+    let CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number,
+        data,
+    } = message
+    else {
+        return None;
+    };
+    let result = match data.split_first() {
+        None => Err(DeclarationError::Empty.into()),
+        Some((version, body)) => {
+            decoder
+                .decode(*sidechain_number, *version, body)
+                .map(|declaration| DecodedProposal {
+                    sidechain_number: *sidechain_number,
+                    proposal_id: SidechainProposalId::from_data(data),
+                    declaration,
+                })
+        }
+    };
+    Some(result)
+}
@@ -0,0 +1,214 @@
+//! Extension traits for parsing drivechain data from `bitcoin` types.
+
+use bitcoin::{Block, Script, Transaction};
+
+use crate::{
+    messages::{CoinbaseMessage, M8BmmRequest, SidechainNumber},
+    parser::{parse_coinbase_script, parse_m8_bmm_request, try_parse_op_drivechain},
+};
+
+pub trait ScriptExt {
+    /// The coinbase message encoded in this script, if any.
+    fn parse_drivechain(&self) -> Option<CoinbaseMessage>;
+
+    /// The M8 BMM request encoded in this script, if any.
+    fn parse_bmm_request(&self) -> Option<M8BmmRequest>;
+
+    /// The sidechain whose treasury this script locks, if it is an OP_DRIVECHAIN
+    /// output.
+    fn treasury_sidechain(&self) -> Option<SidechainNumber>;
+}
+
+impl ScriptExt for Script {
+    fn parse_drivechain(&self) -> Option<CoinbaseMessage> {
+        parse_coinbase_script(self).ok().map(|(_, message)| message)
+    }
+
+    fn parse_bmm_request(&self) -> Option<M8BmmRequest> {
+        parse_m8_bmm_request(self.as_bytes())
+            .ok()
+            .map(|(_, request)| request)
+    }
+
+    fn treasury_sidechain(&self) -> Option<SidechainNumber> {
+        try_parse_op_drivechain(self)
+    }
+}
+
+/// Classifies a transaction's drivechain outputs. Also exported as
+/// `Bip300Transaction`.
+pub trait TransactionExt {
+    /// Every output that decodes as a coinbase message, with its index. This does
+    /// not check that the transaction is a coinbase, see
+    /// [`crate::validation::check_message_placement`].
+    fn drivechain_messages(&self) -> Vec<(usize, CoinbaseMessage)>;
+
+    /// Every output that decodes as an M8 BMM request, with its index.
+    fn bmm_requests(&self) -> Vec<(usize, M8BmmRequest)>;
+
+    /// Whether any output is an M8 BMM request.
+    fn is_bmm_request(&self) -> bool;
+
+    /// Every OP_DRIVECHAIN treasury output, with its index and sidechain.
+    fn op_drivechain_outputs(&self) -> Vec<(usize, SidechainNumber)>;
+
+    /// Like [`Self::drivechain_messages`], but empty unless the transaction is a
+    /// coinbase, the only place coinbase messages count.
+    fn coinbase_messages(&self) -> Vec<(usize, CoinbaseMessage)>;
+}
+
+impl TransactionExt for Transaction {
+    fn drivechain_messages(&self) -> Vec<(usize, CoinbaseMessage)> {
+        self.output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let message = output.script_pubkey.parse_drivechain()?;
+                Some((vout, message))
+            })
+            .collect()
+    }
+
+    fn bmm_requests(&self) -> Vec<(usize, M8BmmRequest)> {
+        self.output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let request = output.script_pubkey.parse_bmm_request()?;
+                Some((vout, request))
+            })
+            .collect()
+    }
+
+    fn is_bmm_request(&self) -> bool {
+        self.output
+            .iter()
+            .any(|output| output.script_pubkey.parse_bmm_request().is_some())
+    }
+
+    fn op_drivechain_outputs(&self) -> Vec<(usize, SidechainNumber)> {
+        self.output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let sidechain_number = output.script_pubkey.treasury_sidechain()?;
+                Some((vout, sidechain_number))
+            })
+            .collect()
+    }
+
+    fn coinbase_messages(&self) -> Vec<(usize, CoinbaseMessage)> {
+        if self.is_coinbase() {
+            self.drivechain_messages()
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// A message found by [`BlockExt::drivechain_messages`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlockMessage {
+    /// A coinbase message, only looked for in the coinbase transaction.
+    Coinbase(CoinbaseMessage),
+    /// An M8 BMM request, only looked for in non-coinbase transactions.
+    BmmRequest(M8BmmRequest),
+}
+
+/// Pulls BIP300/301 data out of a block. Also exported as `Bip300Block`.
+pub trait BlockExt {
+    /// Every drivechain message in the block as `(tx_index, vout, message)`, in
+    /// block order. Outputs are parsed as the iterator advances, so callers that
+    /// stop at the first match don't pay for the rest of the block.
+    fn drivechain_messages(&self) -> BlockMessages<'_>;
+
+    /// Every coinbase message in the block's coinbase, with its output index.
+    fn coinbase_messages(&self) -> Vec<(usize, CoinbaseMessage)>;
+
+    /// Every M8 BMM request outside the coinbase as `(tx_index, vout, request)`.
+    fn bmm_requests(&self) -> Vec<(usize, usize, M8BmmRequest)>;
+
+    /// Every OP_DRIVECHAIN treasury output in the block as
+    /// `(tx_index, vout, sidechain_number)`.
+    fn drivechain_outputs(&self) -> Vec<(usize, usize, SidechainNumber)>;
+}
+
+impl BlockExt for Block {
+    fn drivechain_messages(&self) -> BlockMessages<'_> {
+        BlockMessages {
+            block: self,
+            tx_index: 0,
+            vout: 0,
+        }
+    }
+
+    fn coinbase_messages(&self) -> Vec<(usize, CoinbaseMessage)> {
+        self.txdata
+            .first()
+            .map(TransactionExt::drivechain_messages)
+            .unwrap_or_default()
+    }
+
+    fn bmm_requests(&self) -> Vec<(usize, usize, M8BmmRequest)> {
+        self.txdata
+            .iter()
+            .enumerate()
+            .skip(1)
+            .flat_map(|(tx_index, tx)| {
+                tx.bmm_requests()
+                    .into_iter()
+                    .map(move |(vout, request)| (tx_index, vout, request))
+            })
+            .collect()
+    }
+
+    fn drivechain_outputs(&self) -> Vec<(usize, usize, SidechainNumber)> {
+        self.txdata
+            .iter()
+            .enumerate()
+            .flat_map(|(tx_index, tx)| {
+                tx.op_drivechain_outputs()
+                    .into_iter()
+                    .map(move |(vout, sidechain_number)| (tx_index, vout, sidechain_number))
+            })
+            .collect()
+    }
+}
+
+/// Iterator returned by [`BlockExt::drivechain_messages`].
+#[derive(Clone, Debug)]
+pub struct BlockMessages<'a> {
+    block: &'a Block,
+    tx_index: usize,
+    vout: usize,
+}
+
+impl Iterator for BlockMessages<'_> {
+    type Item = (usize, usize, BlockMessage);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(tx) = self.block.txdata.get(self.tx_index) {
+            while let Some(output) = tx.output.get(self.vout) {
+                let (tx_index, vout) = (self.tx_index, self.vout);
+                self.vout += 1;
+                let message = if tx_index == 0 {
+                    output
+                        .script_pubkey
+                        .parse_drivechain()
+                        .map(BlockMessage::Coinbase)
+                } else {
+                    output
+                        .script_pubkey
+                        .parse_bmm_request()
+                        .map(BlockMessage::BmmRequest)
+                };
+                if let Some(message) = message {
+                    return Some((tx_index, vout, message));
+                }
+            }
+            self.tx_index += 1;
+            self.vout = 0;
+        }
+        None
+    }
+}
@@ -0,0 +1,155 @@
+//! Experimental coinbase messages defined outside this crate.
+//!
+//! An extension message is framed like the standard ones, `OP_RETURN` then a
+//! 4-byte tag, and its payload is whatever follows. Downstream crates register a
+//! tag with an encoder and decoder for their own payload type, and
+//! [`parse_with_extensions`] falls back to the registry for tags the standard
+//! parser doesn't know.
+
+use std::{any::Any, collections::BTreeMap, fmt};
+
+use bitcoin::{opcodes::all::OP_RETURN, Script, ScriptBuf};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    messages::{CoinbaseMessage, COINBASE_MESSAGE_TAGS},
+    parser::{parse_coinbase_script_versioned, SpecVersion},
+};
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum ExtensionError {
+    #[error("tag {0:02x?} belongs to a standard message")]
+    StandardTag([u8; 4]),
+    #[error("tag {0:02x?} is already registered")]
+    AlreadyRegistered([u8; 4]),
+}
+
+type Decoder = Box<dyn Fn(&[u8]) -> Option<Box<dyn Any + Send + Sync>> + Send + Sync>;
+type Encoder = Box<dyn Fn(&dyn Any) -> Option<Vec<u8>> + Send + Sync>;
+
+struct Codec {
+    name: String,
+    decode: Decoder,
+    encode: Encoder,
+}
+
+/// The extension tags known to a parser, with their codecs.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    codecs: BTreeMap<[u8; 4], Codec>,
+}
+
+impl fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.codecs.iter().map(|(tag, codec)| (tag, &codec.name)))
+            .finish()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `tag` for payloads of type `T`. `decode` gets the bytes after the
+    /// tag and returns `None` if they are malformed. See [`crate::derive_tag`]
+    /// for picking a tag; it may not collide with a standard one.
+    pub fn register<T: Any + Send + Sync>(
+        &mut self,
+        tag: [u8; 4],
+        name: impl Into<String>,
+        decode: fn(&[u8]) -> Option<T>,
+        encode: fn(&T) -> Vec<u8>,
+    ) -> Result<(), ExtensionError> {
+        if COINBASE_MESSAGE_TAGS.contains(&tag.as_slice()) {
+            return Err(ExtensionError::StandardTag(tag));
+        }
+        if self.codecs.contains_key(&tag) {
+            return Err(ExtensionError::AlreadyRegistered(tag));
+        }
+        let codec = Codec {
+            name: name.into(),
+            decode: Box::new(move |payload: &[u8]| {
+                decode(payload).map(|value| Box::new(value) as Box<dyn Any + Send + Sync>)
+            }),
+            encode: Box::new(move |value: &dyn Any| value.downcast_ref::<T>().map(encode)),
+        };
+        self.codecs.insert(tag, codec);
+        Ok(())
+    }
+
+    pub fn contains(&self, tag: [u8; 4]) -> bool {
+        self.codecs.contains_key(&tag)
+    }
+
+    /// The name `tag` was registered under.
+    pub fn name(&self, tag: [u8; 4]) -> Option<&str> {
+        self.codecs.get(&tag).map(|codec| codec.name.as_str())
+    }
+
+    /// Encode `value` as the script of the message registered under `tag`, or
+    /// `None` if the tag is unknown or was registered for another type.
+    pub fn encode<T: Any>(&self, tag: [u8; 4], value: &T) -> Option<ScriptBuf> {
+        let payload = (self.codecs.get(&tag)?.encode)(value as &dyn Any)?;
+        let mut script = vec![OP_RETURN.to_u8()];
+        script.extend_from_slice(&tag);
+        script.extend_from_slice(&payload);
+        Some(ScriptBuf::from_bytes(script))
+    }
+
+    /// Decode `script` if it carries a registered tag.
+    pub fn decode(&self, script: &Script) -> Option<ExtensionMessage> {
+        let [op_return, t0, t1, t2, t3, payload @ ..] = script.as_bytes() else {
+            return None;
+        };
+        if *op_return != OP_RETURN.to_u8() {
+            return None;
+        }
+        let tag = [*t0, *t1, *t2, *t3];
+        let value = (self.codecs.get(&tag)?.decode)(payload)?;
+        Some(ExtensionMessage { tag, value })
+    }
+}
+
+/// A decoded extension message. Use [`Self::downcast_ref`] with the type its
+/// tag was registered for.
+#[derive(Debug)]
+pub struct ExtensionMessage {
+    pub tag: [u8; 4],
+    value: Box<dyn Any + Send + Sync>,
+}
+
+impl ExtensionMessage {
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+
+    pub fn downcast<T: Any>(self) -> Result<T, Self> {
+        let tag = self.tag;
+        match self.value.downcast() {
+            Ok(value) => Ok(*value),
+            Err(value) => Err(Self { tag, value }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AnyMessage {
+    Standard(CoinbaseMessage),
+    Extension(ExtensionMessage),
+}
+
+/// Decode `script` as a standard coinbase message, falling back to `registry`
+/// for tags the standard parser doesn't know.
+pub fn parse_with_extensions(
+    script: &Script,
+    spec_version: SpecVersion,
+    registry: &ExtensionRegistry,
+) -> Option<AnyMessage> {
+    if let Ok((_, message)) = parse_coinbase_script_versioned(script, spec_version) {
+        return Some(AnyMessage::Standard(message));
+    }
+    registry.decode(script).map(AnyMessage::Extension)
+}
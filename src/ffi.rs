@@ -0,0 +1,77 @@
+//! UniFFI bindings for mobile wallets: the deposit script of a sidechain, M7
+//! parsing to confirm a BMM request was accepted, and M8 encoding. Hashes cross
+//! the boundary as 32-byte arrays in the order they appear in scripts.
+
+use bitcoin::Script;
+use thiserror::Error;
+
+use crate::{
+    messages::{CoinbaseMessage, M8BmmRequest, SidechainNumber},
+    parser::parse_coinbase_script_strict,
+    treasury::create_op_drivechain_script,
+};
+
+#[derive(Debug, Error, uniffi::Error)]
+pub enum FfiError {
+    #[error("expected a 32-byte hash, got {len} bytes")]
+    InvalidHash { len: u64 },
+    #[error("script is not an M7 BMM accept")]
+    NotBmmAccept,
+    #[error("{message}")]
+    Malformed { message: String },
+}
+
+fn hash(bytes: Vec<u8>) -> Result<[u8; 32], FfiError> {
+    let len = bytes.len() as u64;
+    bytes.try_into().map_err(|_| FfiError::InvalidHash { len })
+}
+
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct BmmRequest {
+    pub sidechain_number: u8,
+    pub sidechain_block_hash: Vec<u8>,
+    pub prev_mainchain_block_hash: Vec<u8>,
+}
+
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct BmmAccept {
+    pub sidechain_number: u8,
+    pub sidechain_block_hash: Vec<u8>,
+}
+
+/// The script of an output depositing to `sidechain_number`'s treasury.
+#[uniffi::export]
+pub fn deposit_script(sidechain_number: u8) -> Vec<u8> {
+    create_op_drivechain_script(SidechainNumber(sidechain_number)).into_bytes()
+}
+
+/// The `OP_RETURN` script carrying `request`.
+#[uniffi::export]
+pub fn encode_bmm_request(request: BmmRequest) -> Result<Vec<u8>, FfiError> {
+    let request = M8BmmRequest::new(
+        SidechainNumber(request.sidechain_number),
+        hash(request.sidechain_block_hash)?,
+        hash(request.prev_mainchain_block_hash)?,
+    );
+    Ok(request.encode().into_bytes())
+}
+
+/// Decode a coinbase output as the M7 accepting a BMM request.
+#[uniffi::export]
+pub fn parse_bmm_accept(script: Vec<u8>) -> Result<BmmAccept, FfiError> {
+    let message = parse_coinbase_script_strict(Script::from_bytes(&script)).map_err(|err| {
+        FfiError::Malformed {
+            message: err.to_string(),
+        }
+    })?;
+    match message {
+        CoinbaseMessage::M7BmmAccept {
+            sidechain_number,
+            sidechain_block_hash,
+        } => Ok(BmmAccept {
+            sidechain_number: sidechain_number.0,
+            sidechain_block_hash: sidechain_block_hash.to_vec(),
+        }),
+        _ => Err(FfiError::NotBmmAccept),
+    }
+}
@@ -0,0 +1,342 @@
+//! Glue between drivechain coinbase outputs and Bitcoin Core's
+//! `getblocktemplate`.
+//!
+//! [`BlockTemplate`] holds the fields of a template response a pool needs to
+//! build a block. With the `serde` feature it deserializes from the JSON
+//! `getblocktemplate` returns, ignoring the fields it doesn't model.
+
+use bitcoin::{
+    absolute::LockTime,
+    block::{Header, Version},
+    consensus::encode::VarInt,
+    hashes::Hash,
+    script::{Builder, PushBytes},
+    transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness, Wtxid,
+};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    ext::TransactionExt,
+    messages::{M8BmmRequest, SidechainNumber},
+    state::Bip300State,
+    validation::{validate_block, BlockEffects, ValidationError, Violation},
+};
+
+/// Weight units per byte of non-witness data, and sigop cost per legacy sigop.
+const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// Bytes in a serialized block header.
+const HEADER_SIZE: u64 = 80;
+
+/// A transaction as `getblocktemplate` lists it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemplateTransaction {
+    #[cfg_attr(feature = "serde", serde(with = "consensus_hex"))]
+    pub data: Transaction,
+    pub txid: Txid,
+    pub hash: Wtxid,
+    /// 1-based indexes of the template transactions this one spends from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub depends: Vec<u32>,
+    #[cfg_attr(feature = "serde", serde(with = "bitcoin::amount::serde::as_sat"))]
+    pub fee: Amount,
+    /// Sigop cost, in the units of [`BlockTemplate::sigop_limit`].
+    pub sigops: u64,
+    pub weight: u64,
+}
+
+/// The parts of a `getblocktemplate` response needed to build a block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockTemplate {
+    pub version: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "previousblockhash"))]
+    pub previous_block_hash: BlockHash,
+    /// Every transaction to include after the coinbase, in order.
+    pub transactions: Vec<TemplateTransaction>,
+    /// The block reward plus the fees of `transactions`: the most the coinbase
+    /// may pay out.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "coinbasevalue", with = "bitcoin::amount::serde::as_sat")
+    )]
+    pub coinbase_value: Amount,
+    #[cfg_attr(feature = "serde", serde(rename = "sigoplimit"))]
+    pub sigop_limit: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "weightlimit"))]
+    pub weight_limit: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "curtime"))]
+    pub time: u32,
+    #[cfg_attr(feature = "serde", serde(with = "bits_hex"))]
+    pub bits: CompactTarget,
+    pub height: u32,
+    /// The witness commitment output the coinbase needs if any transaction has
+    /// a witness.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub default_witness_commitment: Option<ScriptBuf>,
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("coinbase outputs need {required}, but the template allows {coinbase_value}")]
+    CoinbaseValue {
+        coinbase_value: Amount,
+        required: Amount,
+    },
+    #[error("coinbase script is {len} bytes, outside the 2 to 100 allowed")]
+    ScriptSigLength { len: usize },
+    #[error("block would weigh {weight}, over the limit of {weight_limit}")]
+    Weight { weight: u64, weight_limit: u64 },
+    #[error("block would have a sigop cost of {sigops}, over the limit of {sigop_limit}")]
+    Sigops { sigops: u64, sigop_limit: u64 },
+}
+
+impl BlockTemplate {
+    /// The weight of the block this template makes with `coinbase`.
+    pub fn block_weight(&self, coinbase: &Transaction) -> u64 {
+        let tx_count = VarInt(self.transactions.len() as u64 + 1).size() as u64;
+        (HEADER_SIZE + tx_count) * WITNESS_SCALE_FACTOR
+            + coinbase.weight().to_wu()
+            + self.transactions.iter().map(|tx| tx.weight).sum::<u64>()
+    }
+
+    /// The sigop cost of the block this template makes with `coinbase`. A
+    /// coinbase has no inputs to count P2SH or witness sigops for, so only its
+    /// legacy sigops count.
+    pub fn block_sigops(&self, coinbase: &Transaction) -> u64 {
+        let coinbase_sigops = coinbase
+            .input
+            .iter()
+            .map(|input| &input.script_sig)
+            .chain(coinbase.output.iter().map(|output| &output.script_pubkey))
+            .map(|script| script.count_sigops_legacy() as u64)
+            .sum::<u64>();
+        coinbase_sigops * WITNESS_SCALE_FACTOR
+            + self.transactions.iter().map(|tx| tx.sigops).sum::<u64>()
+    }
+
+    /// Check that `coinbase` pays out no more than the template allows and that
+    /// the block it makes stays within the weight and sigop limits.
+    pub fn check_coinbase(&self, coinbase: &Transaction) -> Result<(), TemplateError> {
+        let required = coinbase
+            .output
+            .iter()
+            .try_fold(Amount::ZERO, |total, output| {
+                total.checked_add(output.value)
+            })
+            .unwrap_or(Amount::MAX);
+        if required > self.coinbase_value {
+            return Err(TemplateError::CoinbaseValue {
+                coinbase_value: self.coinbase_value,
+                required,
+            });
+        }
+        let weight = self.block_weight(coinbase);
+        if weight > self.weight_limit {
+            return Err(TemplateError::Weight {
+                weight,
+                weight_limit: self.weight_limit,
+            });
+        }
+        let sigops = self.block_sigops(coinbase);
+        if sigops > self.sigop_limit {
+            return Err(TemplateError::Sigops {
+                sigops,
+                sigop_limit: self.sigop_limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// A coinbase for this template with the drivechain outputs of e.g.
+    /// [`crate::template::assemble_coinbase_outputs`] merged in. The first output
+    /// pays whatever the drivechain outputs leave of the coinbase value to
+    /// `payout`; the witness commitment, if the template has one, goes last. The
+    /// script sig is the BIP34 height followed by `extra_nonce`.
+    pub fn build_coinbase(
+        &self,
+        payout: ScriptBuf,
+        drivechain_outputs: Vec<TxOut>,
+        extra_nonce: &PushBytes,
+    ) -> Result<Transaction, TemplateError> {
+        let drivechain_value = drivechain_outputs
+            .iter()
+            .try_fold(Amount::ZERO, |total, output| {
+                total.checked_add(output.value)
+            })
+            .unwrap_or(Amount::MAX);
+        let Some(payout_value) = self.coinbase_value.checked_sub(drivechain_value) else {
+            return Err(TemplateError::CoinbaseValue {
+                coinbase_value: self.coinbase_value,
+                required: drivechain_value,
+            });
+        };
+        let script_sig = Builder::new()
+            .push_int(self.height.into())
+            .push_slice(extra_nonce)
+            .into_script();
+        if !(2..=100).contains(&script_sig.len()) {
+            return Err(TemplateError::ScriptSigLength {
+                len: script_sig.len(),
+            });
+        }
+        let mut witness = Witness::new();
+        let mut output = vec![TxOut {
+            value: payout_value,
+            script_pubkey: payout,
+        }];
+        output.extend(drivechain_outputs);
+        if let Some(commitment) = &self.default_witness_commitment {
+            // The witness reserved value the commitment was computed with.
+            witness.push([0; 32]);
+            output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: commitment.clone(),
+            });
+        }
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig,
+                sequence: Sequence::MAX,
+                witness,
+            }],
+            output,
+        };
+        self.check_coinbase(&coinbase)?;
+        Ok(coinbase)
+    }
+
+    /// The block this template makes with `coinbase`, with a zero nonce.
+    pub fn to_block(&self, coinbase: Transaction) -> Block {
+        let mut block = Block {
+            header: Header {
+                version: Version::from_consensus(self.version),
+                prev_blockhash: self.previous_block_hash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: self.time,
+                bits: self.bits,
+                nonce: 0,
+            },
+            txdata: std::iter::once(coinbase)
+                .chain(self.transactions.iter().map(|tx| tx.data.clone()))
+                .collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("block has a coinbase");
+        block
+    }
+
+    /// Check the block this template makes with `coinbase` against every BIP300
+    /// rule on top of `state`, see [`validate_block`]. Once `state` has a tip,
+    /// the template has to be for the height after it and build on it.
+    pub fn audit(
+        &self,
+        coinbase: Transaction,
+        state: &Bip300State,
+    ) -> Result<BlockEffects, ValidationError> {
+        if let Some((_, tip)) = state.tip() {
+            let mut violations = vec![];
+            let expected = state.next_height();
+            if self.height != expected {
+                violations.push(Violation::UnexpectedHeight {
+                    expected,
+                    height: self.height,
+                });
+            }
+            if self.previous_block_hash != tip {
+                violations.push(Violation::NotOnTip {
+                    tip,
+                    prev_blockhash: self.previous_block_hash,
+                });
+            }
+            if !violations.is_empty() {
+                return Err(ValidationError { violations });
+            }
+        }
+        validate_block(&self.to_block(coinbase), state)
+    }
+
+    /// The drivechain data in the template's transactions, which needs no chain
+    /// state to find.
+    pub fn drivechain_data(&self) -> TemplateDrivechainData {
+        let mut data = TemplateDrivechainData::default();
+        for tx in &self.transactions {
+            data.bmm_requests.extend(
+                tx.data
+                    .bmm_requests()
+                    .into_iter()
+                    .map(|(vout, request)| (tx.txid, vout, request)),
+            );
+            data.treasury_outputs.extend(
+                tx.data
+                    .op_drivechain_outputs()
+                    .into_iter()
+                    .map(|(vout, sidechain_number)| (tx.txid, vout, sidechain_number)),
+            );
+        }
+        data
+    }
+}
+
+/// Drivechain data found in a template's transactions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TemplateDrivechainData {
+    /// Every M8 as `(txid, vout, request)`.
+    pub bmm_requests: Vec<(Txid, usize, M8BmmRequest)>,
+    /// Every OP_DRIVECHAIN output, i.e. the deposits and withdrawals, as
+    /// `(txid, vout, sidechain_number)`.
+    pub treasury_outputs: Vec<(Txid, usize, SidechainNumber)>,
+}
+
+#[cfg(feature = "serde")]
+mod consensus_hex {
+    use bitcoin::{
+        consensus::encode::{self, serialize_hex},
+        hex::FromHex,
+        Transaction,
+    };
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        tx: &Transaction,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&serialize_hex(tx))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Transaction, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let bytes = Vec::from_hex(&hex).map_err(de::Error::custom)?;
+        encode::deserialize(&bytes).map_err(de::Error::custom)
+    }
+}
+
+/// `bits` as the big-endian hex string `getblocktemplate` uses.
+#[cfg(feature = "serde")]
+mod bits_hex {
+    use bitcoin::CompactTarget;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        bits: &CompactTarget,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:08x}", bits.to_consensus()))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<CompactTarget, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u32::from_str_radix(&hex, 16)
+            .map(CompactTarget::from_consensus)
+            .map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,72 @@
+//! A tonic implementation of the `MessageService` in
+//! `proto/bip300301_service.proto`, to run as a sidecar for infrastructure that
+//! can't link this crate.
+
+use bitcoin::{consensus, ScriptBuf, Transaction};
+use tonic::{Request, Response, Status};
+
+use crate::{messages, parser::parse_coinbase_script_strict};
+
+/// Generated service types.
+pub mod pb {
+    tonic::include_proto!("bip300301");
+}
+
+pub use pb::message_service_server::MessageServiceServer;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageService;
+
+impl MessageService {
+    /// The service ready to add to a `tonic` server.
+    pub fn into_server(self) -> MessageServiceServer<Self> {
+        MessageServiceServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl pb::message_service_server::MessageService for MessageService {
+    async fn decode_coinbase_script(
+        &self,
+        request: Request<pb::DecodeCoinbaseScriptRequest>,
+    ) -> Result<Response<pb::DecodeCoinbaseScriptResponse>, Status> {
+        let script = ScriptBuf::from_bytes(request.into_inner().script);
+        let message = parse_coinbase_script_strict(&script)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Ok(Response::new(pb::DecodeCoinbaseScriptResponse {
+            message: Some(message.into()),
+        }))
+    }
+
+    async fn encode_coinbase_message(
+        &self,
+        request: Request<pb::EncodeCoinbaseMessageRequest>,
+    ) -> Result<Response<pb::EncodeCoinbaseMessageResponse>, Status> {
+        let message = request
+            .into_inner()
+            .message
+            .ok_or_else(|| Status::invalid_argument("missing message"))?;
+        let message = messages::CoinbaseMessage::try_from(message)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let script = message
+            .to_script()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Ok(Response::new(pb::EncodeCoinbaseMessageResponse {
+            script: script.into_bytes(),
+        }))
+    }
+
+    async fn compute_m6_id(
+        &self,
+        request: Request<pb::ComputeM6IdRequest>,
+    ) -> Result<Response<pb::ComputeM6IdResponse>, Status> {
+        let request = request.into_inner();
+        let m6: Transaction = consensus::deserialize(&request.transaction)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let m6_id = messages::m6_to_id(&m6, request.previous_treasury_utxo_total)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Ok(Response::new(pb::ComputeM6IdResponse {
+            m6_id: m6_id.to_vec(),
+        }))
+    }
+}
@@ -0,0 +1,118 @@
+//! Per-network consensus parameters for sidechain activation and bundle votes.
+
+use bitcoin::Network;
+
+/// Thresholds and ages the proposal and bundle trackers enforce.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Params {
+    /// Acks a proposal for an unused slot needs to activate.
+    pub unused_slot_ack_threshold: u32,
+    /// Blocks a proposal for an unused slot has to collect its acks in.
+    pub unused_slot_max_age: u32,
+    /// Acks a proposal replacing an active sidechain needs to activate.
+    pub used_slot_ack_threshold: u32,
+    /// Blocks a proposal replacing an active sidechain has to collect its acks
+    /// in.
+    pub used_slot_max_age: u32,
+    /// Score a bundle needs before its M6 may be included.
+    pub bundle_ack_threshold: u16,
+    /// Blocks a bundle has to reach `bundle_ack_threshold` in.
+    pub bundle_max_age: u32,
+    /// Slots sidechains may be proposed for, counting from 0.
+    pub max_sidechain_slots: usize,
+}
+
+impl Params {
+    pub const fn mainnet() -> Self {
+        Params {
+            unused_slot_ack_threshold: 1815,
+            unused_slot_max_age: 2016,
+            used_slot_ack_threshold: 13_150,
+            used_slot_max_age: 26_300,
+            bundle_ack_threshold: 13_150,
+            bundle_max_age: 26_300,
+            max_sidechain_slots: 256,
+        }
+    }
+
+    pub const fn testnet() -> Self {
+        Self::mainnet()
+    }
+
+    pub const fn signet() -> Self {
+        Self::mainnet()
+    }
+
+    /// Small enough that a test can mine a sidechain through activation and a
+    /// payout in a few dozen blocks.
+    pub const fn regtest() -> Self {
+        Params {
+            unused_slot_ack_threshold: 6,
+            unused_slot_max_age: 10,
+            used_slot_ack_threshold: 12,
+            used_slot_max_age: 20,
+            bundle_ack_threshold: 6,
+            bundle_max_age: 10,
+            max_sidechain_slots: 256,
+        }
+    }
+
+    /// Set the acks a proposal for an unused slot needs.
+    pub const fn with_activation_threshold(mut self, acks: u32) -> Self {
+        self.unused_slot_ack_threshold = acks;
+        self
+    }
+
+    /// Set the blocks a proposal for an unused slot has to collect its acks in.
+    pub const fn with_activation_max_age(mut self, blocks: u32) -> Self {
+        self.unused_slot_max_age = blocks;
+        self
+    }
+
+    /// Set the acks a proposal replacing an active sidechain needs.
+    pub const fn with_replacement_threshold(mut self, acks: u32) -> Self {
+        self.used_slot_ack_threshold = acks;
+        self
+    }
+
+    /// Set the blocks a proposal replacing an active sidechain has to collect its
+    /// acks in.
+    pub const fn with_replacement_max_age(mut self, blocks: u32) -> Self {
+        self.used_slot_max_age = blocks;
+        self
+    }
+
+    /// Set the score a bundle needs before its M6 may be included.
+    pub const fn with_bundle_threshold(mut self, score: u16) -> Self {
+        self.bundle_ack_threshold = score;
+        self
+    }
+
+    /// Set the blocks a bundle has to become spendable in.
+    pub const fn with_bundle_max_age(mut self, blocks: u32) -> Self {
+        self.bundle_max_age = blocks;
+        self
+    }
+
+    pub const fn with_max_sidechain_slots(mut self, slots: usize) -> Self {
+        self.max_sidechain_slots = slots;
+        self
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+impl From<Network> for Params {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => Self::mainnet(),
+            Network::Signet => Self::signet(),
+            Network::Regtest => Self::regtest(),
+            _ => Self::testnet(),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Decoding of drivechain messages from scripts.
+
+#[cfg(feature = "std")]
+use std::{str::FromStr, sync::OnceLock};
+
+#[cfg(feature = "std")]
+use bitcoin::{
+    hex::{FromHex, HexToBytesError},
+    ScriptBuf,
+};
+use bitcoin::{
+    opcodes::{all::OP_PUSHBYTES_1, OP_TRUE},
+    Script, TxOut,
+};
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "std")]
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::messages::{
+    CoinbaseMessage, CoinbaseMessageRef, M4AckBundles, M8BmmRequest, SidechainNumber, Vote, Votes,
+    LEADING_BY_50_TAG, M1_PROPOSE_SIDECHAIN_PREFIX, M2_ACK_SIDECHAIN_PREFIX,
+    M3_PROPOSE_BUNDLE_PREFIX, M4_ACK_BUNDLES_PREFIX, M7_BMM_ACCEPT_PREFIX, M8_BMM_REQUEST_PREFIX,
+    ONE_BYTE_TAG, OP_DRIVECHAIN, REPEAT_PREVIOUS_TAG, TWO_BYTES_TAG,
+};
+
+/// Revision of the BIP300/301 drafts that decides which encodings are accepted.
+/// The revisions are those of `bip-0300.mediawiki` and `bip-0301.mediawiki` in
+/// the bitcoin/bips repository.
+///
+/// Every revision shares the message tags in [`crate::messages`]. Scripts using
+/// tag bytes from before the drafts were submitted there, as some early
+/// drivechain testnets did, are not decoded by any revision and fail with
+/// [`Bip300Error::UnknownTag`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SpecVersion {
+    /// The drafts before the M4 `LeadingBy50` subtype (`0x03`) was added, which
+    /// older testnets ran. A `LeadingBy50` M4 fails with
+    /// [`Bip300Error::UnknownM4Subtype`].
+    V1,
+    /// The current drafts.
+    #[default]
+    V2,
+}
+
+impl SpecVersion {
+    pub(crate) fn has_leading_by_50(self) -> bool {
+        self >= Self::V2
+    }
+}
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[cfg_attr(feature = "std", derive(Diagnostic))]
+pub enum Bip300Error {
+    #[error("script does not start with a known drivechain message tag")]
+    UnknownTag,
+    #[error("unknown M4 subtype {subtype:#04x} at byte {offset}")]
+    UnknownM4Subtype { offset: usize, subtype: u8 },
+    #[error("script ends unexpectedly at byte {offset}")]
+    Truncated { offset: usize },
+    #[error("expected a {expected}-byte hash at byte {offset}, got {got} bytes")]
+    TruncatedHash {
+        offset: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("two-byte M4 votes end in an odd byte at byte {offset}")]
+    OddVotePayload { offset: usize },
+    #[error("expected OP_TRUE at byte {offset}")]
+    MissingOpTrue { offset: usize },
+    #[error("{len} unexpected byte(s) after the message at byte {offset}")]
+    TrailingBytes { offset: usize, len: usize },
+}
+
+impl Bip300Error {
+    /// Whether the script simply isn't the kind of message that was parsed for,
+    /// rather than one carrying its tag that is corrupt.
+    pub fn is_unknown_tag(&self) -> bool {
+        matches!(self, Self::UnknownTag)
+    }
+}
+
+/// The rest of the input after a parsed value, or where and why parsing failed.
+/// Offsets in errors are from the start of the script.
+pub type ParseResult<'a, T> = Result<(&'a [u8], T), Bip300Error>;
+
+fn offset(script: &[u8], input: &[u8]) -> usize {
+    script.len() - input.len()
+}
+
+pub fn parse_coinbase_script<'a>(script: &'a Script) -> ParseResult<'a, CoinbaseMessage> {
+    parse_coinbase_script_versioned(script, SpecVersion::default())
+}
+
+/// Parse a coinbase message according to an older (or the current) spec revision,
+/// for decoding historical chain data.
+pub fn parse_coinbase_script_versioned<'a>(
+    script: &'a Script,
+    spec_version: SpecVersion,
+) -> ParseResult<'a, CoinbaseMessage> {
+    parse_coinbase_script_ref_versioned(script, spec_version)
+        .map(|(input, message)| (input, message.into()))
+}
+
+/// Like [`parse_coinbase_script`], but borrows the M1 payload and hashes from
+/// `script` instead of copying them.
+pub fn parse_coinbase_script_ref<'a>(
+    script: &'a Script,
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    parse_coinbase_script_ref_versioned(script, SpecVersion::default())
+}
+
+pub fn parse_coinbase_script_ref_versioned<'a>(
+    script: &'a Script,
+    spec_version: SpecVersion,
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let script = script.as_bytes();
+    if let Some(input) = script.strip_prefix(&M1_PROPOSE_SIDECHAIN_PREFIX) {
+        parse_m1_propose_sidechain(script, input)
+    } else if let Some(input) = script.strip_prefix(&M2_ACK_SIDECHAIN_PREFIX) {
+        parse_m2_ack_sidechain(script, input)
+    } else if let Some(input) = script.strip_prefix(&M3_PROPOSE_BUNDLE_PREFIX) {
+        parse_m3_propose_bundle(script, input)
+    } else if let Some(input) = script.strip_prefix(&M4_ACK_BUNDLES_PREFIX) {
+        parse_m4_ack_bundles(script, input, spec_version)
+    } else if let Some(input) = script.strip_prefix(&M7_BMM_ACCEPT_PREFIX) {
+        parse_m7_bmm_accept(script, input)
+    } else {
+        Err(Bip300Error::UnknownTag)
+    }
+}
+
+/// Parse a treasury output script, `OP_DRIVECHAIN OP_PUSHBYTES_1 <n> OP_TRUE`.
+/// The whole script has to match the template, so the remaining input is always
+/// empty.
+pub fn parse_op_drivechain(input: &[u8]) -> ParseResult<'_, SidechainNumber> {
+    let script = input;
+    let input = input
+        .strip_prefix(&[OP_DRIVECHAIN.to_u8(), OP_PUSHBYTES_1.to_u8()])
+        .ok_or(Bip300Error::UnknownTag)?;
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let input = input
+        .strip_prefix(&[OP_TRUE.to_u8()])
+        .ok_or(Bip300Error::MissingOpTrue {
+            offset: offset(script, input),
+        })?;
+    if !input.is_empty() {
+        return Err(Bip300Error::TrailingBytes {
+            offset: offset(script, input),
+            len: input.len(),
+        });
+    }
+    Ok((input, sidechain_number))
+}
+
+/// The sidechain of a treasury output script, or `None` if `script` isn't one.
+/// Only compares the script's four bytes against the template, for scanning
+/// every output of every transaction; use [`parse_op_drivechain`] to find out
+/// why a script doesn't match.
+pub fn try_parse_op_drivechain(script: &Script) -> Option<SidechainNumber> {
+    match *script.as_bytes() {
+        [op_drivechain, push, sidechain_number, op_true]
+            if op_drivechain == OP_DRIVECHAIN.to_u8()
+                && push == OP_PUSHBYTES_1.to_u8()
+                && op_true == OP_TRUE.to_u8() =>
+        {
+            Some(SidechainNumber(sidechain_number))
+        }
+        _ => None,
+    }
+}
+
+fn parse_sidechain_number<'a>(script: &[u8], input: &'a [u8]) -> ParseResult<'a, SidechainNumber> {
+    match input.split_first() {
+        Some((sidechain_number, input)) => Ok((input, SidechainNumber(*sidechain_number))),
+        None => Err(Bip300Error::Truncated {
+            offset: offset(script, input),
+        }),
+    }
+}
+
+fn parse_hash<'a>(script: &[u8], input: &'a [u8]) -> ParseResult<'a, &'a [u8; 32]> {
+    if input.len() < 32 {
+        return Err(Bip300Error::TruncatedHash {
+            offset: offset(script, input),
+            expected: 32,
+            got: input.len(),
+        });
+    }
+    let (hash, input) = input.split_at(32);
+    Ok((input, hash.try_into().unwrap()))
+}
+
+fn parse_m1_propose_sidechain<'a>(
+    script: &[u8],
+    input: &'a [u8],
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let message = CoinbaseMessageRef::M1ProposeSidechain {
+        sidechain_number,
+        data: input,
+    };
+    Ok((&input[input.len()..], message))
+}
+
+fn parse_m2_ack_sidechain<'a>(
+    script: &[u8],
+    input: &'a [u8],
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let (input, data_hash) = parse_hash(script, input)?;
+    let message = CoinbaseMessageRef::M2AckSidechain {
+        sidechain_number,
+        data_hash,
+    };
+    Ok((input, message))
+}
+
+fn parse_m3_propose_bundle<'a>(
+    script: &[u8],
+    input: &'a [u8],
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let (input, bundle_txid) = parse_hash(script, input)?;
+    let message = CoinbaseMessageRef::M3ProposeBundle {
+        sidechain_number,
+        bundle_txid,
+    };
+    Ok((input, message))
+}
+
+fn parse_m4_ack_bundles<'a>(
+    script: &[u8],
+    input: &'a [u8],
+    spec_version: SpecVersion,
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let subtype_offset = offset(script, input);
+    let Some((&subtype, input)) = input.split_first() else {
+        return Err(Bip300Error::Truncated {
+            offset: subtype_offset,
+        });
+    };
+    let m4_ack_bundles = match [subtype].as_slice() {
+        REPEAT_PREVIOUS_TAG => M4AckBundles::RepeatPrevious,
+        ONE_BYTE_TAG => {
+            let upvotes = input.iter().copied().map(Vote::from_one_byte).collect();
+            let message = CoinbaseMessageRef::M4AckBundles(M4AckBundles::OneByte { upvotes });
+            return Ok((&input[input.len()..], message));
+        }
+        TWO_BYTES_TAG => {
+            // A trailing odd byte would otherwise be silently dropped from the votes.
+            if input.len() % 2 != 0 {
+                return Err(Bip300Error::OddVotePayload {
+                    offset: script.len() - 1,
+                });
+            }
+            let upvotes: Votes = input
+                .chunks_exact(2)
+                .map(|upvote| Vote::from_two_bytes(BigEndian::read_u16(upvote)))
+                .collect();
+            let message = CoinbaseMessageRef::M4AckBundles(M4AckBundles::TwoBytes { upvotes });
+            return Ok((&input[input.len()..], message));
+        }
+        LEADING_BY_50_TAG if spec_version.has_leading_by_50() => M4AckBundles::LeadingBy50,
+        _ => {
+            return Err(Bip300Error::UnknownM4Subtype {
+                offset: subtype_offset,
+                subtype,
+            })
+        }
+    };
+    Ok((input, CoinbaseMessageRef::M4AckBundles(m4_ack_bundles)))
+}
+
+fn parse_m7_bmm_accept<'a>(
+    script: &[u8],
+    input: &'a [u8],
+) -> ParseResult<'a, CoinbaseMessageRef<'a>> {
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let (input, sidechain_block_hash) = parse_hash(script, input)?;
+    let message = CoinbaseMessageRef::M7BmmAccept {
+        sidechain_number,
+        sidechain_block_hash,
+    };
+    Ok((input, message))
+}
+
+pub fn parse_m8_bmm_request(input: &[u8]) -> ParseResult<'_, M8BmmRequest> {
+    let script = input;
+    let input = input
+        .strip_prefix(&M8_BMM_REQUEST_PREFIX)
+        .ok_or(Bip300Error::UnknownTag)?;
+    let (input, sidechain_number) = parse_sidechain_number(script, input)?;
+    let (input, sidechain_block_hash) = parse_hash(script, input)?;
+    let (input, prev_mainchain_block_hash) = parse_hash(script, input)?;
+    let message = M8BmmRequest {
+        sidechain_number,
+        sidechain_block_hash: *sidechain_block_hash,
+        prev_mainchain_block_hash: *prev_mainchain_block_hash,
+    };
+    Ok((input, message))
+}
+
+/// Fail on any bytes a parser left over.
+fn complete<T>(script: &[u8], result: ParseResult<'_, T>) -> Result<T, Bip300Error> {
+    match result? {
+        ([], value) => Ok(value),
+        (rest, _) => Err(Bip300Error::TrailingBytes {
+            offset: offset(script, rest),
+            len: rest.len(),
+        }),
+    }
+}
+
+/// Like [`parse_coinbase_script`], but rejects scripts with bytes left over after
+/// the message instead of returning them.
+pub fn parse_coinbase_script_strict(script: &Script) -> Result<CoinbaseMessage, Bip300Error> {
+    parse_coinbase_script_versioned_strict(script, SpecVersion::default())
+}
+
+pub fn parse_coinbase_script_versioned_strict(
+    script: &Script,
+    spec_version: SpecVersion,
+) -> Result<CoinbaseMessage, Bip300Error> {
+    complete(
+        script.as_bytes(),
+        parse_coinbase_script_versioned(script, spec_version),
+    )
+}
+
+/// Like [`parse_m8_bmm_request`], but rejects scripts with bytes left over after
+/// the request instead of returning them.
+pub fn parse_m8_bmm_request_strict(input: &[u8]) -> Result<M8BmmRequest, Bip300Error> {
+    complete(input, parse_m8_bmm_request(input))
+}
+
+/// `None` for a failure that only means the script isn't this kind of message.
+fn known_tag<T>(result: ParseResult<'_, T>) -> Option<Result<T, Bip300Error>> {
+    match result {
+        Err(Bip300Error::UnknownTag) => None,
+        result => Some(result.map(|(_, value)| value)),
+    }
+}
+
+/// Decode `script` if it claims to be a coinbase message: `None` if it doesn't
+/// carry a coinbase message tag at all, `Some(Err(_))` if it does but is
+/// malformed. Lets scanners flag corrupt messages without tripping over
+/// unrelated outputs.
+pub fn detect_coinbase_message(script: &Script) -> Option<Result<CoinbaseMessage, Bip300Error>> {
+    detect_coinbase_message_versioned(script, SpecVersion::default())
+}
+
+pub fn detect_coinbase_message_versioned(
+    script: &Script,
+    spec_version: SpecVersion,
+) -> Option<Result<CoinbaseMessage, Bip300Error>> {
+    known_tag(parse_coinbase_script_versioned(script, spec_version))
+}
+
+/// Like [`detect_coinbase_message`], for M8 BMM requests.
+pub fn detect_m8_bmm_request(input: &[u8]) -> Option<Result<M8BmmRequest, Bip300Error>> {
+    known_tag(parse_m8_bmm_request(input))
+}
+
+impl TryFrom<&Script> for CoinbaseMessage {
+    type Error = Bip300Error;
+
+    fn try_from(script: &Script) -> Result<Self, Self::Error> {
+        parse_coinbase_script(script).map(|(_, message)| message)
+    }
+}
+
+impl TryFrom<&TxOut> for CoinbaseMessage {
+    type Error = Bip300Error;
+
+    fn try_from(output: &TxOut) -> Result<Self, Self::Error> {
+        Self::try_from(output.script_pubkey.as_script())
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum MessageFromStrError {
+    #[error("message is not valid hex")]
+    Hex(#[from] HexToBytesError),
+    #[error(transparent)]
+    Message(#[from] Bip300Error),
+}
+
+/// Reads the hex written by `Display`. Bytes after the message are rejected.
+#[cfg(feature = "std")]
+impl FromStr for CoinbaseMessage {
+    type Err = MessageFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let script = ScriptBuf::from_bytes(Vec::from_hex(s)?);
+        Ok(parse_coinbase_script_strict(&script)?)
+    }
+}
+
+/// Reads the hex written by `Display`. Bytes after the request are rejected.
+#[cfg(feature = "std")]
+impl FromStr for M8BmmRequest {
+    type Err = MessageFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(parse_m8_bmm_request_strict(&Vec::from_hex(s)?)?)
+    }
+}
+
+/// A script whose coinbase message is decoded the first time it is accessed, for
+/// indexers that store every output but only look at a few of them.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct LazyMessage {
+    script: ScriptBuf,
+    message: OnceLock<Option<CoinbaseMessage>>,
+}
+
+#[cfg(feature = "std")]
+impl LazyMessage {
+    pub fn new(script: ScriptBuf) -> Self {
+        LazyMessage {
+            script,
+            message: OnceLock::new(),
+        }
+    }
+
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
+    pub fn into_script(self) -> ScriptBuf {
+        self.script
+    }
+
+    /// The decoded message, or `None` if the script is not a coinbase message.
+    pub fn message(&self) -> Option<&CoinbaseMessage> {
+        self.message
+            .get_or_init(|| {
+                parse_coinbase_script(&self.script)
+                    .ok()
+                    .map(|(_, message)| message)
+            })
+            .as_ref()
+    }
+
+    /// Whether the script has been decoded yet.
+    pub fn is_parsed(&self) -> bool {
+        self.message.get().is_some()
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ScriptBuf> for LazyMessage {
+    fn from(script: ScriptBuf) -> Self {
+        Self::new(script)
+    }
+}
@@ -0,0 +1,180 @@
+//! Relay and block template policy for BMM requests (BIP301).
+//!
+//! None of this is consensus: a block with a stale or duplicate M8 is still
+//! valid. It is what miners and relaying nodes agree to, so that the one M8 per
+//! sidechain a miner can act on is for the tip it is mining on.
+
+use std::collections::BTreeMap;
+
+use bitcoin::{Amount, BlockHash, Transaction, Txid};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    bmm::{find_bmm_request, FindBmmRequestError},
+    messages::{M8BmmRequest, SidechainNumber},
+};
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum BmmPolicyError {
+    #[error(transparent)]
+    Request(#[from] FindBmmRequestError),
+    #[error("transaction carries no M8")]
+    NoRequest,
+    #[error("M8 for sidechain {sidechain_number} builds on {prev_mainchain_block_hash}, not the tip {tip}")]
+    NotOnTip {
+        sidechain_number: SidechainNumber,
+        prev_mainchain_block_hash: BlockHash,
+        tip: BlockHash,
+    },
+    #[error("sidechain {sidechain_number} already has an M8 in {txid}")]
+    SidechainTaken {
+        sidechain_number: SidechainNumber,
+        txid: Txid,
+    },
+    #[error(
+        "M8 for sidechain {sidechain_number} bids {bid}, not more than the {current} of {txid}"
+    )]
+    Outbid {
+        sidechain_number: SidechainNumber,
+        bid: Amount,
+        txid: Txid,
+        current: Amount,
+    },
+}
+
+/// Check that `request` builds on `tip`, the block the next one is mined on.
+pub fn check_bmm_request(request: &M8BmmRequest, tip: BlockHash) -> Result<(), BmmPolicyError> {
+    let prev_mainchain_block_hash = request.prev_mainchain_block_hash();
+    if prev_mainchain_block_hash != tip {
+        return Err(BmmPolicyError::NotOnTip {
+            sidechain_number: request.sidechain_number,
+            prev_mainchain_block_hash,
+            tip,
+        });
+    }
+    Ok(())
+}
+
+/// The BMM requests admitted for the next block: each builds on the current tip,
+/// and there is at most one per sidechain, the highest bid if they were
+/// [`offered`](BmmRequestPool::offer).
+#[derive(Clone, Debug)]
+pub struct BmmRequestPool {
+    tip: BlockHash,
+    requests: BTreeMap<SidechainNumber, (Txid, M8BmmRequest, Amount)>,
+}
+
+impl BmmRequestPool {
+    pub fn new(tip: BlockHash) -> Self {
+        BmmRequestPool {
+            tip,
+            requests: BTreeMap::new(),
+        }
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        self.tip
+    }
+
+    /// The admitted requests by sidechain number, with the transactions carrying
+    /// them.
+    pub fn requests(&self) -> impl Iterator<Item = (Txid, &M8BmmRequest)> {
+        self.requests
+            .values()
+            .map(|(txid, request, _)| (*txid, request))
+    }
+
+    pub fn request(&self, sidechain_number: SidechainNumber) -> Option<(Txid, &M8BmmRequest)> {
+        self.requests
+            .get(&sidechain_number)
+            .map(|(txid, request, _)| (*txid, request))
+    }
+
+    /// What the admitted request for `sidechain_number` bids, zero if it was
+    /// [inserted](Self::insert) without one.
+    pub fn bid(&self, sidechain_number: SidechainNumber) -> Option<Amount> {
+        self.requests.get(&sidechain_number).map(|(_, _, bid)| *bid)
+    }
+
+    /// Check whether `request` would be admitted, without admitting it.
+    pub fn check(&self, request: &M8BmmRequest) -> Result<(), BmmPolicyError> {
+        check_bmm_request(request, self.tip)?;
+        if let Some((txid, _, _)) = self.requests.get(&request.sidechain_number) {
+            return Err(BmmPolicyError::SidechainTaken {
+                sidechain_number: request.sidechain_number,
+                txid: *txid,
+            });
+        }
+        Ok(())
+    }
+
+    /// Admit `request`, carried by `txid`, with no bid. To replace a sidechain's
+    /// request, [`Self::remove`] the old one first, or [`Self::offer`] more.
+    pub fn insert(&mut self, txid: Txid, request: M8BmmRequest) -> Result<(), BmmPolicyError> {
+        self.check(&request)?;
+        self.requests
+            .insert(request.sidechain_number, (txid, request, Amount::ZERO));
+        Ok(())
+    }
+
+    /// Admit `request`, carried by `txid` and paying the miner `bid`, in place of
+    /// the sidechain's current request if it bids less. Returns the transaction
+    /// whose request was replaced. An equal bid keeps the request that came first.
+    pub fn offer(
+        &mut self,
+        txid: Txid,
+        request: M8BmmRequest,
+        bid: Amount,
+    ) -> Result<Option<Txid>, BmmPolicyError> {
+        check_bmm_request(&request, self.tip)?;
+        let sidechain_number = request.sidechain_number;
+        if let Some((current_txid, _, current)) = self.requests.get(&sidechain_number) {
+            if bid <= *current {
+                return Err(BmmPolicyError::Outbid {
+                    sidechain_number,
+                    bid,
+                    txid: *current_txid,
+                    current: *current,
+                });
+            }
+        }
+        Ok(self
+            .requests
+            .insert(sidechain_number, (txid, request, bid))
+            .map(|(replaced, _, _)| replaced))
+    }
+
+    /// Admit the M8 carried by `tx`, see [`find_bmm_request`].
+    pub fn insert_tx(&mut self, tx: &Transaction) -> Result<&M8BmmRequest, BmmPolicyError> {
+        let (_, request) = find_bmm_request(tx)?.ok_or(BmmPolicyError::NoRequest)?;
+        let sidechain_number = request.sidechain_number;
+        self.insert(tx.compute_txid(), request)?;
+        Ok(&self.requests[&sidechain_number].1)
+    }
+
+    /// Drop the request carried by `txid`, e.g. once it is mined or replaced.
+    pub fn remove(&mut self, txid: Txid) -> Option<M8BmmRequest> {
+        let sidechain_number = self
+            .requests
+            .iter()
+            .find(|(_, (request_txid, _, _))| *request_txid == txid)
+            .map(|(sidechain_number, _)| *sidechain_number)?;
+        self.requests
+            .remove(&sidechain_number)
+            .map(|(_, request, _)| request)
+    }
+
+    /// Move to a new tip, evicting every request, since they all built on the
+    /// old one. Returns the transactions carrying the evicted requests.
+    pub fn set_tip(&mut self, tip: BlockHash) -> Vec<Txid> {
+        if tip == self.tip {
+            return vec![];
+        }
+        self.tip = tip;
+        std::mem::take(&mut self.requests)
+            .into_values()
+            .map(|(txid, _, _)| txid)
+            .collect()
+    }
+}
@@ -0,0 +1,16 @@
+//! The types and extension traits most call sites need, for glob import.
+
+pub use crate::{
+    builder::CoinbaseBuilder,
+    ext::{
+        BlockExt, BlockExt as Bip300Block, BlockMessage, ScriptExt, TransactionExt,
+        TransactionExt as Bip300Transaction,
+    },
+    messages::{
+        CoinbaseMessage, HashBytes, M4AckBundles, M8BmmRequest, MessageKind, SidechainBlockHash,
+        SidechainDeclaration, SidechainNumber, SidechainProposalId, SidechainSet, Vote, Votes,
+    },
+    parser::{parse_coinbase_script, parse_m8_bmm_request, parse_op_drivechain},
+    state::{Bip300State, CtipTracker},
+    validation::{ParseConfig, Violation},
+};
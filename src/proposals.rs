@@ -0,0 +1,348 @@
+//! Sidechain proposals and their acks (BIP300's D1).
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use bitcoin::{hashes::Hash, Block};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    ext::BlockExt,
+    messages::{CoinbaseMessage, M1Data, SidechainNumber, SidechainProposalId, SidechainSet},
+    params::Params,
+    state::{changed_entries, restore_entries, Retention},
+};
+
+/// A pending M1.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SidechainProposal {
+    pub sidechain_number: SidechainNumber,
+    pub data: M1Data,
+    /// Height of the block carrying the M1.
+    pub proposal_height: u32,
+    /// Blocks that acked the proposal with an M2.
+    pub acks: u32,
+}
+
+impl SidechainProposal {
+    pub fn id(&self) -> SidechainProposalId {
+        SidechainProposalId::from_data(&self.data)
+    }
+
+    /// Blocks connected on top of the one proposing this, as of `height`.
+    pub fn age(&self, height: u32) -> u32 {
+        height.saturating_sub(self.proposal_height)
+    }
+}
+
+/// A sidechain occupying its slot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ActiveSidechain {
+    pub proposal: SidechainProposal,
+    pub activation_height: u32,
+}
+
+/// A proposal leaving the pending set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProposalEvent {
+    /// The proposal reached its ack threshold and now occupies its slot.
+    /// `replaced` is the sidechain that occupied it before, if any.
+    Activated {
+        sidechain_number: SidechainNumber,
+        proposal_id: SidechainProposalId,
+        replaced: Option<SidechainProposalId>,
+    },
+    /// The proposal can no longer reach its ack threshold before it gets too old.
+    Failed {
+        sidechain_number: SidechainNumber,
+        proposal_id: SidechainProposalId,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum ProposalError {
+    #[error("M2 acks {proposal_id} for sidechain {sidechain_number}, which is not pending")]
+    UnknownProposal {
+        sidechain_number: SidechainNumber,
+        proposal_id: SidechainProposalId,
+    },
+    #[error(
+        "M1 proposes sidechain {sidechain_number}, past the last of {max_sidechain_slots} slots"
+    )]
+    SlotOutOfRange {
+        sidechain_number: SidechainNumber,
+        max_sidechain_slots: usize,
+    },
+}
+
+/// Where a proposal stands after a block.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ProposalStatus {
+    /// Still collecting acks.
+    Pending,
+    /// Reached its ack threshold.
+    Activated,
+    /// Can no longer reach its ack threshold before it gets too old.
+    Failed,
+}
+
+/// The ack threshold and maximum age for a proposal, which depend on whether its
+/// slot is occupied by an active sidechain.
+pub fn proposal_thresholds(slot_used: bool, params: &Params) -> (u32, u32) {
+    if slot_used {
+        (params.used_slot_ack_threshold, params.used_slot_max_age)
+    } else {
+        (params.unused_slot_ack_threshold, params.unused_slot_max_age)
+    }
+}
+
+/// How many more acks a proposal with `acks` needs to activate.
+pub fn proposal_acks_needed(acks: u32, slot_used: bool, params: &Params) -> u32 {
+    let (threshold, _) = proposal_thresholds(slot_used, params);
+    threshold.saturating_sub(acks)
+}
+
+/// The status of a proposal made at `proposal_height` with `acks` as of the
+/// block at `height`. It fails as soon as the blocks left before its maximum
+/// age can't make up the acks it still needs, rather than when it reaches that
+/// age.
+pub fn proposal_status(
+    acks: u32,
+    proposal_height: u32,
+    height: u32,
+    slot_used: bool,
+    params: &Params,
+) -> ProposalStatus {
+    let (threshold, max_age) = proposal_thresholds(slot_used, params);
+    let blocks_left = max_age.saturating_sub(height.saturating_sub(proposal_height));
+    if acks >= threshold {
+        ProposalStatus::Activated
+    } else if acks.saturating_add(blocks_left) < threshold {
+        ProposalStatus::Failed
+    } else {
+        ProposalStatus::Pending
+    }
+}
+
+/// What one block changed in a [`SidechainProposals`], enough to undo it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProposalUndo {
+    pub height: u32,
+    /// Every pending proposal the block added, acked, activated or failed, as it
+    /// was before the block. `None` if the block proposed it.
+    pub pending: BTreeMap<(SidechainNumber, SidechainProposalId), Option<SidechainProposal>>,
+    /// Every slot the block activated a sidechain in, with the sidechain that
+    /// occupied it before.
+    pub active: BTreeMap<SidechainNumber, Option<ActiveSidechain>>,
+}
+
+/// Tracks pending sidechain proposals and active sidechains across blocks.
+/// Activated and failed proposals leave the pending set, and a replaced
+/// sidechain is dropped, so only undo data for the blocks the [`Retention`]
+/// keeps refers to them.
+#[derive(Clone, Debug, Default)]
+pub struct SidechainProposals {
+    params: Params,
+    pending: BTreeMap<(SidechainNumber, SidechainProposalId), SidechainProposal>,
+    active: BTreeMap<SidechainNumber, ActiveSidechain>,
+    retention: Retention,
+    history: VecDeque<ProposalUndo>,
+}
+
+impl SidechainProposals {
+    pub fn new(params: Params) -> Self {
+        SidechainProposals {
+            params,
+            ..Self::default()
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Change the retention policy, dropping any history it no longer allows.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.retention.prune(&mut self.history);
+    }
+
+    /// Undo data for the retained blocks, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &ProposalUndo> {
+        self.history.iter()
+    }
+
+    pub fn proposal(
+        &self,
+        sidechain_number: SidechainNumber,
+        proposal_id: SidechainProposalId,
+    ) -> Option<&SidechainProposal> {
+        self.pending.get(&(sidechain_number, proposal_id))
+    }
+
+    /// Every pending proposal, by sidechain number then proposal id.
+    pub fn proposals(&self) -> impl Iterator<Item = &SidechainProposal> {
+        self.pending.values()
+    }
+
+    pub fn active_sidechain(&self, sidechain_number: SidechainNumber) -> Option<&ActiveSidechain> {
+        self.active.get(&sidechain_number)
+    }
+
+    /// The occupied slots, in the order M4 vote vectors list them.
+    pub fn active_sidechains(&self) -> SidechainSet {
+        self.active.keys().copied().collect()
+    }
+
+    /// The ack threshold and maximum age for a proposal to `sidechain_number`,
+    /// which depend on whether the slot is occupied.
+    pub fn thresholds(&self, sidechain_number: SidechainNumber) -> (u32, u32) {
+        proposal_thresholds(self.active.contains_key(&sidechain_number), &self.params)
+    }
+
+    /// The status of the pending proposal as of `height`, see
+    /// [`proposal_status`].
+    pub fn status(&self, proposal: &SidechainProposal, height: u32) -> ProposalStatus {
+        proposal_status(
+            proposal.acks,
+            proposal.proposal_height,
+            height,
+            self.active.contains_key(&proposal.sidechain_number),
+            &self.params,
+        )
+    }
+
+    /// Apply the M1s and M2s in the coinbase of `block` at `height`.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+    ) -> Result<Vec<ProposalEvent>, ProposalError> {
+        let messages = block
+            .coinbase_messages()
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        self.connect_messages(&messages, height)
+    }
+
+    /// Apply the coinbase `messages` of the block at `height`, ignoring anything
+    /// but M1s and M2s. A block acks each proposal at most once, and can't ack a
+    /// proposal it makes itself. Nothing is applied if an M2 acks a proposal that
+    /// isn't pending, or an M1 proposes a slot past the configured ones.
+    pub fn connect_messages(
+        &mut self,
+        messages: &[CoinbaseMessage],
+        height: u32,
+    ) -> Result<Vec<ProposalEvent>, ProposalError> {
+        let mut acked = BTreeSet::new();
+        for message in messages {
+            if let CoinbaseMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } = message
+            {
+                let proposal_id = SidechainProposalId::from_byte_array(*data_hash);
+                if !self.pending.contains_key(&(*sidechain_number, proposal_id)) {
+                    return Err(ProposalError::UnknownProposal {
+                        sidechain_number: *sidechain_number,
+                        proposal_id,
+                    });
+                }
+                acked.insert((*sidechain_number, proposal_id));
+            }
+            if let CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number, ..
+            } = message
+            {
+                if usize::from(sidechain_number.0) >= self.params.max_sidechain_slots {
+                    return Err(ProposalError::SlotOutOfRange {
+                        sidechain_number: *sidechain_number,
+                        max_sidechain_slots: self.params.max_sidechain_slots,
+                    });
+                }
+            }
+        }
+        let pending = self.pending.clone();
+        let active = self.active.clone();
+        for key in acked {
+            if let Some(proposal) = self.pending.get_mut(&key) {
+                proposal.acks += 1;
+            }
+        }
+        for message in messages {
+            if let CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number,
+                data,
+            } = message
+            {
+                let proposal_id = SidechainProposalId::from_data(data);
+                self.pending
+                    .entry((*sidechain_number, proposal_id))
+                    .or_insert_with(|| SidechainProposal {
+                        sidechain_number: *sidechain_number,
+                        data: data.clone(),
+                        proposal_height: height,
+                        acks: 0,
+                    });
+            }
+        }
+
+        let mut events = Vec::new();
+        let keys = self.pending.keys().copied().collect::<Vec<_>>();
+        for (sidechain_number, proposal_id) in keys {
+            let proposal = &self.pending[&(sidechain_number, proposal_id)];
+            match self.status(proposal, height) {
+                ProposalStatus::Pending => (),
+                ProposalStatus::Activated => {
+                    let proposal = self
+                        .pending
+                        .remove(&(sidechain_number, proposal_id))
+                        .expect("proposal is pending");
+                    let replaced = self.active.insert(
+                        sidechain_number,
+                        ActiveSidechain {
+                            proposal,
+                            activation_height: height,
+                        },
+                    );
+                    events.push(ProposalEvent::Activated {
+                        sidechain_number,
+                        proposal_id,
+                        replaced: replaced.map(|active| active.proposal.id()),
+                    });
+                }
+                ProposalStatus::Failed => {
+                    self.pending.remove(&(sidechain_number, proposal_id));
+                    events.push(ProposalEvent::Failed {
+                        sidechain_number,
+                        proposal_id,
+                    });
+                }
+            }
+        }
+        self.history.push_back(ProposalUndo {
+            height,
+            pending: changed_entries(&pending, &self.pending),
+            active: changed_entries(&active, &self.active),
+        });
+        self.retention.prune(&mut self.history);
+        Ok(events)
+    }
+
+    /// Undo the block at `height`, which must be the last connected one. Returns
+    /// `false`, changing nothing, if the retention policy dropped its history.
+    pub fn disconnect_messages(&mut self, height: u32) -> bool {
+        if self.history.back().map(|undo| undo.height) != Some(height) {
+            return false;
+        }
+        let undo = self.history.pop_back().expect("history is not empty");
+        restore_entries(&mut self.pending, undo.pending);
+        restore_entries(&mut self.active, undo.active);
+        true
+    }
+}
@@ -0,0 +1,271 @@
+//! Protobuf messages for the BIP300/301 message types, matching
+//! `proto/bip300301.proto`, with conversions to and from the types in
+//! [`crate::messages`].
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::messages::{self, SidechainNumber, Vote, Votes};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M1ProposeSidechain {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M2AckSidechain {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data_hash: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M3ProposeBundle {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub bundle_txid: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum M4Kind {
+    RepeatPrevious = 0,
+    OneByte = 1,
+    TwoBytes = 2,
+    LeadingBy50 = 3,
+}
+
+/// [`M4AckBundles::votes`] value for an abstention.
+pub const ABSTAIN: u32 = u32::MAX;
+/// [`M4AckBundles::votes`] value for an alarm.
+pub const ALARM: u32 = u32::MAX - 1;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M4AckBundles {
+    #[prost(enumeration = "M4Kind", tag = "1")]
+    pub kind: i32,
+    /// A bundle index, [`ABSTAIN`] or [`ALARM`] per sidechain.
+    #[prost(uint32, repeated, tag = "2")]
+    pub votes: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M7BmmAccept {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub sidechain_block_hash: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CoinbaseMessage {
+    #[prost(oneof = "coinbase_message::Message", tags = "1, 2, 3, 4, 7")]
+    pub message: Option<coinbase_message::Message>,
+}
+
+pub mod coinbase_message {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Message {
+        #[prost(message, tag = "1")]
+        M1ProposeSidechain(super::M1ProposeSidechain),
+        #[prost(message, tag = "2")]
+        M2AckSidechain(super::M2AckSidechain),
+        #[prost(message, tag = "3")]
+        M3ProposeBundle(super::M3ProposeBundle),
+        #[prost(message, tag = "4")]
+        M4AckBundles(super::M4AckBundles),
+        #[prost(message, tag = "7")]
+        M7BmmAccept(super::M7BmmAccept),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct M8BmmRequest {
+    #[prost(uint32, tag = "1")]
+    pub sidechain_number: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub sidechain_block_hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub prev_mainchain_block_hash: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum ProtoError {
+    #[error("coinbase message has no message set")]
+    MissingMessage,
+    #[error("sidechain number {0} is out of range")]
+    SidechainNumber(u32),
+    #[error("expected a 32-byte hash, got {len} bytes")]
+    HashLength { len: usize },
+    #[error("unknown M4 kind {0}")]
+    M4Kind(i32),
+    #[error("vote {0} is neither a bundle index nor abstain/alarm")]
+    Vote(u32),
+}
+
+fn sidechain_number(sidechain_number: u32) -> Result<SidechainNumber, ProtoError> {
+    u8::try_from(sidechain_number)
+        .map(SidechainNumber)
+        .map_err(|_| ProtoError::SidechainNumber(sidechain_number))
+}
+
+fn hash(hash: Vec<u8>) -> Result<[u8; 32], ProtoError> {
+    let len = hash.len();
+    hash.try_into().map_err(|_| ProtoError::HashLength { len })
+}
+
+fn vote_to_proto(vote: &Vote) -> u32 {
+    match vote {
+        Vote::Upvote(index) => u32::from(*index),
+        Vote::Abstain => ABSTAIN,
+        Vote::Alarm => ALARM,
+    }
+}
+
+fn vote_from_proto(vote: u32) -> Result<Vote, ProtoError> {
+    match vote {
+        ABSTAIN => Ok(Vote::Abstain),
+        ALARM => Ok(Vote::Alarm),
+        index => u16::try_from(index)
+            .map(Vote::Upvote)
+            .map_err(|_| ProtoError::Vote(vote)),
+    }
+}
+
+impl From<messages::M4AckBundles> for M4AckBundles {
+    fn from(m4_ack_bundles: messages::M4AckBundles) -> Self {
+        let (kind, votes) = match &m4_ack_bundles {
+            messages::M4AckBundles::RepeatPrevious => (M4Kind::RepeatPrevious, None),
+            messages::M4AckBundles::OneByte { upvotes } => (M4Kind::OneByte, Some(upvotes)),
+            messages::M4AckBundles::TwoBytes { upvotes } => (M4Kind::TwoBytes, Some(upvotes)),
+            messages::M4AckBundles::LeadingBy50 => (M4Kind::LeadingBy50, None),
+        };
+        M4AckBundles {
+            kind: kind.into(),
+            votes: votes.into_iter().flatten().map(vote_to_proto).collect(),
+        }
+    }
+}
+
+impl TryFrom<M4AckBundles> for messages::M4AckBundles {
+    type Error = ProtoError;
+
+    fn try_from(m4_ack_bundles: M4AckBundles) -> Result<Self, Self::Error> {
+        let kind = M4Kind::try_from(m4_ack_bundles.kind)
+            .map_err(|_| ProtoError::M4Kind(m4_ack_bundles.kind))?;
+        let upvotes = || -> Result<Votes, ProtoError> {
+            m4_ack_bundles
+                .votes
+                .iter()
+                .map(|vote| vote_from_proto(*vote))
+                .collect()
+        };
+        Ok(match kind {
+            M4Kind::RepeatPrevious => Self::RepeatPrevious,
+            M4Kind::OneByte => Self::OneByte {
+                upvotes: upvotes()?,
+            },
+            M4Kind::TwoBytes => Self::TwoBytes {
+                upvotes: upvotes()?,
+            },
+            M4Kind::LeadingBy50 => Self::LeadingBy50,
+        })
+    }
+}
+
+impl From<messages::CoinbaseMessage> for CoinbaseMessage {
+    fn from(message: messages::CoinbaseMessage) -> Self {
+        use coinbase_message::Message;
+        let message = match message {
+            messages::CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number,
+                data,
+            } => Message::M1ProposeSidechain(M1ProposeSidechain {
+                sidechain_number: sidechain_number.0.into(),
+                data: data.into(),
+            }),
+            messages::CoinbaseMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } => Message::M2AckSidechain(M2AckSidechain {
+                sidechain_number: sidechain_number.0.into(),
+                data_hash: data_hash.to_vec(),
+            }),
+            messages::CoinbaseMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid,
+            } => Message::M3ProposeBundle(M3ProposeBundle {
+                sidechain_number: sidechain_number.0.into(),
+                bundle_txid: bundle_txid.to_vec(),
+            }),
+            messages::CoinbaseMessage::M4AckBundles(m4_ack_bundles) => {
+                Message::M4AckBundles(m4_ack_bundles.into())
+            }
+            messages::CoinbaseMessage::M7BmmAccept {
+                sidechain_number,
+                sidechain_block_hash,
+            } => Message::M7BmmAccept(M7BmmAccept {
+                sidechain_number: sidechain_number.0.into(),
+                sidechain_block_hash: sidechain_block_hash.to_vec(),
+            }),
+        };
+        CoinbaseMessage {
+            message: Some(message),
+        }
+    }
+}
+
+impl TryFrom<CoinbaseMessage> for messages::CoinbaseMessage {
+    type Error = ProtoError;
+
+    fn try_from(message: CoinbaseMessage) -> Result<Self, Self::Error> {
+        use coinbase_message::Message;
+        let message = match message.message.ok_or(ProtoError::MissingMessage)? {
+            Message::M1ProposeSidechain(m1) => Self::M1ProposeSidechain {
+                sidechain_number: sidechain_number(m1.sidechain_number)?,
+                data: m1.data.into(),
+            },
+            Message::M2AckSidechain(m2) => Self::M2AckSidechain {
+                sidechain_number: sidechain_number(m2.sidechain_number)?,
+                data_hash: hash(m2.data_hash)?,
+            },
+            Message::M3ProposeBundle(m3) => Self::M3ProposeBundle {
+                sidechain_number: sidechain_number(m3.sidechain_number)?,
+                bundle_txid: hash(m3.bundle_txid)?,
+            },
+            Message::M4AckBundles(m4) => Self::M4AckBundles(m4.try_into()?),
+            Message::M7BmmAccept(m7) => Self::M7BmmAccept {
+                sidechain_number: sidechain_number(m7.sidechain_number)?,
+                sidechain_block_hash: hash(m7.sidechain_block_hash)?,
+            },
+        };
+        Ok(message)
+    }
+}
+
+impl From<messages::M8BmmRequest> for M8BmmRequest {
+    fn from(request: messages::M8BmmRequest) -> Self {
+        M8BmmRequest {
+            sidechain_number: request.sidechain_number.0.into(),
+            sidechain_block_hash: request.sidechain_block_hash.to_vec(),
+            prev_mainchain_block_hash: request.prev_mainchain_block_hash.to_vec(),
+        }
+    }
+}
+
+impl TryFrom<M8BmmRequest> for messages::M8BmmRequest {
+    type Error = ProtoError;
+
+    fn try_from(request: M8BmmRequest) -> Result<Self, Self::Error> {
+        Ok(messages::M8BmmRequest::new(
+            sidechain_number(request.sidechain_number)?,
+            hash(request.sidechain_block_hash)?,
+            hash(request.prev_mainchain_block_hash)?,
+        ))
+    }
+}
@@ -0,0 +1,172 @@
+//! Fetching drivechain data from Bitcoin Core with `bitcoincore-rpc`.
+//!
+//! Blocks come back with their coinbase messages and BMM requests parsed, and,
+//! checked against a [`Bip300State`], with the deposits and withdrawals they
+//! make. Mempool transactions are classified against the current treasuries.
+
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+use bitcoincore_rpc::{jsonrpc, Client, RpcApi};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    ext::{BlockExt, TransactionExt},
+    messages::{CoinbaseMessage, M8BmmRequest},
+    state::{Bip300State, CtipTracker},
+    sync::BlockSource,
+    treasury::{parse_m5_deposit, parse_m6_withdrawal, M5Deposit, M6Withdrawal},
+    validation::{validate_block, BlockEffects, ValidationError},
+};
+
+#[derive(Debug, Diagnostic, Error)]
+pub enum RpcError {
+    #[error(transparent)]
+    Rpc(#[from] bitcoincore_rpc::Error),
+    #[error("block {block_hash} builds on {prev_blockhash}, not the tip {tip}")]
+    NotOnTip {
+        block_hash: BlockHash,
+        prev_blockhash: BlockHash,
+        tip: BlockHash,
+    },
+    #[error(transparent)]
+    Invalid(#[from] ValidationError),
+}
+
+/// The drivechain data in a block that needs no chain state to find.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockData {
+    pub block: Block,
+    pub height: u32,
+    /// Every message in the coinbase, with its output index.
+    pub coinbase_messages: Vec<(usize, CoinbaseMessage)>,
+    /// Every M8 outside the coinbase as `(tx_index, vout, request)`.
+    pub bmm_requests: Vec<(usize, usize, M8BmmRequest)>,
+}
+
+impl BlockData {
+    pub fn new(block: Block, height: u32) -> Self {
+        BlockData {
+            coinbase_messages: block.coinbase_messages(),
+            bmm_requests: block.bmm_requests(),
+            block,
+            height,
+        }
+    }
+}
+
+/// The block at `height` on the node's best chain.
+pub fn fetch_block<C: RpcApi>(client: &C, height: u32) -> Result<Block, RpcError> {
+    let block_hash = client.get_block_hash(height.into())?;
+    Ok(client.get_block(&block_hash)?)
+}
+
+/// The block at `height` with its coinbase messages and BMM requests parsed.
+pub fn fetch_block_data<C: RpcApi>(client: &C, height: u32) -> Result<BlockData, RpcError> {
+    Ok(BlockData::new(fetch_block(client, height)?, height))
+}
+
+/// The block after the tip of `state`, checked against every BIP300 rule (see
+/// [`validate_block`]), with what it would change: its deposits, withdrawals,
+/// M7s and proposal and bundle events. `state` is left as is; connect the block
+/// with [`Bip300State::connect_block`] to move on.
+pub fn fetch_next_block<C: RpcApi>(
+    client: &C,
+    state: &Bip300State,
+) -> Result<(BlockData, BlockEffects), RpcError> {
+    let height = state.next_height();
+    let block = fetch_block(client, height)?;
+    if let Some((_, tip)) = state.tip() {
+        if block.header.prev_blockhash != tip {
+            return Err(RpcError::NotOnTip {
+                block_hash: block.block_hash(),
+                prev_blockhash: block.header.prev_blockhash,
+                tip,
+            });
+        }
+    }
+    let effects = validate_block(&block, state)?;
+    Ok((BlockData::new(block, height), effects))
+}
+
+/// Drivechain transactions waiting in the mempool.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MempoolData {
+    /// Every M8 as `(txid, vout, request)`.
+    pub bmm_requests: Vec<(Txid, usize, M8BmmRequest)>,
+    /// Transactions that would make a deposit on top of the current treasuries.
+    pub deposits: Vec<(Txid, M5Deposit)>,
+    /// Transactions shaped like a withdrawal from the current treasuries. Whether
+    /// their bundle is spendable is up to the caller, see
+    /// [`crate::BundleVotes::is_spendable`].
+    pub withdrawals: Vec<(Txid, M6Withdrawal)>,
+}
+
+impl MempoolData {
+    /// Classify `txs` against the treasuries in `ctips`. Treasury transactions
+    /// that are neither a valid deposit nor a valid withdrawal are left out.
+    pub fn new<'a>(txs: impl IntoIterator<Item = &'a Transaction>, ctips: &CtipTracker) -> Self {
+        let mut data = MempoolData::default();
+        for tx in txs {
+            let txid = tx.compute_txid();
+            data.bmm_requests.extend(
+                tx.bmm_requests()
+                    .into_iter()
+                    .map(|(vout, request)| (txid, vout, request)),
+            );
+            for (vout, sidechain_number) in tx.op_drivechain_outputs() {
+                let ctip = ctips.ctip(sidechain_number);
+                match ctip {
+                    Some(ctip) if tx.output[vout].value < ctip.value => {
+                        if let Ok(withdrawal) = parse_m6_withdrawal(tx, sidechain_number, ctip) {
+                            data.withdrawals.push((txid, withdrawal));
+                        }
+                    }
+                    _ => {
+                        if let Ok(deposit) = parse_m5_deposit(tx, sidechain_number, ctip) {
+                            data.deposits.push((txid, deposit));
+                        }
+                    }
+                }
+            }
+        }
+        data
+    }
+}
+
+/// `RPC_INVALID_ADDRESS_OR_KEY`, which Bitcoin Core returns for a transaction it
+/// doesn't know.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+fn is_not_found(err: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        err,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))
+            if err.code == RPC_INVALID_ADDRESS_OR_KEY
+    )
+}
+
+/// Every mempool transaction, classified against the treasuries in `ctips`.
+/// Transactions that leave the mempool between listing and fetching it are
+/// skipped; any other error fetching one is returned.
+pub fn fetch_mempool_data<C: RpcApi>(
+    client: &C,
+    ctips: &CtipTracker,
+) -> Result<MempoolData, RpcError> {
+    let mut txs = Vec::new();
+    for txid in client.get_raw_mempool()? {
+        match client.get_raw_transaction(&txid, None) {
+            Ok(tx) => txs.push(tx),
+            Err(err) if is_not_found(&err) => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(MempoolData::new(&txs, ctips))
+}
+
+impl BlockSource for Client {
+    type Error = RpcError;
+
+    fn block(&self, height: u32) -> Result<Block, Self::Error> {
+        fetch_block(self, height)
+    }
+}
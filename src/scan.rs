@@ -0,0 +1,377 @@
+//! Locating drivechain messages in transactions.
+
+use std::collections::HashMap;
+
+use bitcoin::{Block, Script, Transaction};
+use smallvec::SmallVec;
+
+use crate::{
+    ext::TransactionExt,
+    messages::{
+        match_tag, CoinbaseMessage, M1Data, M4AckBundles, MessageKind, SidechainNumber,
+        SidechainSet, OP_DRIVECHAIN,
+    },
+    parser::{
+        detect_coinbase_message_versioned, parse_coinbase_script_versioned, parse_m8_bmm_request,
+        Bip300Error, SpecVersion,
+    },
+};
+
+/// Every output of `tx` that decodes as any drivechain message, regardless of
+/// whether `tx` is a coinbase.
+pub fn scan_message_kinds(
+    tx: &Transaction,
+    spec_version: SpecVersion,
+) -> Vec<(usize, MessageKind)> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, output)| {
+            let script = output.script_pubkey.as_script();
+            match_tag(script.as_bytes())?;
+            if let Ok((_, message)) = parse_coinbase_script_versioned(script, spec_version) {
+                Some((vout, message.kind()))
+            } else if parse_m8_bmm_request(script.as_bytes()).is_ok() {
+                Some((vout, MessageKind::M8BmmRequest))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn is_treasury_prefix(script: &[u8]) -> bool {
+    script.first() == Some(&OP_DRIVECHAIN.to_u8())
+}
+
+/// Whether any output in `block` starts like a drivechain message or a treasury
+/// output. Only prefixes are looked at, so this can report activity for a block
+/// whose messages turn out to be malformed, but never misses a well-formed one.
+pub fn block_contains_drivechain_activity(block: &Block) -> bool {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .any(|output| {
+            let script = output.script_pubkey.as_bytes();
+            is_treasury_prefix(script) || match_tag(script).is_some()
+        })
+}
+
+const MESSAGE_KINDS: [MessageKind; 6] = [
+    MessageKind::M1ProposeSidechain,
+    MessageKind::M2AckSidechain,
+    MessageKind::M3ProposeBundle,
+    MessageKind::M4AckBundles,
+    MessageKind::M7BmmAccept,
+    MessageKind::M8BmmRequest,
+];
+
+/// A compact summary of which message kinds and sidechains a block touches,
+/// meant to be stored per block so later scans can skip blocks without
+/// refetching them. Built from output prefixes like
+/// [`block_contains_drivechain_activity`], so it can have false positives but no
+/// false negatives.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ActivityFilter {
+    kinds: u8,
+    treasury: bool,
+    sidechains: SidechainSet,
+}
+
+impl ActivityFilter {
+    pub const ENCODED_LEN: usize = 1 + 32;
+
+    pub fn from_block(block: &Block) -> Self {
+        let mut filter = Self::default();
+        for output in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            let script = output.script_pubkey.as_bytes();
+            if is_treasury_prefix(script) {
+                filter.treasury = true;
+                // OP_DRIVECHAIN OP_PUSHBYTES_1 <sidechain number> OP_TRUE
+                if let Some(sidechain_number) = script.get(2) {
+                    filter.sidechains.insert(SidechainNumber(*sidechain_number));
+                }
+                continue;
+            }
+            let Some(kind) = match_tag(script) else {
+                continue;
+            };
+            filter.kinds |= 1 << kind as u8;
+            let sidechain_number_offset = match kind {
+                MessageKind::M4AckBundles => continue,
+                MessageKind::M8BmmRequest => 4,
+                _ => 5,
+            };
+            if let Some(sidechain_number) = script.get(sidechain_number_offset) {
+                filter.sidechains.insert(SidechainNumber(*sidechain_number));
+            }
+        }
+        filter
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds == 0 && !self.treasury
+    }
+
+    pub fn contains_kind(&self, kind: MessageKind) -> bool {
+        self.kinds & (1 << kind as u8) != 0
+    }
+
+    pub fn kinds(&self) -> impl Iterator<Item = MessageKind> + '_ {
+        MESSAGE_KINDS
+            .into_iter()
+            .filter(|kind| self.contains_kind(*kind))
+    }
+
+    /// Whether the block has a treasury output.
+    pub fn has_treasury_output(&self) -> bool {
+        self.treasury
+    }
+
+    /// Whether the block may affect `sidechain_number`. Always true if the block
+    /// has an M4, which votes on every sidechain.
+    pub fn may_touch(&self, sidechain_number: SidechainNumber) -> bool {
+        self.contains_kind(MessageKind::M4AckBundles) || self.sidechains.contains(sidechain_number)
+    }
+
+    /// One byte of flags, then a bit per sidechain slot.
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[0] = self.kinds | (u8::from(self.treasury) << 7);
+        for sidechain_number in self.sidechains.iter() {
+            let index = sidechain_number.0 as usize;
+            bytes[1 + index / 8] |= 1 << (index % 8);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::ENCODED_LEN]) -> Self {
+        let sidechains = SidechainNumber::all()
+            .filter(|sidechain_number| {
+                let index = sidechain_number.0 as usize;
+                bytes[1 + index / 8] & (1 << (index % 8)) != 0
+            })
+            .collect();
+        ActivityFilter {
+            kinds: bytes[0] & 0x7F,
+            treasury: bytes[0] & 0x80 != 0,
+            sidechains,
+        }
+    }
+}
+
+/// A coinbase output that decoded as a message, or one that carries a coinbase
+/// message tag but failed to decode, with its output index.
+pub type CoinbaseScanResult = Result<(usize, CoinbaseMessage), (usize, Bip300Error)>;
+
+/// Decode one coinbase output. `None` if the script doesn't carry a coinbase
+/// message tag at all.
+pub fn scan_coinbase_output(
+    vout: usize,
+    script: &Script,
+    spec_version: SpecVersion,
+) -> Option<CoinbaseScanResult> {
+    let result = detect_coinbase_message_versioned(script, spec_version)?;
+    Some(
+        result
+            .map(|message| (vout, message))
+            .map_err(|err| (vout, err)),
+    )
+}
+
+/// Every output of `coinbase` that carries a coinbase message tag, decoded where
+/// possible. A malformed output doesn't stop the rest from being decoded.
+pub fn scan_coinbase(coinbase: &Transaction, spec_version: SpecVersion) -> Vec<CoinbaseScanResult> {
+    coinbase
+        .output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, output)| {
+            scan_coinbase_output(vout, &output.script_pubkey, spec_version)
+        })
+        .collect()
+}
+
+/// Every coinbase message in `coinbase` with its output index, skipping outputs
+/// that aren't drivechain messages. Fails with the index of the first output that
+/// carries a coinbase message tag but doesn't decode; use [`scan_coinbase`] to
+/// keep going past it.
+pub fn parse_coinbase_transaction(
+    coinbase: &Transaction,
+) -> Result<Vec<(usize, CoinbaseMessage)>, (usize, Bip300Error)> {
+    scan_coinbase(coinbase, SpecVersion::default())
+        .into_iter()
+        .collect()
+}
+
+/// A hash stored in a [`HashInterner`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct HashId(u32);
+
+/// Hands out 4-byte ids for the 32-byte hashes seen during a scan, so repeated
+/// hashes aren't copied into every message that carries them. Proposal hashes in
+/// particular repeat across every block that acks them. Each distinct hash is
+/// stored once; ids are looked up by the hash's first 8 bytes, which rarely
+/// collide since the hashes are uniformly distributed.
+#[derive(Clone, Debug, Default)]
+pub struct HashInterner {
+    hashes: Vec<[u8; 32]>,
+    ids: HashMap<u64, SmallVec<[HashId; 1]>>,
+}
+
+fn hash_prefix(hash: &[u8; 32]) -> u64 {
+    u64::from_le_bytes(hash[..8].try_into().expect("a hash is longer than 8 bytes"))
+}
+
+impl HashInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, hash: [u8; 32]) -> HashId {
+        if let Some(id) = self.get(&hash) {
+            return id;
+        }
+        let id =
+            HashId(u32::try_from(self.hashes.len()).expect("more than u32::MAX distinct hashes"));
+        self.hashes.push(hash);
+        self.ids.entry(hash_prefix(&hash)).or_default().push(id);
+        id
+    }
+
+    /// The id of `hash`, if it has been interned.
+    pub fn get(&self, hash: &[u8; 32]) -> Option<HashId> {
+        self.ids
+            .get(&hash_prefix(hash))?
+            .iter()
+            .copied()
+            .find(|id| self.resolve(*id) == hash)
+    }
+
+    /// # Panics
+    /// If `id` was handed out by a different interner.
+    pub fn resolve(&self, id: HashId) -> &[u8; 32] {
+        &self.hashes[id.0 as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn intern_message(&mut self, message: CoinbaseMessage) -> InternedMessage {
+        match message {
+            CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number,
+                data,
+            } => InternedMessage::M1ProposeSidechain {
+                sidechain_number,
+                data: Box::new(data),
+            },
+            CoinbaseMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } => InternedMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash: self.intern(data_hash),
+            },
+            CoinbaseMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid,
+            } => InternedMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid: self.intern(bundle_txid),
+            },
+            CoinbaseMessage::M4AckBundles(m4_ack_bundles) => {
+                InternedMessage::M4AckBundles(Box::new(m4_ack_bundles))
+            }
+            CoinbaseMessage::M7BmmAccept {
+                sidechain_number,
+                sidechain_block_hash,
+            } => InternedMessage::M7BmmAccept {
+                sidechain_number,
+                sidechain_block_hash: self.intern(sidechain_block_hash),
+            },
+        }
+    }
+
+    pub fn resolve_message(&self, message: &InternedMessage) -> CoinbaseMessage {
+        match message {
+            InternedMessage::M1ProposeSidechain {
+                sidechain_number,
+                data,
+            } => CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number: *sidechain_number,
+                data: M1Data::clone(data),
+            },
+            InternedMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } => CoinbaseMessage::M2AckSidechain {
+                sidechain_number: *sidechain_number,
+                data_hash: *self.resolve(*data_hash),
+            },
+            InternedMessage::M3ProposeBundle {
+                sidechain_number,
+                bundle_txid,
+            } => CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: *sidechain_number,
+                bundle_txid: *self.resolve(*bundle_txid),
+            },
+            InternedMessage::M4AckBundles(m4_ack_bundles) => {
+                CoinbaseMessage::M4AckBundles(M4AckBundles::clone(m4_ack_bundles))
+            }
+            InternedMessage::M7BmmAccept {
+                sidechain_number,
+                sidechain_block_hash,
+            } => CoinbaseMessage::M7BmmAccept {
+                sidechain_number: *sidechain_number,
+                sidechain_block_hash: *self.resolve(*sidechain_block_hash),
+            },
+        }
+    }
+}
+
+/// A [`CoinbaseMessage`] with its hashes replaced by [`HashId`]s. Sidechain
+/// numbers are a single byte already and are kept as they are. The rare M1
+/// payloads and M4 vote vectors are boxed, so the messages that repeat, M2s, M3s
+/// and M7s, take 16 bytes each instead of the size of a [`CoinbaseMessage`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InternedMessage {
+    M1ProposeSidechain {
+        sidechain_number: SidechainNumber,
+        data: Box<M1Data>,
+    },
+    M2AckSidechain {
+        sidechain_number: SidechainNumber,
+        data_hash: HashId,
+    },
+    M3ProposeBundle {
+        sidechain_number: SidechainNumber,
+        bundle_txid: HashId,
+    },
+    M4AckBundles(Box<M4AckBundles>),
+    M7BmmAccept {
+        sidechain_number: SidechainNumber,
+        sidechain_block_hash: HashId,
+    },
+}
+
+const _: () = assert!(std::mem::size_of::<InternedMessage>() <= 16);
+
+/// Every coinbase message in `coinbase`, with its output index, interned into
+/// `interner`.
+pub fn scan_coinbase_interned(
+    coinbase: &Transaction,
+    interner: &mut HashInterner,
+) -> Vec<(usize, InternedMessage)> {
+    coinbase
+        .drivechain_messages()
+        .into_iter()
+        .map(|(vout, message)| (vout, interner.intern_message(message)))
+        .collect()
+}
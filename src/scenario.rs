@@ -0,0 +1,254 @@
+//! Deterministic block sequences for downstream test suites.
+//!
+//! The blocks are meant to exercise drivechain logic, not Bitcoin consensus: they
+//! have no proof of work, coinbase outputs are spent immediately and every
+//! non-treasury output is a bare `OP_TRUE`. The same inputs always produce the
+//! same blocks, hashes included.
+
+use bitcoin::{
+    absolute::LockTime,
+    block::{Header, Version},
+    hashes::Hash,
+    opcodes::OP_TRUE,
+    script::Builder,
+    transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Witness,
+};
+
+use crate::{
+    builder::CoinbaseBuilder,
+    messages::{m6_to_id, M4AckBundles, SidechainDeclaration, SidechainNumber, Vote},
+    treasury::create_op_drivechain_script,
+};
+
+const START_TIME: u32 = 1_700_000_000;
+const BLOCK_INTERVAL: u32 = 600;
+const BLOCK_REWARD: Amount = Amount::from_sat(50 * 100_000_000);
+
+fn anyone_can_spend() -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![OP_TRUE.to_u8()])
+}
+
+fn spend(previous_output: OutPoint, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output,
+    }
+}
+
+/// A chain of blocks under construction.
+#[derive(Clone, Debug, Default)]
+pub struct ChainBuilder {
+    blocks: Vec<Block>,
+}
+
+impl ChainBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Height of the next block. The first block built is at height 1, on top of
+    /// an all-zero parent hash.
+    pub fn next_height(&self) -> u32 {
+        self.blocks.len() as u32 + 1
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn into_blocks(self) -> Vec<Block> {
+        self.blocks
+    }
+
+    /// Append a block whose coinbase pays the reward to output 0 and has
+    /// `coinbase_outputs` after it, followed by `txs`.
+    pub fn mine(&mut self, coinbase_outputs: Vec<TxOut>, txs: Vec<Transaction>) -> &Block {
+        let height = self.next_height();
+        let mut output = vec![TxOut {
+            value: BLOCK_REWARD,
+            script_pubkey: anyone_can_spend(),
+        }];
+        output.extend(coinbase_outputs);
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height.into()).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output,
+        };
+        let prev_blockhash = self
+            .blocks
+            .last()
+            .map(Block::block_hash)
+            .unwrap_or_else(BlockHash::all_zeros);
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: START_TIME + height * BLOCK_INTERVAL,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: [coinbase].into_iter().chain(txs).collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("block has a coinbase");
+        self.blocks.push(block);
+        self.blocks.last().unwrap()
+    }
+
+    /// Append a block with only a coinbase.
+    pub fn mine_empty(&mut self) -> &Block {
+        self.mine(vec![], vec![])
+    }
+
+    /// A copy of this chain truncated to its first `height` blocks, to build a
+    /// competing branch on.
+    pub fn fork_at(&self, height: u32) -> Self {
+        ChainBuilder {
+            blocks: self.blocks[..height as usize].to_vec(),
+        }
+    }
+}
+
+/// Knobs for [`sidechain_lifecycle`]. The defaults produce blocks that are
+/// valid under [`crate::Params::regtest`]; lower its thresholds to match when
+/// lowering the ack counts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LifecycleParams {
+    pub sidechain_number: SidechainNumber,
+    /// Blocks acking the proposal after the one proposing it.
+    pub proposal_acks: u32,
+    /// Blocks upvoting the bundle after the one proposing it.
+    pub bundle_acks: u32,
+    pub deposit: Amount,
+    pub payout: Amount,
+    pub fee: Amount,
+}
+
+impl Default for LifecycleParams {
+    fn default() -> Self {
+        LifecycleParams {
+            sidechain_number: SidechainNumber(0),
+            proposal_acks: 6,
+            bundle_acks: 6,
+            deposit: Amount::from_sat(100_000_000),
+            payout: Amount::from_sat(40_000_000),
+            fee: Amount::from_sat(1_000),
+        }
+    }
+}
+
+/// Blocks taking one sidechain through its whole life: an M1, M2s acking it, an
+/// M5 deposit, an M3 proposing a withdrawal bundle, M4s upvoting it, and the M6
+/// paying it out.
+pub fn sidechain_lifecycle(params: &LifecycleParams) -> Vec<Block> {
+    lifecycle_chain(params).into_blocks()
+}
+
+fn lifecycle_chain(params: &LifecycleParams) -> ChainBuilder {
+    let sidechain_number = params.sidechain_number;
+    let mut chain = ChainBuilder::new();
+
+    let declaration = SidechainDeclaration {
+        sidechain_number,
+        title: format!("scenario sidechain {sidechain_number}"),
+        description: String::new(),
+        hash_id_1: [0; 32],
+        hash_id_2: [0; 20],
+    };
+    let (builder, proposal_id) = CoinbaseBuilder::new()
+        .propose_and_track(&declaration)
+        .expect("the scenario declaration encodes");
+    chain.mine(builder.build().expect("an M1 always encodes"), vec![]);
+    for _ in 0..params.proposal_acks {
+        let outputs = CoinbaseBuilder::new()
+            .ack_sidechain(sidechain_number, proposal_id)
+            .build()
+            .expect("an M2 always encodes");
+        chain.mine(outputs, vec![]);
+    }
+
+    let funding = &chain.blocks()[0].txdata[0];
+    let deposit = spend(
+        OutPoint {
+            txid: funding.compute_txid(),
+            vout: 0,
+        },
+        vec![
+            TxOut {
+                value: params.deposit,
+                script_pubkey: create_op_drivechain_script(sidechain_number),
+            },
+            TxOut {
+                value: BLOCK_REWARD - params.deposit - params.fee,
+                script_pubkey: anyone_can_spend(),
+            },
+        ],
+    );
+    let treasury = OutPoint {
+        txid: deposit.compute_txid(),
+        vout: 0,
+    };
+    chain.mine(vec![], vec![deposit]);
+
+    let withdrawal = spend(
+        treasury,
+        vec![
+            TxOut {
+                value: params.deposit - params.payout - params.fee,
+                script_pubkey: create_op_drivechain_script(sidechain_number),
+            },
+            TxOut {
+                value: params.payout,
+                script_pubkey: anyone_can_spend(),
+            },
+        ],
+    );
+    let bundle_id =
+        m6_to_id(&withdrawal, params.deposit.to_sat()).expect("payout and fee fit the deposit");
+    let outputs = CoinbaseBuilder::new()
+        .propose_bundle(sidechain_number, bundle_id)
+        .build()
+        .expect("an M3 always encodes");
+    chain.mine(outputs, vec![]);
+    for _ in 0..params.bundle_acks {
+        let upvotes = vec![Vote::Upvote(0)].into();
+        let outputs = CoinbaseBuilder::new()
+            .ack_bundles(M4AckBundles::OneByte { upvotes })
+            .build()
+            .expect("an upvote for bundle 0 fits one byte");
+        chain.mine(outputs, vec![]);
+    }
+    chain.mine(vec![], vec![withdrawal]);
+    chain
+}
+
+/// A lifecycle from [`sidechain_lifecycle`] and a longer competing chain that
+/// forks off `depth` blocks below its tip with empty blocks, undoing the payout
+/// (and, for large depths, earlier steps). Both chains are returned from the
+/// first block.
+pub fn sidechain_lifecycle_with_reorg(
+    params: &LifecycleParams,
+    depth: u32,
+) -> (Vec<Block>, Vec<Block>) {
+    let chain = lifecycle_chain(params);
+    let tip_height = chain.blocks().len() as u32;
+    let mut fork = chain.fork_at(tip_height.saturating_sub(depth));
+    while fork.blocks().len() as u32 <= tip_height {
+        fork.mine_empty();
+    }
+    (chain.into_blocks(), fork.into_blocks())
+}
@@ -0,0 +1,79 @@
+//! Byte strings as hex in human-readable formats and as raw bytes otherwise, for
+//! `#[serde(with = "crate::serde_hex")]`.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bitcoin::hex::{DisplayHex, FromHex};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserializer, Serializer,
+};
+
+pub(crate) fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    let bytes = bytes.as_ref();
+    if serializer.is_human_readable() {
+        serializer.collect_str(&bytes.as_hex())
+    } else {
+        serializer.serialize_bytes(bytes)
+    }
+}
+
+pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<Vec<u8>>,
+{
+    let bytes = if deserializer.is_human_readable() {
+        deserializer.deserialize_str(HexVisitor)?
+    } else {
+        deserializer.deserialize_bytes(BytesVisitor)?
+    };
+    let len = bytes.len();
+    T::try_from(bytes)
+        .map_err(|_| de::Error::invalid_length(len, &"a byte string of the right length"))
+}
+
+struct HexVisitor;
+
+impl Visitor<'_> for HexVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a hex string")
+    }
+
+    fn visit_str<E: de::Error>(self, hex: &str) -> Result<Self::Value, E> {
+        Vec::from_hex(hex).map_err(E::custom)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,122 @@
+//! Authorship signatures for M1 and M3 messages.
+//!
+//! A signed message is the normal script encoding followed by a 96-byte trailer:
+//! the signer's x-only public key and a BIP340 signature over the sha256d of the
+//! unsigned script. This is strictly off-consensus. Signed scripts are meant to be
+//! passed between sidechain teams and miners, and only the unsigned script goes in
+//! a coinbase; a signed M1 put on chain would have the trailer read back as part
+//! of its payload.
+
+use bitcoin::{
+    secp256k1::{schnorr, Keypair, Message, Secp256k1, Signing, Verification, XOnlyPublicKey},
+    ScriptBuf,
+};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    messages::{sha256d, CoinbaseMessage, MessageKind},
+    parser::parse_coinbase_script,
+};
+
+pub const SIGNED_TRAILER_LEN: usize = 32 + 64;
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum SignedMessageError {
+    #[error("{0:?} messages can't carry a signature, only M1 and M3 can")]
+    NotSignable(MessageKind),
+    #[error("script is too short to carry a signature trailer")]
+    MissingTrailer,
+    #[error("signature trailer does not contain a valid public key")]
+    InvalidPublicKey,
+    #[error("signature trailer does not contain a valid signature")]
+    InvalidSignature,
+    #[error("signed body is not a coinbase message")]
+    InvalidBody,
+    #[error("signature does not verify against the message")]
+    BadSignature,
+}
+
+/// An M1 or M3 together with a signature from its author.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignedMessage {
+    pub message: CoinbaseMessage,
+    pub public_key: XOnlyPublicKey,
+    pub signature: schnorr::Signature,
+}
+
+fn check_signable(message: &CoinbaseMessage) -> Result<(), SignedMessageError> {
+    match message.kind() {
+        MessageKind::M1ProposeSidechain | MessageKind::M3ProposeBundle => Ok(()),
+        kind => Err(SignedMessageError::NotSignable(kind)),
+    }
+}
+
+/// The unsigned script of a signable message.
+fn signed_body(message: &CoinbaseMessage) -> Result<ScriptBuf, SignedMessageError> {
+    check_signable(message)?;
+    Ok(message.to_script().expect("M1 and M3 always encode"))
+}
+
+fn body_digest(body: &[u8]) -> Message {
+    Message::from_digest(sha256d(body))
+}
+
+impl SignedMessage {
+    pub fn sign<C: Signing>(
+        secp: &Secp256k1<C>,
+        message: CoinbaseMessage,
+        keypair: &Keypair,
+    ) -> Result<Self, SignedMessageError> {
+        let body = signed_body(&message)?;
+        let signature = secp.sign_schnorr_no_aux_rand(&body_digest(body.as_bytes()), keypair);
+        let (public_key, _parity) = keypair.x_only_public_key();
+        Ok(Self {
+            message,
+            public_key,
+            signature,
+        })
+    }
+
+    pub fn verify<C: Verification>(&self, secp: &Secp256k1<C>) -> Result<(), SignedMessageError> {
+        let body = signed_body(&self.message)?;
+        secp.verify_schnorr(
+            &self.signature,
+            &body_digest(body.as_bytes()),
+            &self.public_key,
+        )
+        .map_err(|_| SignedMessageError::BadSignature)
+    }
+
+    /// The unsigned script followed by the signature trailer.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SignedMessageError> {
+        let mut bytes = signed_body(&self.message)?.into_bytes();
+        bytes.extend_from_slice(&self.public_key.serialize());
+        bytes.extend_from_slice(&self.signature.serialize());
+        Ok(bytes)
+    }
+
+    /// Split off and decode the trailer, then decode the body. The signature is
+    /// not checked, see [`Self::verify`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SignedMessageError> {
+        let body_len = bytes
+            .len()
+            .checked_sub(SIGNED_TRAILER_LEN)
+            .ok_or(SignedMessageError::MissingTrailer)?;
+        let (body, trailer) = bytes.split_at(body_len);
+        let (public_key, signature) = trailer.split_at(32);
+        let public_key = XOnlyPublicKey::from_slice(public_key)
+            .map_err(|_| SignedMessageError::InvalidPublicKey)?;
+        let signature = schnorr::Signature::from_slice(signature)
+            .map_err(|_| SignedMessageError::InvalidSignature)?;
+        let body = ScriptBuf::from_bytes(body.to_vec());
+        let (_, message) =
+            parse_coinbase_script(&body).map_err(|_| SignedMessageError::InvalidBody)?;
+        check_signable(&message)?;
+        Ok(Self {
+            message,
+            public_key,
+            signature,
+        })
+    }
+}
@@ -0,0 +1,447 @@
+//! Drivechain state tracked across blocks.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use bitcoin::{Amount, Block, BlockHash, OutPoint, Txid};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    bundles::BundleVotes,
+    messages::SidechainNumber,
+    params::Params,
+    parser::try_parse_op_drivechain,
+    proposals::SidechainProposals,
+    validation::{check_block_rules, check_block_state, ParseConfig, Severity, Violation},
+};
+
+/// A sidechain's current treasury UTXO (its critical transaction index pair).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ctip {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+}
+
+/// A block breaks the rule that each sidechain has exactly one chain of treasury
+/// UTXOs, each spending the previous one.
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum CtipViolation {
+    #[error(
+        "treasury {outpoint} of sidechain {sidechain_number} is spent by both {first_spender} and {second_spender}"
+    )]
+    TreasurySpentTwice {
+        sidechain_number: SidechainNumber,
+        outpoint: OutPoint,
+        first_spender: Txid,
+        second_spender: Txid,
+    },
+    #[error(
+        "{txid} creates a treasury output for sidechain {sidechain_number} at vout {vout} without spending its treasury {ctip}"
+    )]
+    UnchainedTreasuryOutput {
+        sidechain_number: SidechainNumber,
+        txid: Txid,
+        vout: u32,
+        ctip: OutPoint,
+    },
+    #[error(
+        "{txid} creates more than one treasury output for sidechain {sidechain_number}: {vouts:?}"
+    )]
+    MultipleTreasuryOutputs {
+        sidechain_number: SidechainNumber,
+        txid: Txid,
+        vouts: Vec<u32>,
+    },
+    #[error(
+        "{txid} spends the treasury of sidechain {sidechain_number} without creating a new one"
+    )]
+    TreasuryNotReplaced {
+        sidechain_number: SidechainNumber,
+        txid: Txid,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum DisconnectError {
+    #[error("block {block_hash} is not the last connected block")]
+    NotTip { block_hash: BlockHash },
+    #[error("no undo data is retained for block {block_hash}")]
+    NoHistory { block_hash: BlockHash },
+}
+
+/// How much per-block history a state tracker keeps. Settled proposals and
+/// bundles leave the current state, so with a window the memory a tracker uses
+/// stays flat however many blocks are connected. Blocks older than the window
+/// can't be disconnected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Retention {
+    /// Keep history for every connected block.
+    Full,
+    /// Keep history for only the most recent `blocks` connected blocks.
+    Window { blocks: usize },
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self::Window {
+            blocks: Self::DEFAULT_WINDOW,
+        }
+    }
+}
+
+impl Retention {
+    /// Blocks of history kept by default. Coinbase outputs take this long to
+    /// mature, so a deeper reorg is not one a node is expected to follow.
+    pub const DEFAULT_WINDOW: usize = 100;
+
+    fn limit(self) -> Option<usize> {
+        match self {
+            Self::Full => None,
+            Self::Window { blocks } => Some(blocks),
+        }
+    }
+
+    /// Drop the oldest entries of `history` this policy no longer allows.
+    pub(crate) fn prune<T>(self, history: &mut VecDeque<T>) {
+        if let Some(limit) = self.limit() {
+            while history.len() > limit {
+                history.pop_front();
+            }
+        }
+    }
+}
+
+/// The entries `after` changed relative to `before`, with their values in
+/// `before`: `None` for entries `after` added.
+pub(crate) fn changed_entries<K: Clone + Ord, V: Clone + PartialEq>(
+    before: &BTreeMap<K, V>,
+    after: &BTreeMap<K, V>,
+) -> BTreeMap<K, Option<V>> {
+    let changed = before
+        .iter()
+        .filter(|(key, value)| after.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), Some(value.clone())));
+    let added = after
+        .keys()
+        .filter(|key| !before.contains_key(*key))
+        .map(|key| (key.clone(), None));
+    changed.chain(added).collect()
+}
+
+/// Put back the entries [`changed_entries`] recorded.
+pub(crate) fn restore_entries<K: Ord, V>(map: &mut BTreeMap<K, V>, undo: BTreeMap<K, Option<V>>) {
+    for (key, value) in undo {
+        match value {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+    }
+}
+
+/// The state a connected block replaced, enough to undo it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CtipUndo {
+    pub block_hash: BlockHash,
+    /// The previous treasury of every sidechain the block changed, `None` if the
+    /// block created the sidechain's first treasury.
+    pub previous: BTreeMap<SidechainNumber, Option<Ctip>>,
+}
+
+/// Tracks the treasury UTXO of every sidechain across blocks.
+#[derive(Clone, Debug, Default)]
+pub struct CtipTracker {
+    ctips: BTreeMap<SidechainNumber, Ctip>,
+    retention: Retention,
+    history: VecDeque<CtipUndo>,
+}
+
+impl CtipTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retention(retention: Retention) -> Self {
+        Self {
+            retention,
+            ..Self::default()
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Change the retention policy, dropping any history it no longer allows.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.prune();
+    }
+
+    /// Undo data for the retained blocks, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &CtipUndo> {
+        self.history.iter()
+    }
+
+    fn prune(&mut self) {
+        self.retention.prune(&mut self.history);
+    }
+
+    pub fn ctip(&self, sidechain_number: SidechainNumber) -> Option<&Ctip> {
+        self.ctips.get(&sidechain_number)
+    }
+
+    pub fn ctips(&self) -> impl Iterator<Item = (SidechainNumber, &Ctip)> {
+        self.ctips
+            .iter()
+            .map(|(sidechain_number, ctip)| (*sidechain_number, ctip))
+    }
+
+    /// Apply the treasury spends and outputs in `block`. If the block breaks the
+    /// single-treasury-chain invariant for any sidechain, nothing is applied.
+    pub fn connect_block(&mut self, block: &Block) -> Result<(), CtipViolation> {
+        let ctips = self.next_ctips(block)?;
+        let previous = changed_entries(&self.ctips, &ctips);
+        self.history.push_back(CtipUndo {
+            block_hash: block.block_hash(),
+            previous,
+        });
+        self.ctips = ctips;
+        self.prune();
+        Ok(())
+    }
+
+    /// Undo `block`, which must be the last connected block, restoring the
+    /// treasuries it replaced. Blocks can be disconnected one by one for as far
+    /// back as the retention policy kept their history.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<(), DisconnectError> {
+        let block_hash = block.block_hash();
+        let undo = match self.history.back() {
+            None => return Err(DisconnectError::NoHistory { block_hash }),
+            Some(undo) if undo.block_hash != block_hash => {
+                return Err(DisconnectError::NotTip { block_hash })
+            }
+            Some(_) => self.history.pop_back().expect("history is not empty"),
+        };
+        restore_entries(&mut self.ctips, undo.previous);
+        Ok(())
+    }
+
+    /// Check `block` as [`Self::connect_block`] would, without applying it.
+    pub fn check_block(&self, block: &Block) -> Result<(), CtipViolation> {
+        self.next_ctips(block).map(|_| ())
+    }
+
+    fn next_ctips(&self, block: &Block) -> Result<BTreeMap<SidechainNumber, Ctip>, CtipViolation> {
+        let mut ctips = self.ctips.clone();
+        // Every treasury outpoint spent so far in this block, and its spender.
+        let mut spent_in_block = HashMap::<OutPoint, (SidechainNumber, Txid)>::new();
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            let mut spent = Vec::<SidechainNumber>::new();
+            for input in &tx.input {
+                let outpoint = input.previous_output;
+                if let Some((sidechain_number, first_spender)) = spent_in_block.get(&outpoint) {
+                    return Err(CtipViolation::TreasurySpentTwice {
+                        sidechain_number: *sidechain_number,
+                        outpoint,
+                        first_spender: *first_spender,
+                        second_spender: txid,
+                    });
+                }
+                let Some(sidechain_number) = ctips
+                    .iter()
+                    .find(|(_, ctip)| ctip.outpoint == outpoint)
+                    .map(|(sidechain_number, _)| *sidechain_number)
+                else {
+                    continue;
+                };
+                spent_in_block.insert(outpoint, (sidechain_number, txid));
+                spent.push(sidechain_number);
+            }
+            let mut created = BTreeMap::<SidechainNumber, Vec<(u32, Amount)>>::new();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if let Some(sidechain_number) = try_parse_op_drivechain(&output.script_pubkey) {
+                    created
+                        .entry(sidechain_number)
+                        .or_default()
+                        .push((vout as u32, output.value));
+                }
+            }
+            for (sidechain_number, outputs) in created.iter() {
+                if outputs.len() > 1 {
+                    return Err(CtipViolation::MultipleTreasuryOutputs {
+                        sidechain_number: *sidechain_number,
+                        txid,
+                        vouts: outputs.iter().map(|(vout, _)| *vout).collect(),
+                    });
+                }
+                let (vout, value) = outputs[0];
+                if !spent.contains(sidechain_number) {
+                    if let Some(ctip) = ctips.get(sidechain_number) {
+                        return Err(CtipViolation::UnchainedTreasuryOutput {
+                            sidechain_number: *sidechain_number,
+                            txid,
+                            vout,
+                            ctip: ctip.outpoint,
+                        });
+                    }
+                }
+                let ctip = Ctip {
+                    outpoint: OutPoint { txid, vout },
+                    value,
+                };
+                ctips.insert(*sidechain_number, ctip);
+            }
+            if let Some(sidechain_number) = spent
+                .iter()
+                .find(|sidechain_number| !created.contains_key(*sidechain_number))
+            {
+                return Err(CtipViolation::TreasuryNotReplaced {
+                    sidechain_number: *sidechain_number,
+                    txid,
+                });
+            }
+        }
+        Ok(ctips)
+    }
+}
+
+/// All drivechain state tracked by this crate as of the last connected block.
+#[derive(Clone, Debug, Default)]
+pub struct Bip300State {
+    config: ParseConfig,
+    tip: Option<(u32, BlockHash)>,
+    ctips: CtipTracker,
+    proposals: SidechainProposals,
+    bundles: BundleVotes,
+    /// The tip before each block the trackers keep history for.
+    previous_tips: VecDeque<Option<(u32, BlockHash)>>,
+}
+
+impl Bip300State {
+    pub fn new(config: ParseConfig) -> Self {
+        Bip300State {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// A state enforcing `params` instead of the mainnet ones, keeping undo
+    /// history as `retention` allows.
+    pub fn with_params(params: Params, config: ParseConfig, retention: Retention) -> Self {
+        let mut state = Bip300State {
+            config,
+            proposals: SidechainProposals::new(params),
+            bundles: BundleVotes::new(params),
+            ..Self::default()
+        };
+        state.set_retention(retention);
+        state
+    }
+
+    pub fn params(&self) -> &Params {
+        self.proposals.params()
+    }
+
+    pub fn config(&self) -> &ParseConfig {
+        &self.config
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.ctips.retention()
+    }
+
+    /// Change the retention policy of every tracker, dropping any history it no
+    /// longer allows.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.ctips.set_retention(retention);
+        self.proposals.set_retention(retention);
+        self.bundles.set_retention(retention);
+        retention.prune(&mut self.previous_tips);
+    }
+
+    /// How many of the last connected blocks can be disconnected.
+    pub fn retained_blocks(&self) -> usize {
+        self.previous_tips.len()
+    }
+
+    /// Height and hash of the last connected block.
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.tip
+    }
+
+    /// Height the next block is connected at: one past the tip, or 0 before
+    /// any block is connected.
+    pub fn next_height(&self) -> u32 {
+        self.tip.map_or(0, |(height, _)| height + 1)
+    }
+
+    pub fn ctips(&self) -> &CtipTracker {
+        &self.ctips
+    }
+
+    pub fn proposals(&self) -> &SidechainProposals {
+        &self.proposals
+    }
+
+    pub fn bundles(&self) -> &BundleVotes {
+        &self.bundles
+    }
+
+    /// Check `block` at `height` with [`crate::check_block`] and apply it if
+    /// nothing reaches error severity under the configured mode. On failure every
+    /// violation found is returned, warnings included, and nothing is applied.
+    pub fn connect_block(&mut self, block: &Block, height: u32) -> Result<(), Vec<Violation>> {
+        let violations = check_block_rules(block, &self.config);
+        self.connect_block_with(block, height, violations)
+    }
+
+    /// [`Self::connect_block`] for a block whose [`check_block_rules`] have
+    /// already been run.
+    pub(crate) fn connect_block_with(
+        &mut self,
+        block: &Block,
+        height: u32,
+        mut violations: Vec<Violation>,
+    ) -> Result<(), Vec<Violation>> {
+        let (state_violations, effects) = check_block_state(block, height, self);
+        violations.extend(state_violations);
+        if violations
+            .iter()
+            .any(|violation| violation.severity(self.config.mode) == Severity::Error)
+        {
+            return Err(violations);
+        }
+        self.ctips
+            .connect_block(block)
+            .expect("treasury rules were checked");
+        (self.proposals, self.bundles) = effects.into_trackers();
+        self.previous_tips.push_back(self.tip);
+        self.retention().prune(&mut self.previous_tips);
+        self.tip = Some((height, block.block_hash()));
+        Ok(())
+    }
+
+    /// Undo `block`, which must be the last connected block, moving the tip back
+    /// to the one before it and restoring the proposals and bundles as they were.
+    /// After the first connected block is undone there is no tip. See
+    /// [`CtipTracker::disconnect_block`] for how far back this can go.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<(), DisconnectError> {
+        let block_hash = block.block_hash();
+        let Some((height, tip_hash)) = self.tip else {
+            return Err(DisconnectError::NoHistory { block_hash });
+        };
+        if tip_hash != block_hash {
+            return Err(DisconnectError::NotTip { block_hash });
+        }
+        if self.previous_tips.is_empty() {
+            return Err(DisconnectError::NoHistory { block_hash });
+        }
+        self.ctips.disconnect_block(block)?;
+        let undone =
+            self.proposals.disconnect_messages(height) && self.bundles.disconnect_messages(height);
+        assert!(undone, "every tracker keeps history for the same blocks");
+        self.tip = self.previous_tips.pop_back().expect("history is not empty");
+        Ok(())
+    }
+}
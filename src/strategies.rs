@@ -0,0 +1,142 @@
+//! `proptest` strategies for messages and the scripts encoding them, for
+//! property testing against this crate's encoders.
+
+use bitcoin::ScriptBuf;
+use proptest::{collection::vec, prelude::*};
+
+use crate::messages::{
+    CoinbaseMessage, M4AckBundles, M8BmmRequest, SidechainNumber, Vote, Votes,
+    M1_PROPOSE_SIDECHAIN_PREFIX, M2_ACK_SIDECHAIN_PREFIX, M3_PROPOSE_BUNDLE_PREFIX,
+    M4_ACK_BUNDLES_PREFIX, M7_BMM_ACCEPT_PREFIX, M8_BMM_REQUEST_PREFIX,
+};
+
+/// Longest M1 payload and vote vector generated.
+const MAX_LEN: usize = 512;
+
+pub fn sidechain_number() -> impl Strategy<Value = SidechainNumber> {
+    any::<u8>().prop_map(SidechainNumber)
+}
+
+pub fn hash() -> impl Strategy<Value = [u8; 32]> {
+    any::<[u8; 32]>()
+}
+
+/// Votes that fit the one-byte encoding.
+pub fn one_byte_votes() -> impl Strategy<Value = Votes> {
+    vec(any::<u8>().prop_map(Vote::from_one_byte), 0..=MAX_LEN).prop_map(Votes::from)
+}
+
+/// Votes that fit the two-byte encoding.
+pub fn two_byte_votes() -> impl Strategy<Value = Votes> {
+    vec(any::<u16>().prop_map(Vote::from_two_bytes), 0..=MAX_LEN).prop_map(Votes::from)
+}
+
+pub fn m4_ack_bundles() -> impl Strategy<Value = M4AckBundles> {
+    prop_oneof![
+        Just(M4AckBundles::RepeatPrevious),
+        one_byte_votes().prop_map(|upvotes| M4AckBundles::OneByte { upvotes }),
+        two_byte_votes().prop_map(|upvotes| M4AckBundles::TwoBytes { upvotes }),
+        Just(M4AckBundles::LeadingBy50),
+    ]
+}
+
+pub fn m1_propose_sidechain() -> impl Strategy<Value = CoinbaseMessage> {
+    (sidechain_number(), vec(any::<u8>(), 0..=MAX_LEN)).prop_map(|(sidechain_number, data)| {
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number,
+            data: data.into(),
+        }
+    })
+}
+
+pub fn m2_ack_sidechain() -> impl Strategy<Value = CoinbaseMessage> {
+    (sidechain_number(), hash()).prop_map(|(sidechain_number, data_hash)| {
+        CoinbaseMessage::M2AckSidechain {
+            sidechain_number,
+            data_hash,
+        }
+    })
+}
+
+pub fn m3_propose_bundle() -> impl Strategy<Value = CoinbaseMessage> {
+    (sidechain_number(), hash()).prop_map(|(sidechain_number, bundle_txid)| {
+        CoinbaseMessage::m3_propose_bundle(sidechain_number, bundle_txid)
+    })
+}
+
+pub fn m7_bmm_accept() -> impl Strategy<Value = CoinbaseMessage> {
+    (sidechain_number(), hash()).prop_map(|(sidechain_number, sidechain_block_hash)| {
+        CoinbaseMessage::m7_bmm_accept(sidechain_number, sidechain_block_hash)
+    })
+}
+
+/// Any coinbase message that encodes.
+pub fn coinbase_message() -> impl Strategy<Value = CoinbaseMessage> {
+    prop_oneof![
+        m1_propose_sidechain(),
+        m2_ack_sidechain(),
+        m3_propose_bundle(),
+        m4_ack_bundles().prop_map(CoinbaseMessage::M4AckBundles),
+        m7_bmm_accept(),
+    ]
+}
+
+pub fn m8_bmm_request() -> impl Strategy<Value = M8BmmRequest> {
+    (sidechain_number(), hash(), hash()).prop_map(
+        |(sidechain_number, sidechain_block_hash, prev_mainchain_block_hash)| {
+            M8BmmRequest::new(
+                sidechain_number,
+                sidechain_block_hash,
+                prev_mainchain_block_hash,
+            )
+        },
+    )
+}
+
+/// The encoding of a [`coinbase_message`].
+pub fn coinbase_script() -> impl Strategy<Value = ScriptBuf> {
+    coinbase_message().prop_map(|message| {
+        message
+            .to_script()
+            .expect("generated messages always encode")
+    })
+}
+
+/// The encoding of an [`m8_bmm_request`].
+pub fn m8_bmm_request_script() -> impl Strategy<Value = ScriptBuf> {
+    m8_bmm_request().prop_map(|request| request.encode())
+}
+
+/// Scripts a parser has to handle without panicking: valid messages cut short or
+/// with bytes appended, and message tags followed by random bytes.
+pub fn adversarial_script() -> impl Strategy<Value = ScriptBuf> {
+    let valid = prop_oneof![coinbase_script(), m8_bmm_request_script()].boxed();
+    let truncated = valid
+        .clone()
+        .prop_flat_map(|script| {
+            let len = script.len();
+            (Just(script), 0..len.max(1))
+        })
+        .prop_map(|(script, len)| ScriptBuf::from_bytes(script.as_bytes()[..len].to_vec()));
+    let extended = (valid, vec(any::<u8>(), 1..=32)).prop_map(|(script, extra)| {
+        let mut bytes = script.into_bytes();
+        bytes.extend(extra);
+        ScriptBuf::from_bytes(bytes)
+    });
+    let prefixes: [&'static [u8]; 6] = [
+        &M1_PROPOSE_SIDECHAIN_PREFIX,
+        &M2_ACK_SIDECHAIN_PREFIX,
+        &M3_PROPOSE_BUNDLE_PREFIX,
+        &M4_ACK_BUNDLES_PREFIX,
+        &M7_BMM_ACCEPT_PREFIX,
+        &M8_BMM_REQUEST_PREFIX,
+    ];
+    let tagged = (
+        proptest::sample::select(prefixes.to_vec()),
+        vec(any::<u8>(), 0..=80),
+    )
+        .prop_map(|(prefix, rest)| {
+            ScriptBuf::from_bytes(prefix.iter().copied().chain(rest).collect())
+        });
+    prop_oneof![truncated, extended, tagged]
+}
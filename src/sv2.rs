@@ -0,0 +1,124 @@
+//! Drivechain coinbase outputs in the shape Stratum V2 carries them.
+//!
+//! SV2 passes coinbase outputs around as an output count and the outputs'
+//! consensus encodings concatenated into a `B0_64K` field, e.g.
+//! `coinbase_tx_outputs` in `NewTemplate` and in job declaration. A pool also
+//! has to tell its template provider up front how many bytes and sigops it will
+//! add to the coinbase, with `CoinbaseOutputDataSize`.
+
+use bitcoin::{
+    consensus::{
+        encode::{deserialize_partial, VarInt},
+        Encodable,
+    },
+    Amount, TxOut,
+};
+use miette::Diagnostic;
+use thiserror::Error;
+
+/// The longest an SV2 `B0_64K` field can be.
+pub const B064K_MAX_LEN: usize = u16::MAX as usize;
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum Sv2Error {
+    #[error("outputs serialize to {size} bytes, more than a B0_64K field holds")]
+    TooLarge { size: usize },
+    #[error("outputs pay {value}, but only {remaining} of the coinbase value is left")]
+    ValueExceedsRemaining { value: Amount, remaining: Amount },
+    #[error("output {index} of {count} is malformed")]
+    MalformedOutput { index: u32, count: u32 },
+    #[error("{trailing} bytes left after {count} outputs")]
+    TrailingBytes { count: u32, trailing: usize },
+}
+
+/// A list of coinbase outputs serialized for an SV2 message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Sv2CoinbaseOutputs {
+    /// How many outputs `serialized` holds.
+    pub count: u32,
+    /// The outputs' consensus encodings, concatenated without a count prefix.
+    pub serialized: Vec<u8>,
+    /// What the outputs pay out in total.
+    pub value: Amount,
+    /// The outputs' legacy sigops, which all count in a coinbase.
+    pub sigops: u32,
+}
+
+impl Sv2CoinbaseOutputs {
+    pub fn new(outputs: &[TxOut]) -> Result<Self, Sv2Error> {
+        let mut this = Self::default();
+        this.extend(outputs)?;
+        Ok(this)
+    }
+
+    /// Append `outputs`, e.g. the drivechain outputs of
+    /// [`crate::template::assemble_coinbase_outputs`] to the outputs of a
+    /// `NewTemplate`. Nothing is appended if the result wouldn't fit a `B0_64K`.
+    pub fn extend(&mut self, outputs: &[TxOut]) -> Result<(), Sv2Error> {
+        let mut serialized = self.serialized.clone();
+        let mut value = self.value;
+        let mut sigops = self.sigops;
+        for output in outputs {
+            output
+                .consensus_encode(&mut serialized)
+                .expect("writing to a vec can't fail");
+            value = value.checked_add(output.value).unwrap_or(Amount::MAX);
+            sigops = sigops.saturating_add(output.script_pubkey.count_sigops_legacy() as u32);
+        }
+        if serialized.len() > B064K_MAX_LEN {
+            return Err(Sv2Error::TooLarge {
+                size: serialized.len(),
+            });
+        }
+        self.count += outputs.len() as u32;
+        self.serialized = serialized;
+        self.value = value;
+        self.sigops = sigops;
+        Ok(())
+    }
+
+    /// Decode the `count` outputs in `serialized`, as found in an SV2 message.
+    pub fn decode(count: u32, serialized: &[u8]) -> Result<Vec<TxOut>, Sv2Error> {
+        let mut outputs = Vec::new();
+        let mut rest = serialized;
+        for index in 0..count {
+            let (output, consumed) = deserialize_partial::<TxOut>(rest)
+                .map_err(|_| Sv2Error::MalformedOutput { index, count })?;
+            outputs.push(output);
+            rest = &rest[consumed..];
+        }
+        if !rest.is_empty() {
+            return Err(Sv2Error::TrailingBytes {
+                count,
+                trailing: rest.len(),
+            });
+        }
+        Ok(outputs)
+    }
+
+    /// Decode [`Self::serialized`] back into outputs.
+    pub fn outputs(&self) -> Vec<TxOut> {
+        Self::decode(self.count, &self.serialized).expect("outputs were encoded by this type")
+    }
+
+    /// The bytes these outputs add to a coinbase that already has
+    /// `existing_outputs` outputs, including any growth of the output count
+    /// prefix. This is the `coinbase_output_max_additional_size` to declare in
+    /// `CoinbaseOutputDataSize`.
+    pub fn additional_size(&self, existing_outputs: u32) -> u32 {
+        let count_growth = VarInt(u64::from(existing_outputs) + u64::from(self.count)).size()
+            - VarInt(u64::from(existing_outputs)).size();
+        (self.serialized.len() + count_growth) as u32
+    }
+
+    /// Check that these outputs fit in the `coinbase_tx_value_remaining` of a
+    /// `NewTemplate`, and return what is left of it after them.
+    pub fn value_remaining(&self, remaining: Amount) -> Result<Amount, Sv2Error> {
+        remaining
+            .checked_sub(self.value)
+            .ok_or(Sv2Error::ValueExceedsRemaining {
+                value: self.value,
+                remaining,
+            })
+    }
+}
@@ -0,0 +1,153 @@
+//! Building drivechain state from a range of blocks using every core.
+//!
+//! Blocks are fetched and put through the stateless checks
+//! ([`check_block_rules`]) by a pool of workers, then connected to the state in
+//! height order on the calling thread. Workers stay at most a bounded number of
+//! blocks ahead of the last connected one, so memory use doesn't grow with the
+//! range.
+
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    ops::Range,
+    sync::{mpsc, Condvar, Mutex},
+};
+
+use bitcoin::Block;
+use thiserror::Error;
+
+use crate::{
+    state::Bip300State,
+    validation::{check_block_rules, Violation},
+};
+
+/// Where [`sync_range`] gets blocks from, e.g. an RPC client or a block store.
+pub trait BlockSource: Sync {
+    type Error: Send;
+
+    /// The block at `height` on the chain being synced.
+    fn block(&self, height: u32) -> Result<Block, Self::Error>;
+}
+
+#[derive(Debug, Error)]
+pub enum SyncError<E> {
+    #[error("failed to fetch block {height}")]
+    Source {
+        height: u32,
+        #[source]
+        source: E,
+    },
+    #[error("block {height} breaks {} drivechain rule(s)", .violations.len())]
+    Invalid {
+        height: u32,
+        violations: Vec<Violation>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SyncOptions {
+    /// Number of fetch and check workers.
+    pub workers: NonZeroUsize,
+    /// How many blocks past the last connected one workers may fetch.
+    pub window: NonZeroUsize,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        let workers = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        SyncOptions {
+            workers,
+            window: workers.saturating_mul(NonZeroUsize::new(4).unwrap()),
+        }
+    }
+}
+
+/// Connect the blocks at `range` from `source` to `state`, with the default
+/// [`SyncOptions`]. Stops at the first block that can't be fetched or connected;
+/// every block before it stays connected.
+pub fn sync_range<S: BlockSource>(
+    source: &S,
+    range: Range<u32>,
+    state: &mut Bip300State,
+) -> Result<(), SyncError<S::Error>> {
+    sync_range_with(source, range, state, SyncOptions::default())
+}
+
+pub fn sync_range_with<S: BlockSource>(
+    source: &S,
+    range: Range<u32>,
+    state: &mut Bip300State,
+    options: SyncOptions,
+) -> Result<(), SyncError<S::Error>> {
+    struct Progress {
+        next: u32,
+        connected: u32,
+        stopped: bool,
+    }
+    let progress = Mutex::new(Progress {
+        next: range.start,
+        connected: range.start,
+        stopped: false,
+    });
+    let progressed = Condvar::new();
+    let window = u32::try_from(options.window.get()).unwrap_or(u32::MAX);
+    let config = *state.config();
+    let stop = || {
+        progress.lock().unwrap().stopped = true;
+        progressed.notify_all();
+    };
+
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel(options.window.get());
+        for _ in 0..options.workers.get() {
+            let sender = sender.clone();
+            let (progress, progressed, range) = (&progress, &progressed, range.clone());
+            scope.spawn(move || loop {
+                let height = {
+                    let mut progress = progressed
+                        .wait_while(progress.lock().unwrap(), |progress| {
+                            !progress.stopped
+                                && progress.next < range.end
+                                && progress.next - progress.connected >= window
+                        })
+                        .unwrap();
+                    if progress.stopped || progress.next >= range.end {
+                        return;
+                    }
+                    progress.next += 1;
+                    progress.next - 1
+                };
+                let result = source.block(height).map(|block| {
+                    let violations = check_block_rules(&block, &config);
+                    (block, violations)
+                });
+                if sender.send((height, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut height = range.start;
+        for (fetched_height, result) in receiver {
+            pending.insert(fetched_height, result);
+            while let Some(result) = pending.remove(&height) {
+                let connected = match result {
+                    Err(source) => Err(SyncError::Source { height, source }),
+                    Ok((block, violations)) => state
+                        .connect_block_with(&block, height, violations)
+                        .map_err(|violations| SyncError::Invalid { height, violations }),
+                };
+                if let Err(err) = connected {
+                    stop();
+                    return Err(err);
+                }
+                height += 1;
+                progress.lock().unwrap().connected = height;
+                progressed.notify_all();
+            }
+        }
+        Ok(())
+    })
+}
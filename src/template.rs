@@ -0,0 +1,152 @@
+//! Assembly of the drivechain outputs of the next block's coinbase from the
+//! pending D1/D2 state and what the miner wants to vote for.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bitcoin::{hashes::Hash, TxOut};
+
+use crate::{
+    builder::CoinbaseBuilder,
+    messages::{
+        resolve_leading_by_50, CoinbaseMessage, M4AckBundles, SidechainNumber, SidechainProposalId,
+        Vote, Votes,
+    },
+    policy::BmmRequestPool,
+    state::Bip300State,
+};
+
+/// What a miner acks, votes for and accepts in its next block. Anything that
+/// the chain state would reject, such as an ack for a proposal that is no longer
+/// pending, is left out by [`assemble_coinbase_messages`] rather than reported.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MinerPolicy {
+    /// Pending proposals to ack with an M2.
+    pub ack_proposals: BTreeSet<(SidechainNumber, SidechainProposalId)>,
+    /// The pending bundle to upvote for each sidechain, by bundle txid.
+    /// Sidechains without one abstain.
+    pub upvote_bundles: BTreeMap<SidechainNumber, [u8; 32]>,
+    /// Sidechains to vote down every pending bundle of. Takes precedence over
+    /// `upvote_bundles`.
+    pub alarm: BTreeSet<SidechainNumber>,
+    /// Bundles to propose with an M3.
+    pub propose_bundles: Vec<(SidechainNumber, [u8; 32])>,
+    /// The sidechain block to accept with an M7 for each sidechain.
+    pub bmm_accepts: BTreeMap<SidechainNumber, [u8; 32]>,
+}
+
+impl MinerPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept every request admitted to `pool`, replacing any accept already
+    /// set for the same sidechain. `pool` should be on the tip the block is
+    /// assembled on.
+    pub fn accept_bmm_requests(&mut self, pool: &BmmRequestPool) -> &mut Self {
+        for (_, request) in pool.requests() {
+            self.bmm_accepts
+                .insert(request.sidechain_number, request.sidechain_block_hash);
+        }
+        self
+    }
+}
+
+/// The votes `policy` casts for the sidechains active in `state`, in vote vector
+/// order. Upvotes for bundle indexes no M4 can encode become abstentions.
+pub fn policy_votes(state: &Bip300State, policy: &MinerPolicy) -> Votes {
+    state
+        .proposals()
+        .active_sidechains()
+        .iter()
+        .map(|sidechain_number| {
+            if policy.alarm.contains(&sidechain_number) {
+                return Vote::Alarm;
+            }
+            policy
+                .upvote_bundles
+                .get(&sidechain_number)
+                .and_then(|bundle_txid| {
+                    state
+                        .bundles()
+                        .bundles(sidechain_number)
+                        .iter()
+                        .position(|bundle| bundle.bundle_txid == *bundle_txid)
+                })
+                .and_then(|index| u16::try_from(index).ok())
+                .map(Vote::Upvote)
+                .filter(|vote| vote.to_two_bytes().is_some())
+                .unwrap_or(Vote::Abstain)
+        })
+        .collect()
+}
+
+/// The drivechain messages the next block's coinbase should carry under
+/// `policy`, valid on top of `state`: M2s for pending proposals, the cheapest M4
+/// for the policy's votes (none if they all abstain), M3s for active sidechains
+/// that aren't already pending, and M7s for active sidechains.
+pub fn assemble_coinbase_messages(
+    state: &Bip300State,
+    policy: &MinerPolicy,
+) -> Vec<CoinbaseMessage> {
+    let proposals = state.proposals();
+    let bundles = state.bundles();
+    let active_sidechains = proposals.active_sidechains();
+    let mut messages = Vec::new();
+
+    for (sidechain_number, proposal_id) in &policy.ack_proposals {
+        if proposals
+            .proposal(*sidechain_number, *proposal_id)
+            .is_some()
+        {
+            messages.push(CoinbaseMessage::M2AckSidechain {
+                sidechain_number: *sidechain_number,
+                data_hash: proposal_id.to_byte_array(),
+            });
+        }
+    }
+
+    let votes = policy_votes(state, policy);
+    if votes.iter().any(|vote| *vote != Vote::Abstain) {
+        let leading_by_50 = resolve_leading_by_50(&bundles.standings(&active_sidechains));
+        messages.push(CoinbaseMessage::M4AckBundles(M4AckBundles::cheapest(
+            &votes,
+            bundles.previous_votes(),
+            Some(&leading_by_50),
+        )));
+    }
+
+    let mut proposed = BTreeSet::new();
+    for (sidechain_number, bundle_txid) in &policy.propose_bundles {
+        if active_sidechains.contains(*sidechain_number)
+            && bundles.bundle(*sidechain_number, bundle_txid).is_none()
+            && proposed.insert((*sidechain_number, *bundle_txid))
+        {
+            messages.push(CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: *sidechain_number,
+                bundle_txid: *bundle_txid,
+            });
+        }
+    }
+
+    for (sidechain_number, sidechain_block_hash) in &policy.bmm_accepts {
+        if active_sidechains.contains(*sidechain_number) {
+            messages.push(CoinbaseMessage::M7BmmAccept {
+                sidechain_number: *sidechain_number,
+                sidechain_block_hash: *sidechain_block_hash,
+            });
+        }
+    }
+    messages
+}
+
+/// [`assemble_coinbase_messages`] as zero-value coinbase outputs, to append
+/// after the outputs paying the block reward.
+pub fn assemble_coinbase_outputs(state: &Bip300State, policy: &MinerPolicy) -> Vec<TxOut> {
+    assemble_coinbase_messages(state, policy)
+        .into_iter()
+        .fold(CoinbaseBuilder::new(), |builder, message| {
+            builder.push(message)
+        })
+        .build()
+        .expect("policy votes always encode")
+}
@@ -0,0 +1,215 @@
+//! Reference encodings of every message kind, including edge cases like an empty
+//! M1 payload and vote vectors covering all 256 sidechains, for other
+//! implementations to check themselves against.
+
+use bitcoin::{hex::DisplayHex, ScriptBuf};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    messages::{CoinbaseMessage, M4AckBundles, M8BmmRequest, SidechainNumber, Vote, Votes},
+    parser::{parse_coinbase_script_strict, parse_m8_bmm_request_strict},
+};
+
+pub const M1_EMPTY_DATA_HEX: &str = "6ad5e0c4af00";
+
+pub const M1_WITH_DATA_HEX: &str = "6ad5e0c4af0d000102030405060708090a0b0c0d0e0f";
+
+pub const M2_ACK_SIDECHAIN_HEX: &str =
+    "6ad6e1c5dfff1111111111111111111111111111111111111111111111111111111111111111";
+
+pub const M3_PROPOSE_BUNDLE_HEX: &str =
+    "6ad45aa94302000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+pub const M4_REPEAT_PREVIOUS_HEX: &str = "6ad77d177600";
+
+pub const M4_ONE_BYTE_EMPTY_HEX: &str = "6ad77d177601";
+
+pub const M4_ONE_BYTE_HEX: &str = "6ad77d17760100fffefd";
+
+pub const M4_ONE_BYTE_EVERY_SIDECHAIN_HEX: &str = "6ad77d177601000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f606162636465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9fa0a1a2a3a4a5a6a7a8a9aaabacadaeafb0b1b2b3b4b5b6b7b8b9babbbcbdbebfc0c1c2c3c4c5c6c7c8c9cacbcccdcecfd0d1d2d3d4d5d6d7d8d9dadbdcdddedfe0e1e2e3e4e5e6e7e8e9eaebecedeeeff0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+
+pub const M4_TWO_BYTES_HEX: &str = "6ad77d177602000003e8fffffffe";
+
+pub const M4_TWO_BYTES_EVERY_SIDECHAIN_HEX: &str = "6ad77d17760200000100020003000400050006000700080009000a000b000c000d000e000f0010001100120013001400150016001700180019001a001b001c001d001e001f0020002100220023002400250026002700280029002a002b002c002d002e002f0030003100320033003400350036003700380039003a003b003c003d003e003f0040004100420043004400450046004700480049004a004b004c004d004e004f0050005100520053005400550056005700580059005a005b005c005d005e005f0060006100620063006400650066006700680069006a006b006c006d006e006f0070007100720073007400750076007700780079007a007b007c007d007e007f0080008100820083008400850086008700880089008a008b008c008d008e008f0090009100920093009400950096009700980099009a009b009c009d009e009f00a000a100a200a300a400a500a600a700a800a900aa00ab00ac00ad00ae00af00b000b100b200b300b400b500b600b700b800b900ba00bb00bc00bd00be00bf00c000c100c200c300c400c500c600c700c800c900ca00cb00cc00cd00ce00cf00d000d100d200d300d400d500d600d700d800d900da00db00dc00dd00de00df00e000e100e200e300e400e500e600e700e800e900ea00eb00ec00ed00ee00ef00f000f100f200f300f400f500f600f700f800f900fa00fb00fc00fd00fe00ff00";
+
+pub const M4_LEADING_BY_50_HEX: &str = "6ad77d177603";
+
+pub const M7_BMM_ACCEPT_HEX: &str =
+    "6ad161736807efefefefefefefefefefefefefefefefefefefefefefefefefefefefefefefef";
+
+pub const M8_BMM_REQUEST_HEX: &str = "6a00bf000811111111111111111111111111111111111111111111111111111111111111112222222222222222222222222222222222222222222222222222222222222222";
+
+/// A coinbase message and the script hex it encodes to.
+#[derive(Clone, Copy, Debug)]
+pub struct CoinbaseMessageVector {
+    pub name: &'static str,
+    pub script_hex: &'static str,
+    pub message: fn() -> CoinbaseMessage,
+}
+
+pub const COINBASE_MESSAGE_VECTORS: &[CoinbaseMessageVector] = &[
+    CoinbaseMessageVector {
+        name: "m1_empty_data",
+        script_hex: M1_EMPTY_DATA_HEX,
+        message: || CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: SidechainNumber(0),
+            data: Vec::<u8>::new().into(),
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m1_with_data",
+        script_hex: M1_WITH_DATA_HEX,
+        message: || CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: SidechainNumber(13),
+            data: (0..16).collect::<Vec<u8>>().into(),
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m2_ack_sidechain",
+        script_hex: M2_ACK_SIDECHAIN_HEX,
+        message: || CoinbaseMessage::m2_ack_sidechain(SidechainNumber(255), [0x11; 32]),
+    },
+    CoinbaseMessageVector {
+        name: "m3_propose_bundle",
+        script_hex: M3_PROPOSE_BUNDLE_HEX,
+        message: || {
+            CoinbaseMessage::m3_propose_bundle(SidechainNumber(2), std::array::from_fn(|i| i as u8))
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_repeat_previous",
+        script_hex: M4_REPEAT_PREVIOUS_HEX,
+        message: || CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious),
+    },
+    CoinbaseMessageVector {
+        name: "m4_one_byte_empty",
+        script_hex: M4_ONE_BYTE_EMPTY_HEX,
+        message: || {
+            CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: Votes::new(),
+            })
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_one_byte",
+        script_hex: M4_ONE_BYTE_HEX,
+        message: || {
+            CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: vec![
+                    Vote::Upvote(0),
+                    Vote::Abstain,
+                    Vote::Alarm,
+                    Vote::Upvote(253),
+                ]
+                .into(),
+            })
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_one_byte_every_sidechain",
+        script_hex: M4_ONE_BYTE_EVERY_SIDECHAIN_HEX,
+        message: || {
+            CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: (0..=u8::MAX).map(Vote::from_one_byte).collect(),
+            })
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_two_bytes",
+        script_hex: M4_TWO_BYTES_HEX,
+        message: || {
+            CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+                upvotes: vec![
+                    Vote::Upvote(0),
+                    Vote::Upvote(1000),
+                    Vote::Abstain,
+                    Vote::Alarm,
+                ]
+                .into(),
+            })
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_two_bytes_every_sidechain",
+        script_hex: M4_TWO_BYTES_EVERY_SIDECHAIN_HEX,
+        message: || {
+            CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+                upvotes: (0..=u8::MAX)
+                    .map(|i| Vote::Upvote(u16::from(i) << 8))
+                    .collect(),
+            })
+        },
+    },
+    CoinbaseMessageVector {
+        name: "m4_leading_by_50",
+        script_hex: M4_LEADING_BY_50_HEX,
+        message: || CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50),
+    },
+    CoinbaseMessageVector {
+        name: "m7_bmm_accept",
+        script_hex: M7_BMM_ACCEPT_HEX,
+        message: || CoinbaseMessage::m7_bmm_accept(SidechainNumber(7), [0xEF; 32]),
+    },
+];
+
+/// The M8 encoded by [`M8_BMM_REQUEST_HEX`].
+pub fn m8_bmm_request() -> M8BmmRequest {
+    M8BmmRequest::new(SidechainNumber(8), [0x11; 32], [0x22; 32])
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+#[error("test vector {name} encodes to {actual}, expected {expected}")]
+pub struct TestVectorMismatch {
+    pub name: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Check that every vector encodes to its hex and that the hex parses back to the
+/// same message. Meant to be called from downstream test suites, like
+/// [`crate::verify_m6_id_vectors`].
+pub fn verify_test_vectors() -> Result<(), TestVectorMismatch> {
+    for vector in COINBASE_MESSAGE_VECTORS {
+        let message = (vector.message)();
+        let script = message.to_script().map_err(|err| TestVectorMismatch {
+            name: vector.name,
+            expected: vector.script_hex.to_owned(),
+            actual: err.to_string(),
+        })?;
+        check(vector.name, vector.script_hex, &script)?;
+        let parsed = parse_coinbase_script_strict(&script).ok();
+        if parsed.as_ref() != Some(&message) {
+            return Err(TestVectorMismatch {
+                name: vector.name,
+                expected: format!("{message:?}"),
+                actual: format!("{parsed:?}"),
+            });
+        }
+    }
+    let request = m8_bmm_request();
+    let script = request.encode();
+    check("m8_bmm_request", M8_BMM_REQUEST_HEX, &script)?;
+    let parsed = parse_m8_bmm_request_strict(script.as_bytes()).ok();
+    if parsed.as_ref() != Some(&request) {
+        return Err(TestVectorMismatch {
+            name: "m8_bmm_request",
+            expected: format!("{request:?}"),
+            actual: format!("{parsed:?}"),
+        });
+    }
+    Ok(())
+}
+
+fn check(name: &'static str, expected: &str, script: &ScriptBuf) -> Result<(), TestVectorMismatch> {
+    let actual = script.as_bytes().to_lower_hex_string();
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(TestVectorMismatch {
+            name,
+            expected: expected.to_owned(),
+            actual,
+        })
+    }
+}
@@ -0,0 +1,308 @@
+//! Transactions that move a sidechain's treasury: M5 deposits and M6 withdrawals.
+
+use bitcoin::{
+    absolute::LockTime,
+    hex::DisplayHex,
+    opcodes::{all::OP_PUSHBYTES_1, OP_TRUE},
+    transaction, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    messages::{m6_to_id, SidechainNumber, OP_DRIVECHAIN},
+    parser::try_parse_op_drivechain,
+    state::Ctip,
+};
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+pub enum TreasuryTxError {
+    #[error("transaction has no treasury output")]
+    NoTreasuryOutput,
+    #[error("transaction has more than one treasury output: {vouts:?}")]
+    MultipleTreasuryOutputs { vouts: Vec<u32> },
+    #[error("transaction does not spend the current treasury {}", .ctip.outpoint)]
+    CtipNotSpent { ctip: Ctip },
+    #[error("treasury output is for sidechain {found}, expected sidechain {expected}")]
+    WrongSidechain {
+        expected: SidechainNumber,
+        found: SidechainNumber,
+    },
+    #[error("treasury value goes from {previous} to {new}, a deposit must increase it")]
+    NotADeposit { previous: Amount, new: Amount },
+    #[error("treasury value overflows")]
+    ValueOverflow,
+    #[error("a deposit must be for more than zero")]
+    ZeroDeposit,
+    #[error("treasury of {treasury} can't cover payouts and fee of {required}")]
+    InsufficientTreasury { treasury: Amount, required: Amount },
+    #[error("treasury output is at index {vout}, a withdrawal must create it at index 0")]
+    TreasuryNotFirst { vout: u32 },
+    #[error("withdrawal has {count} inputs, it must only spend the treasury")]
+    UnexpectedInputs { count: usize },
+    #[error("treasury value goes from {previous} to {new}, a withdrawal must decrease it")]
+    NotAWithdrawal { previous: Amount, new: Amount },
+    #[error("withdrawal pays out bundle {}, but bundle {} was acked", .got.as_hex(), .expected.as_hex())]
+    BundleMismatch { expected: [u8; 32], got: [u8; 32] },
+}
+
+/// The script of a sidechain's treasury output:
+/// `OP_DRIVECHAIN OP_PUSHBYTES_1 <sidechain_number> OP_TRUE`.
+pub fn create_op_drivechain_script(sidechain_number: SidechainNumber) -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![
+        OP_DRIVECHAIN.to_u8(),
+        OP_PUSHBYTES_1.to_u8(),
+        sidechain_number.0,
+        OP_TRUE.to_u8(),
+    ])
+}
+
+fn unsigned_input(previous_output: OutPoint) -> TxIn {
+    TxIn {
+        previous_output,
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+    }
+}
+
+/// A decoded M5.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct M5Deposit {
+    pub sidechain_number: SidechainNumber,
+    /// Index of the new treasury output.
+    pub treasury_vout: u32,
+    pub new_treasury_value: Amount,
+    /// How much the deposit adds to the treasury.
+    pub amount: Amount,
+}
+
+/// The single treasury output of `tx`, with its index and sidechain.
+fn treasury_output(tx: &Transaction) -> Result<(u32, SidechainNumber, Amount), TreasuryTxError> {
+    let mut treasury_outputs = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, output)| {
+            let sidechain_number = try_parse_op_drivechain(&output.script_pubkey)?;
+            Some((vout as u32, sidechain_number, output.value))
+        })
+        .collect::<Vec<_>>();
+    match treasury_outputs.len() {
+        0 => Err(TreasuryTxError::NoTreasuryOutput),
+        1 => Ok(treasury_outputs.remove(0)),
+        _ => Err(TreasuryTxError::MultipleTreasuryOutputs {
+            vouts: treasury_outputs.iter().map(|(vout, _, _)| *vout).collect(),
+        }),
+    }
+}
+
+fn check_spends(tx: &Transaction, ctip: &Ctip) -> Result<(), TreasuryTxError> {
+    if tx
+        .input
+        .iter()
+        .any(|input| input.previous_output == ctip.outpoint)
+    {
+        Ok(())
+    } else {
+        Err(TreasuryTxError::CtipNotSpent { ctip: *ctip })
+    }
+}
+
+/// Decode `tx` as an M5 deposit to `sidechain_number`, whose current treasury is
+/// `ctip`. Pass `None` for the first deposit to a sidechain, which creates its
+/// treasury instead of spending one.
+pub fn parse_m5_deposit(
+    tx: &Transaction,
+    sidechain_number: SidechainNumber,
+    ctip: Option<&Ctip>,
+) -> Result<M5Deposit, TreasuryTxError> {
+    let (treasury_vout, found, new_treasury_value) = treasury_output(tx)?;
+    if found != sidechain_number {
+        return Err(TreasuryTxError::WrongSidechain {
+            expected: sidechain_number,
+            found,
+        });
+    }
+    let previous = match ctip {
+        Some(ctip) => {
+            check_spends(tx, ctip)?;
+            ctip.value
+        }
+        None => Amount::ZERO,
+    };
+    if new_treasury_value <= previous {
+        return Err(TreasuryTxError::NotADeposit {
+            previous,
+            new: new_treasury_value,
+        });
+    }
+    Ok(M5Deposit {
+        sidechain_number,
+        treasury_vout,
+        new_treasury_value,
+        amount: new_treasury_value - previous,
+    })
+}
+
+/// The skeleton of an M5 depositing `amount` to `sidechain_number`: the input
+/// spending the current treasury `ctip` (if the sidechain has one yet) and the
+/// new treasury output at index 0 holding the old value plus `amount`. The
+/// depositor adds the inputs funding `amount` and any change output after these,
+/// then signs. A zero `amount` is rejected, as the deposit would not increase
+/// the treasury.
+pub fn build_m5_deposit(
+    sidechain_number: SidechainNumber,
+    ctip: Option<&Ctip>,
+    amount: Amount,
+) -> Result<Transaction, TreasuryTxError> {
+    if amount == Amount::ZERO {
+        return Err(TreasuryTxError::ZeroDeposit);
+    }
+    let previous = ctip.map_or(Amount::ZERO, |ctip| ctip.value);
+    let value = previous
+        .checked_add(amount)
+        .ok_or(TreasuryTxError::ValueOverflow)?;
+    Ok(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: ctip
+            .map(|ctip| unsigned_input(ctip.outpoint))
+            .into_iter()
+            .collect(),
+        output: vec![TxOut {
+            value,
+            script_pubkey: create_op_drivechain_script(sidechain_number),
+        }],
+    })
+}
+
+/// An unsigned M6 paying out `payouts` from the treasury `ctip` of
+/// `sidechain_number`, leaving `fee` for the miner: the treasury input, the new
+/// treasury output at index 0 and the payouts after it in the given order. Use
+/// [`crate::m6_to_id`] on the result for the bundle id to propose in an M3.
+pub fn build_m6_withdrawal(
+    sidechain_number: SidechainNumber,
+    ctip: &Ctip,
+    payouts: Vec<TxOut>,
+    fee: Amount,
+) -> Result<Transaction, TreasuryTxError> {
+    let required = payouts
+        .iter()
+        .try_fold(fee, |total, payout| total.checked_add(payout.value))
+        .ok_or(TreasuryTxError::ValueOverflow)?;
+    let value = ctip
+        .value
+        .checked_sub(required)
+        .ok_or(TreasuryTxError::InsufficientTreasury {
+            treasury: ctip.value,
+            required,
+        })?;
+    let treasury = TxOut {
+        value,
+        script_pubkey: create_op_drivechain_script(sidechain_number),
+    };
+    Ok(Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![unsigned_input(ctip.outpoint)],
+        output: [treasury].into_iter().chain(payouts).collect(),
+    })
+}
+
+/// A decoded M6.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct M6Withdrawal {
+    pub sidechain_number: SidechainNumber,
+    /// Every output after the new treasury, in order.
+    pub payouts: Vec<TxOut>,
+    pub new_treasury_value: Amount,
+    pub fee: Amount,
+}
+
+/// Decode `tx` as an M6 withdrawal from `sidechain_number`, whose current treasury
+/// is `ctip`. This checks the shape of the transaction only; whether its bundle
+/// was acked is up to the caller.
+pub fn parse_m6_withdrawal(
+    tx: &Transaction,
+    sidechain_number: SidechainNumber,
+    ctip: &Ctip,
+) -> Result<M6Withdrawal, TreasuryTxError> {
+    check_spends(tx, ctip)?;
+    if tx.input.len() != 1 {
+        return Err(TreasuryTxError::UnexpectedInputs {
+            count: tx.input.len(),
+        });
+    }
+    m6_values(tx, Some(sidechain_number), ctip.value)
+}
+
+/// The treasury and payouts of `tx` as a withdrawal from a treasury holding
+/// `previous`, checking the treasury output is for `expected` if given.
+fn m6_values(
+    tx: &Transaction,
+    expected: Option<SidechainNumber>,
+    previous: Amount,
+) -> Result<M6Withdrawal, TreasuryTxError> {
+    let (treasury_vout, sidechain_number, new_treasury_value) = treasury_output(tx)?;
+    if let Some(expected) = expected {
+        if sidechain_number != expected {
+            return Err(TreasuryTxError::WrongSidechain {
+                expected,
+                found: sidechain_number,
+            });
+        }
+    }
+    if treasury_vout != 0 {
+        return Err(TreasuryTxError::TreasuryNotFirst {
+            vout: treasury_vout,
+        });
+    }
+    if new_treasury_value >= previous {
+        return Err(TreasuryTxError::NotAWithdrawal {
+            previous,
+            new: new_treasury_value,
+        });
+    }
+    let payouts = tx.output[1..].to_vec();
+    let required = payouts
+        .iter()
+        .try_fold(new_treasury_value, |total, payout| {
+            total.checked_add(payout.value)
+        })
+        .ok_or(TreasuryTxError::ValueOverflow)?;
+    let fee = previous
+        .checked_sub(required)
+        .ok_or(TreasuryTxError::InsufficientTreasury {
+            treasury: previous,
+            required,
+        })?;
+    Ok(M6Withdrawal {
+        sidechain_number,
+        payouts,
+        new_treasury_value,
+        fee,
+    })
+}
+
+/// Check `tx` as the M6 paying out the bundle `acked_bundle_hash` from a
+/// treasury holding `previous_treasury_value`: the new treasury is output 0,
+/// `T_n = T_n-1 - P_total - F_total` holds with a non-negative fee and no
+/// overflow, and the blinded txid (see [`m6_to_id`]) is the acked bundle. Which
+/// outpoint `tx` spends is not checked, see [`parse_m6_withdrawal`] for that.
+pub fn validate_m6(
+    tx: &Transaction,
+    previous_treasury_value: Amount,
+    acked_bundle_hash: &[u8; 32],
+) -> Result<M6Withdrawal, TreasuryTxError> {
+    let withdrawal = m6_values(tx, None, previous_treasury_value)?;
+    let bundle_hash =
+        m6_to_id(tx, previous_treasury_value.to_sat()).expect("withdrawal values were checked");
+    if bundle_hash != *acked_bundle_hash {
+        return Err(TreasuryTxError::BundleMismatch {
+            expected: *acked_bundle_hash,
+            got: bundle_hash,
+        });
+    }
+    Ok(withdrawal)
+}
@@ -0,0 +1,652 @@
+//! Rules checked against blocks and transactions that go beyond decoding.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use bitcoin::{hashes::Hash, Amount, Block, BlockHash, OutPoint, Transaction, Txid};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    bundles::{BundleEvent, BundleVoteError, BundleVotes},
+    messages::{
+        m6_to_id, sha256d, CoinbaseMessage, M8BmmRequest, MessageKind, Placement, SidechainNumber,
+    },
+    parser::{
+        parse_coinbase_script_versioned, parse_m8_bmm_request, try_parse_op_drivechain,
+        Bip300Error, SpecVersion,
+    },
+    proposals::{ProposalError, ProposalEvent, SidechainProposals},
+    scan::{scan_coinbase, scan_message_kinds},
+    state::{Bip300State, Ctip, CtipViolation},
+    treasury::{parse_m5_deposit, parse_m6_withdrawal, M5Deposit, M6Withdrawal, TreasuryTxError},
+};
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ParseMode {
+    /// Report heuristic findings as warnings.
+    #[default]
+    Lenient,
+    /// Report heuristic findings as errors.
+    Strict,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Optional rules applied when validating drivechain outputs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParseConfig {
+    /// Flag coinbase message outputs and M8 outputs that carry a non-zero value.
+    pub require_zero_value: bool,
+    /// Flag M7 accepts with no matching M8 request in the same block. BIP301 does
+    /// not require this, so it is off by default.
+    pub require_bmm_request: bool,
+    /// Spec revision used to decode the messages being validated.
+    pub spec_version: SpecVersion,
+    /// Flag all-zero and repeated-byte hashes in M2, M3, M7 and M8 messages, which
+    /// are almost always an encoding bug upstream.
+    pub check_hashes: bool,
+    pub mode: ParseMode,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            require_zero_value: true,
+            require_bmm_request: false,
+            spec_version: SpecVersion::default(),
+            check_hashes: false,
+            mode: ParseMode::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// A coinbase message or M8 output carries a non-zero value.
+    NonZeroValue {
+        txid: Txid,
+        vout: usize,
+        kind: MessageKind,
+        value: Amount,
+    },
+    /// An M7 accepts a sidechain block hash that no M8 in the block requested.
+    UnrequestedBmmAccept {
+        sidechain_number: SidechainNumber,
+        sidechain_block_hash: [u8; 32],
+    },
+    /// A hash field consists of a single repeated byte, e.g. all zeroes.
+    SuspiciousHash {
+        txid: Txid,
+        vout: usize,
+        field: &'static str,
+        hash: [u8; 32],
+    },
+    /// A message appears in a transaction where its kind is not valid, e.g. an
+    /// M2 in a regular transaction or an M8 in a coinbase.
+    MisplacedMessage {
+        txid: Txid,
+        vout: usize,
+        kind: MessageKind,
+    },
+    /// The block breaks the treasury chain of a sidechain.
+    Treasury(CtipViolation),
+    /// The block is not at the height after the current tip.
+    UnexpectedHeight { expected: u32, height: u32 },
+    /// The block does not build on the current tip.
+    NotOnTip {
+        tip: BlockHash,
+        prev_blockhash: BlockHash,
+    },
+    /// A coinbase output starts with a message tag but does not decode.
+    MalformedMessage { vout: usize, error: Bip300Error },
+    /// The block breaks a sidechain proposal (D1) rule.
+    Proposal(ProposalError),
+    /// The block breaks a bundle vote (D2) rule, e.g. an M4 with the wrong
+    /// number of votes.
+    BundleVote(BundleVoteError),
+    /// A transaction moves a treasury in a way neither a deposit nor a
+    /// withdrawal may.
+    TreasuryTx {
+        txid: Txid,
+        sidechain_number: SidechainNumber,
+        error: TreasuryTxError,
+    },
+    /// An M6 pays out a bundle that has not reached its ack threshold.
+    UnackedWithdrawal {
+        txid: Txid,
+        sidechain_number: SidechainNumber,
+        bundle_txid: [u8; 32],
+    },
+    /// A transaction creates the first treasury of a sidechain that is not
+    /// active.
+    InactiveSidechainDeposit {
+        txid: Txid,
+        sidechain_number: SidechainNumber,
+    },
+    /// An M7 accepts a block of a sidechain that is not active.
+    InactiveSidechainBmmAccept { sidechain_number: SidechainNumber },
+    /// The coinbase has more than one M7 for the same sidechain.
+    DuplicateBmmAccept { sidechain_number: SidechainNumber },
+}
+
+impl Violation {
+    /// Heuristic findings are warnings in lenient mode. Misplaced messages and
+    /// valued M8s are always warnings: anyone can put them in a transaction, so
+    /// rejecting the block over them would let them stall every tracker.
+    /// Everything else is always an error.
+    pub fn severity(&self, mode: ParseMode) -> Severity {
+        match (self, mode) {
+            (Self::SuspiciousHash { .. }, ParseMode::Lenient)
+            | (Self::MisplacedMessage { .. }, _)
+            | (
+                Self::NonZeroValue {
+                    kind: MessageKind::M8BmmRequest,
+                    ..
+                },
+                _,
+            ) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// Flag every drivechain message output in `tx` with a non-zero value.
+/// Coinbase messages are only considered if `tx` is a coinbase, M8 requests are
+/// considered in any transaction.
+pub fn check_output_values(tx: &Transaction, config: &ParseConfig) -> Vec<Violation> {
+    if !config.require_zero_value {
+        return vec![];
+    }
+    let is_coinbase = tx.is_coinbase();
+    let offending: Vec<(usize, MessageKind, Amount)> = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.value != Amount::ZERO)
+        .filter_map(|(vout, output)| {
+            let script = output.script_pubkey.as_script();
+            let kind = if is_coinbase {
+                parse_coinbase_script_versioned(script, config.spec_version)
+                    .ok()
+                    .map(|(_, message)| message.kind())
+            } else {
+                None
+            };
+            let kind = kind.or_else(|| {
+                parse_m8_bmm_request(script.as_bytes())
+                    .ok()
+                    .map(|_| MessageKind::M8BmmRequest)
+            })?;
+            Some((vout, kind, output.value))
+        })
+        .collect();
+    if offending.is_empty() {
+        return vec![];
+    }
+    let txid = tx.compute_txid();
+    offending
+        .into_iter()
+        .map(|(vout, kind, value)| Violation::NonZeroValue {
+            txid,
+            vout,
+            kind,
+            value,
+        })
+        .collect()
+}
+
+/// Flag every M7 in the coinbase of `block` without an M8 request for the same
+/// sidechain and sidechain block hash, built on this block's parent, elsewhere in
+/// `block`. Only applied if `config.require_bmm_request` is set.
+pub fn check_bmm_accepts(block: &Block, config: &ParseConfig) -> Vec<Violation> {
+    if !config.require_bmm_request {
+        return vec![];
+    }
+    let Some(coinbase) = block.coinbase() else {
+        return vec![];
+    };
+    let prev_mainchain_block_hash = block.header.prev_blockhash.to_byte_array();
+    let requests: Vec<M8BmmRequest> = block
+        .txdata
+        .iter()
+        .skip(1)
+        .flat_map(|tx| tx.output.iter())
+        .filter_map(|output| parse_m8_bmm_request(output.script_pubkey.as_bytes()).ok())
+        .map(|(_, request)| request)
+        .filter(|request| request.prev_mainchain_block_hash == prev_mainchain_block_hash)
+        .collect();
+    coinbase
+        .output
+        .iter()
+        .filter_map(|output| {
+            parse_coinbase_script_versioned(&output.script_pubkey, config.spec_version).ok()
+        })
+        .map(|(_, message)| message)
+        .filter(|message| {
+            !requests
+                .iter()
+                .any(|request| message.matches_bmm_request(request))
+        })
+        .filter_map(|message| match message {
+            CoinbaseMessage::M7BmmAccept {
+                sidechain_number,
+                sidechain_block_hash,
+            } => Some(Violation::UnrequestedBmmAccept {
+                sidechain_number,
+                sidechain_block_hash,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_suspicious_hash(hash: &[u8; 32]) -> bool {
+    hash.iter().all(|byte| *byte == hash[0])
+}
+
+/// Flag hash fields in `tx` that consist of a single repeated byte. Coinbase
+/// messages are only considered if `tx` is a coinbase, M8 requests are considered
+/// in any transaction. Only applied if `config.check_hashes` is set.
+pub fn check_message_hashes(tx: &Transaction, config: &ParseConfig) -> Vec<Violation> {
+    if !config.check_hashes {
+        return vec![];
+    }
+    let is_coinbase = tx.is_coinbase();
+    let mut suspicious: Vec<(usize, &'static str, [u8; 32])> = vec![];
+    for (vout, output) in tx.output.iter().enumerate() {
+        let script = output.script_pubkey.as_script();
+        if is_coinbase {
+            if let Ok((_, message)) = parse_coinbase_script_versioned(script, config.spec_version) {
+                match message {
+                    CoinbaseMessage::M2AckSidechain { data_hash, .. } => {
+                        suspicious.push((vout, "data_hash", data_hash));
+                    }
+                    CoinbaseMessage::M3ProposeBundle { bundle_txid, .. } => {
+                        suspicious.push((vout, "bundle_txid", bundle_txid));
+                    }
+                    CoinbaseMessage::M7BmmAccept {
+                        sidechain_block_hash,
+                        ..
+                    } => {
+                        suspicious.push((vout, "sidechain_block_hash", sidechain_block_hash));
+                    }
+                    CoinbaseMessage::M1ProposeSidechain { .. }
+                    | CoinbaseMessage::M4AckBundles(_) => (),
+                }
+            }
+        }
+        if let Ok((_, request)) = parse_m8_bmm_request(script.as_bytes()) {
+            suspicious.push((vout, "sidechain_block_hash", request.sidechain_block_hash));
+            suspicious.push((
+                vout,
+                "prev_mainchain_block_hash",
+                request.prev_mainchain_block_hash,
+            ));
+        }
+    }
+    suspicious.retain(|(_, _, hash)| is_suspicious_hash(hash));
+    if suspicious.is_empty() {
+        return vec![];
+    }
+    let txid = tx.compute_txid();
+    suspicious
+        .into_iter()
+        .map(|(vout, field, hash)| Violation::SuspiciousHash {
+            txid,
+            vout,
+            field,
+            hash,
+        })
+        .collect()
+}
+
+/// Flag every coinbase output of `block` that carries a message tag but does not
+/// decode.
+pub fn check_coinbase_messages(block: &Block, config: &ParseConfig) -> Vec<Violation> {
+    let Some(coinbase) = block.coinbase() else {
+        return vec![];
+    };
+    scan_coinbase(coinbase, config.spec_version)
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|(vout, error)| Violation::MalformedMessage { vout, error })
+        .collect()
+}
+
+/// Flag every message in `block` that appears in a transaction where its kind is
+/// not valid, see [`MessageKind::placement`].
+pub fn check_message_placement(block: &Block, config: &ParseConfig) -> Vec<Violation> {
+    let mut violations = vec![];
+    for tx in &block.txdata {
+        let placement = if tx.is_coinbase() {
+            Placement::Coinbase
+        } else {
+            Placement::NonCoinbase
+        };
+        let misplaced: Vec<(usize, MessageKind)> = scan_message_kinds(tx, config.spec_version)
+            .into_iter()
+            .filter(|(_, kind)| kind.placement() != placement)
+            .collect();
+        if misplaced.is_empty() {
+            continue;
+        }
+        let txid = tx.compute_txid();
+        violations.extend(
+            misplaced
+                .into_iter()
+                .map(|(vout, kind)| Violation::MisplacedMessage { txid, vout, kind }),
+        );
+    }
+    violations
+}
+
+// Below this many proposals per thread, spawning threads costs more than hashing.
+const MIN_PROPOSALS_PER_THREAD: usize = 64;
+
+/// Link each ack to the proposal it acks: for every entry of `acks`, the index in
+/// `proposals` of the first M1 for the same sidechain whose data hashes to the M2's
+/// data hash, or `None` if there is none or the entry is not an M2. Proposal data
+/// is hashed across all available threads.
+pub fn verify_acks(acks: &[CoinbaseMessage], proposals: &[CoinbaseMessage]) -> Vec<Option<usize>> {
+    let threads = std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1);
+    verify_acks_with_threads(acks, proposals, threads)
+}
+
+/// [`verify_acks`] hashing on at most `threads` threads. Small blocks are hashed
+/// on the calling thread whatever `threads` is.
+pub fn verify_acks_with_threads(
+    acks: &[CoinbaseMessage],
+    proposals: &[CoinbaseMessage],
+    threads: usize,
+) -> Vec<Option<usize>> {
+    let hash_proposal = |proposal: &CoinbaseMessage| match proposal {
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number,
+            data,
+        } => Some((*sidechain_number, sha256d(data))),
+        _ => None,
+    };
+    let threads = threads
+        .min(proposals.len() / MIN_PROPOSALS_PER_THREAD)
+        .max(1);
+    let hashes: Vec<Option<(SidechainNumber, [u8; 32])>> = if threads == 1 {
+        proposals.iter().map(hash_proposal).collect()
+    } else {
+        let chunk_size = proposals.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = proposals
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || chunk.iter().map(hash_proposal).collect::<Vec<_>>())
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("proposal hashing thread panicked"))
+                .collect()
+        })
+    };
+    let mut index = HashMap::<(SidechainNumber, [u8; 32]), usize>::new();
+    for (proposal_index, hash) in hashes.into_iter().enumerate() {
+        if let Some(key) = hash {
+            index.entry(key).or_insert(proposal_index);
+        }
+    }
+    acks.iter()
+        .map(|ack| match ack {
+            CoinbaseMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } => index.get(&(*sidechain_number, *data_hash)).copied(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The checks in [`check_block`] that don't depend on any state, which can be
+/// run on many blocks in parallel.
+pub fn check_block_rules(block: &Block, config: &ParseConfig) -> Vec<Violation> {
+    let mut violations = vec![];
+    for tx in &block.txdata {
+        violations.extend(check_output_values(tx, config));
+        violations.extend(check_message_hashes(tx, config));
+    }
+    violations.extend(check_coinbase_messages(block, config));
+    violations.extend(check_bmm_accepts(block, config));
+    violations.extend(check_message_placement(block, config));
+    violations
+}
+
+/// What connecting a valid block does to the drivechain state.
+#[derive(Clone, Debug, Default)]
+pub struct BlockEffects {
+    pub height: u32,
+    /// Proposals the block activated or failed.
+    pub proposal_events: Vec<ProposalEvent>,
+    /// Bundles the block made spendable or expired.
+    pub bundle_events: Vec<BundleEvent>,
+    pub deposits: Vec<(Txid, M5Deposit)>,
+    /// Each M6 in the block, with the bundle it paid out.
+    pub withdrawals: Vec<(Txid, M6Withdrawal, [u8; 32])>,
+    /// The sidechain block hash each M7 in the coinbase accepted.
+    pub bmm_accepts: Vec<(SidechainNumber, [u8; 32])>,
+    proposals: SidechainProposals,
+    bundles: BundleVotes,
+}
+
+impl BlockEffects {
+    pub(crate) fn into_trackers(self) -> (SidechainProposals, BundleVotes) {
+        (self.proposals, self.bundles)
+    }
+}
+
+#[derive(Clone, Debug, Diagnostic, Eq, Error, PartialEq)]
+#[error("block breaks {} drivechain rule(s)", .violations.len())]
+pub struct ValidationError {
+    /// Every violation found, warnings included.
+    pub violations: Vec<Violation>,
+}
+
+/// Check the deposits and withdrawals in `block`. Only the value rules are
+/// checked here, the treasury chain itself is up to [`crate::CtipTracker`].
+fn check_treasury_txs(
+    block: &Block,
+    state: &Bip300State,
+    effects: &mut BlockEffects,
+    violations: &mut Vec<Violation>,
+) {
+    let mut ctips: BTreeMap<SidechainNumber, Ctip> = state
+        .ctips()
+        .ctips()
+        .map(|(sidechain_number, ctip)| (sidechain_number, *ctip))
+        .collect();
+    let active_sidechains = state.proposals().active_sidechains();
+    for tx in block.txdata.iter().skip(1) {
+        let created: BTreeMap<SidechainNumber, (u32, Amount)> = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let sidechain_number = try_parse_op_drivechain(&output.script_pubkey)?;
+                Some((sidechain_number, (vout as u32, output.value)))
+            })
+            .collect();
+        let spent = ctips
+            .iter()
+            .filter(|(_, ctip)| {
+                tx.input
+                    .iter()
+                    .any(|input| input.previous_output == ctip.outpoint)
+            })
+            .map(|(sidechain_number, _)| *sidechain_number);
+        let sidechains: BTreeSet<SidechainNumber> = created.keys().copied().chain(spent).collect();
+        if sidechains.is_empty() {
+            continue;
+        }
+        let txid = tx.compute_txid();
+        for sidechain_number in sidechains {
+            let Some((vout, value)) = created.get(&sidechain_number).copied() else {
+                continue;
+            };
+            let ctip = ctips.get(&sidechain_number).copied();
+            match ctip {
+                Some(ctip) if value < ctip.value => {
+                    match parse_m6_withdrawal(tx, sidechain_number, &ctip) {
+                        Ok(withdrawal) => {
+                            let bundle_txid = m6_to_id(tx, ctip.value.to_sat())
+                                .expect("withdrawal values were checked");
+                            if state.bundles().is_spendable(sidechain_number, &bundle_txid) {
+                                effects.bundles.spend(sidechain_number, &bundle_txid);
+                                effects.withdrawals.push((txid, withdrawal, bundle_txid));
+                            } else {
+                                violations.push(Violation::UnackedWithdrawal {
+                                    txid,
+                                    sidechain_number,
+                                    bundle_txid,
+                                });
+                            }
+                        }
+                        Err(error) => violations.push(Violation::TreasuryTx {
+                            txid,
+                            sidechain_number,
+                            error,
+                        }),
+                    }
+                }
+                None if !active_sidechains.contains(sidechain_number) => {
+                    violations.push(Violation::InactiveSidechainDeposit {
+                        txid,
+                        sidechain_number,
+                    });
+                }
+                _ => match parse_m5_deposit(tx, sidechain_number, ctip.as_ref()) {
+                    Ok(deposit) => effects.deposits.push((txid, deposit)),
+                    Err(error) => violations.push(Violation::TreasuryTx {
+                        txid,
+                        sidechain_number,
+                        error,
+                    }),
+                },
+            }
+            ctips.insert(
+                sidechain_number,
+                Ctip {
+                    outpoint: OutPoint { txid, vout },
+                    value,
+                },
+            );
+        }
+    }
+}
+
+/// The checks in [`check_block`] that depend on `state`, and what the block
+/// would do to it.
+pub(crate) fn check_block_state(
+    block: &Block,
+    height: u32,
+    state: &Bip300State,
+) -> (Vec<Violation>, BlockEffects) {
+    let mut violations = vec![];
+    if let Some((tip_height, tip)) = state.tip() {
+        let expected = tip_height + 1;
+        if height != expected {
+            violations.push(Violation::UnexpectedHeight { expected, height });
+        }
+        if block.header.prev_blockhash != tip {
+            violations.push(Violation::NotOnTip {
+                tip,
+                prev_blockhash: block.header.prev_blockhash,
+            });
+        }
+    }
+    if let Err(violation) = state.ctips().check_block(block) {
+        violations.push(Violation::Treasury(violation));
+    }
+
+    let messages: Vec<CoinbaseMessage> = block
+        .coinbase()
+        .map(|coinbase| scan_coinbase(coinbase, state.config().spec_version))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(|(_, message)| message)
+        .collect();
+    let active_sidechains = state.proposals().active_sidechains();
+    let mut effects = BlockEffects {
+        height,
+        proposals: state.proposals().clone(),
+        bundles: state.bundles().clone(),
+        ..BlockEffects::default()
+    };
+    match effects.proposals.connect_messages(&messages, height) {
+        Ok(events) => effects.proposal_events = events,
+        Err(error) => violations.push(Violation::Proposal(error)),
+    }
+    match effects
+        .bundles
+        .connect_messages(&messages, height, &active_sidechains)
+    {
+        Ok(events) => effects.bundle_events = events,
+        Err(error) => violations.push(Violation::BundleVote(error)),
+    }
+    for message in &messages {
+        if let CoinbaseMessage::M7BmmAccept {
+            sidechain_number,
+            sidechain_block_hash,
+        } = message
+        {
+            if !active_sidechains.contains(*sidechain_number) {
+                violations.push(Violation::InactiveSidechainBmmAccept {
+                    sidechain_number: *sidechain_number,
+                });
+            } else if effects
+                .bmm_accepts
+                .iter()
+                .any(|(accepted, _)| accepted == sidechain_number)
+            {
+                violations.push(Violation::DuplicateBmmAccept {
+                    sidechain_number: *sidechain_number,
+                });
+            } else {
+                effects
+                    .bmm_accepts
+                    .push((*sidechain_number, *sidechain_block_hash));
+            }
+        }
+    }
+    check_treasury_txs(block, state, &mut effects, &mut violations);
+    (violations, effects)
+}
+
+/// Every violation `block` would cause if connected at `height` on top of
+/// `state`, using `state`'s config. The state is not modified, so competing
+/// candidates for the same height can be checked against it one after another.
+pub fn check_block(block: &Block, height: u32, state: &Bip300State) -> Vec<Violation> {
+    let mut violations = check_block_rules(block, state.config());
+    violations.extend(check_block_state(block, height, state).0);
+    violations
+}
+
+/// Check `block` against every BIP300 rule as the block after the tip of
+/// `state` (see [`Bip300State::next_height`]): well-formed coinbase messages,
+/// the proposal and bundle vote rules, deposits only increasing a treasury,
+/// withdrawals only paying out spendable bundles and M7s only for active
+/// sidechains. On success, returns what the block would change.
+pub fn validate_block(block: &Block, state: &Bip300State) -> Result<BlockEffects, ValidationError> {
+    let mut violations = check_block_rules(block, state.config());
+    let (state_violations, effects) = check_block_state(block, state.next_height(), state);
+    violations.extend(state_violations);
+    if violations
+        .iter()
+        .any(|violation| violation.severity(state.config().mode) == Severity::Error)
+    {
+        return Err(ValidationError { violations });
+    }
+    Ok(effects)
+}
@@ -0,0 +1,43 @@
+//! `wasm-bindgen` entry points for decoding and encoding drivechain scripts in
+//! the browser. Messages cross the boundary as JSON in the `serde` format of
+//! [`CoinbaseMessage`] and [`M8BmmRequest`], and scripts as byte arrays.
+//!
+//! The exports end up in any `cdylib` that links this crate with the `wasm`
+//! feature enabled.
+
+use bitcoin::Script;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    messages::{CoinbaseMessage, M8BmmRequest},
+    parser::{parse_coinbase_script_strict, parse_m8_bmm_request_strict},
+};
+
+/// Decode a coinbase output script into a JSON message.
+#[wasm_bindgen(js_name = decodeCoinbaseScript)]
+pub fn decode_coinbase_script(script: &[u8]) -> Result<String, JsError> {
+    let message = parse_coinbase_script_strict(Script::from_bytes(script))?;
+    Ok(serde_json::to_string(&message)?)
+}
+
+/// Encode a JSON message into a coinbase output script.
+#[wasm_bindgen(js_name = encodeCoinbaseMessage)]
+pub fn encode_coinbase_message(message: &str) -> Result<Vec<u8>, JsError> {
+    let message: CoinbaseMessage = serde_json::from_str(message)?;
+    let script = message.to_script()?;
+    Ok(script.into_bytes())
+}
+
+/// Decode an M8 output script into a JSON request.
+#[wasm_bindgen(js_name = decodeBmmRequest)]
+pub fn decode_bmm_request(script: &[u8]) -> Result<String, JsError> {
+    let request = parse_m8_bmm_request_strict(script)?;
+    Ok(serde_json::to_string(&request)?)
+}
+
+/// Encode a JSON request into an M8 output script.
+#[wasm_bindgen(js_name = encodeBmmRequest)]
+pub fn encode_bmm_request(request: &str) -> Result<Vec<u8>, JsError> {
+    let request: M8BmmRequest = serde_json::from_str(request)?;
+    Ok(request.encode().into_bytes())
+}
@@ -0,0 +1,3151 @@
+use bip300301_messages::{
+    bitcoin::{
+        consensus::{deserialize, serialize},
+        ScriptBuf,
+    },
+    parse_coinbase_script, parse_m8_bmm_request, CoinbaseMessage, M4AckBundles, M8BmmRequest,
+    SidechainNumber, Vote,
+};
+
+fn round_trip(message: CoinbaseMessage) {
+    let script = message.to_script().unwrap();
+    let (rest, parsed) = parse_coinbase_script(&script).unwrap();
+    assert!(rest.is_empty(), "{message:?} left {} bytes", rest.len());
+    assert_eq!(parsed, message);
+    let encoded = serialize(&message);
+    assert_eq!(deserialize::<CoinbaseMessage>(&encoded).unwrap(), message);
+    assert_eq!(
+        message.to_string().parse::<CoinbaseMessage>().unwrap(),
+        message
+    );
+}
+
+/// One message of each kind and M4 encoding, for the tests of the optional
+/// encodings.
+fn sample_messages() -> Vec<CoinbaseMessage> {
+    vec![
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: SidechainNumber(1),
+            data: vec![0u8, 1, 2, 3].into(),
+        },
+        CoinbaseMessage::M2AckSidechain {
+            sidechain_number: SidechainNumber(2),
+            data_hash: [0xAB; 32],
+        },
+        CoinbaseMessage::M3ProposeBundle {
+            sidechain_number: SidechainNumber(3),
+            bundle_txid: [0xCD; 32],
+        },
+        CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious),
+        CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+            upvotes: vec![Vote::Upvote(0), Vote::Abstain, Vote::Alarm].into(),
+        }),
+        CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+            upvotes: vec![Vote::Upvote(1000), Vote::Abstain].into(),
+        }),
+        CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50),
+        CoinbaseMessage::M7BmmAccept {
+            sidechain_number: SidechainNumber(255),
+            sidechain_block_hash: [0xEF; 32],
+        },
+    ]
+}
+
+fn sample_request() -> M8BmmRequest {
+    M8BmmRequest {
+        sidechain_number: SidechainNumber(8),
+        sidechain_block_hash: [0x11; 32],
+        prev_mainchain_block_hash: [0x22; 32],
+    }
+}
+
+#[test]
+fn m1_propose_sidechain() {
+    round_trip(CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(1),
+        data: vec![0u8, 1, 2, 3].into(),
+    });
+    round_trip(CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(255),
+        data: Vec::<u8>::new().into(),
+    });
+}
+
+#[test]
+fn m2_ack_sidechain() {
+    round_trip(CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(2),
+        data_hash: [0xAB; 32],
+    });
+}
+
+#[test]
+fn m3_propose_bundle() {
+    round_trip(CoinbaseMessage::M3ProposeBundle {
+        sidechain_number: SidechainNumber(3),
+        bundle_txid: [0xCD; 32],
+    });
+}
+
+#[test]
+fn m4_ack_bundles() {
+    let votes = vec![Vote::Upvote(0), Vote::Abstain, Vote::Alarm, Vote::Upvote(7)];
+    round_trip(CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious));
+    round_trip(CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+        upvotes: votes.clone().into(),
+    }));
+    round_trip(CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+        upvotes: votes.into_iter().chain([Vote::Upvote(1000)]).collect(),
+    }));
+    round_trip(CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50));
+}
+
+#[test]
+fn m4_two_bytes_rejects_odd_payload() {
+    let script: ScriptBuf = CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+        upvotes: vec![Vote::Upvote(1), Vote::Abstain].into(),
+    })
+    .to_script()
+    .unwrap();
+    let mut bytes = script.into_bytes();
+    bytes.push(0x00);
+    let script = ScriptBuf::from_bytes(bytes);
+    assert!(parse_coinbase_script(&script).is_err());
+}
+
+#[test]
+fn m4_upvote_out_of_range_fails_to_encode() {
+    use bip300301_messages::{bitcoin::consensus::Encodable, CoinbaseBuilder, M4EncodeError};
+
+    let message = CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+        upvotes: vec![Vote::Abstain, Vote::Upvote(300)].into(),
+    });
+    let err = M4EncodeError {
+        index: 1,
+        vote: Vote::Upvote(300),
+        width: 1,
+    };
+    assert_eq!(message.to_script(), Err(err));
+    assert_eq!(ScriptBuf::try_from(&message), Err(err));
+    assert_eq!(message.to_string(), format!("<{err}>"));
+    assert!(message.consensus_encode(&mut Vec::new()).is_err());
+    let built = CoinbaseBuilder::new().push(message).build();
+    assert_eq!(built, Err(err));
+}
+
+#[test]
+fn display_and_from_str() {
+    use bip300301_messages::{Bip300Error, MessageFromStrError};
+
+    let message = CoinbaseMessage::M3ProposeBundle {
+        sidechain_number: SidechainNumber(3),
+        bundle_txid: [0xCD; 32],
+    };
+    let hex = message.to_string();
+    assert_eq!(hex, format!("6ad45aa94303{}", "cd".repeat(32)));
+    assert_eq!(hex.to_uppercase().parse::<CoinbaseMessage>(), Ok(message));
+
+    assert!(matches!(
+        "6ad45aa9430".parse::<CoinbaseMessage>(),
+        Err(MessageFromStrError::Hex(_))
+    ));
+    assert!(matches!(
+        format!("{hex}00").parse::<CoinbaseMessage>(),
+        Err(MessageFromStrError::Message(Bip300Error::TrailingBytes {
+            len: 1,
+            ..
+        }))
+    ));
+    assert!(matches!(
+        "6a00".parse::<CoinbaseMessage>(),
+        Err(MessageFromStrError::Message(_))
+    ));
+
+    // An M4 that doesn't encode has no script to write, so its `Display` is the
+    // error, which doesn't parse back.
+    let unencodable = CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+        upvotes: vec![Vote::Upvote(300)].into(),
+    });
+    assert!(unencodable.to_string().starts_with('<'));
+    assert!(matches!(
+        unencodable.to_string().parse::<CoinbaseMessage>(),
+        Err(MessageFromStrError::Hex(_))
+    ));
+}
+
+#[test]
+fn m7_bmm_accept() {
+    round_trip(CoinbaseMessage::M7BmmAccept {
+        sidechain_number: SidechainNumber(7),
+        sidechain_block_hash: [0xEF; 32],
+    });
+}
+
+#[test]
+fn m8_bmm_request() {
+    let request = M8BmmRequest {
+        sidechain_number: SidechainNumber(8),
+        sidechain_block_hash: [0x11; 32],
+        prev_mainchain_block_hash: [0x22; 32],
+    };
+    let script: ScriptBuf = request.clone().into();
+    let (rest, parsed) = parse_m8_bmm_request(script.as_bytes()).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(parsed, request);
+    let encoded = serialize(&request);
+    assert_eq!(deserialize::<M8BmmRequest>(&encoded).unwrap(), request);
+    assert_eq!(
+        request.to_string().parse::<M8BmmRequest>().unwrap(),
+        request
+    );
+}
+
+/// The tags as BIP300 and BIP301 list them. The BIPs give no preimage for them,
+/// so this is the only thing that catches an edit to one.
+#[test]
+fn message_tags_match_spec() {
+    use bip300301_messages::{
+        M1_PROPOSE_SIDECHAIN_TAG, M2_ACK_SIDECHAIN_TAG, M3_PROPOSE_BUNDLE_TAG, M4_ACK_BUNDLES_TAG,
+        M7_BMM_ACCEPT_TAG, M8_BMM_REQUEST_TAG,
+    };
+
+    assert_eq!(M1_PROPOSE_SIDECHAIN_TAG, [0xD5, 0xE0, 0xC4, 0xAF]);
+    assert_eq!(M2_ACK_SIDECHAIN_TAG, [0xD6, 0xE1, 0xC5, 0xDF]);
+    assert_eq!(M3_PROPOSE_BUNDLE_TAG, [0xD4, 0x5A, 0xA9, 0x43]);
+    assert_eq!(M4_ACK_BUNDLES_TAG, [0xD7, 0x7D, 0x17, 0x76]);
+    assert_eq!(M7_BMM_ACCEPT_TAG, [0xD1, 0x61, 0x73, 0x68]);
+    assert_eq!(M8_BMM_REQUEST_TAG, [0x00, 0xBF, 0x00]);
+}
+
+#[test]
+fn derive_tag_hashes_the_name() {
+    use bip300301_messages::{
+        derive_tag, sha256d, M1_PROPOSE_SIDECHAIN_TAG, M2_ACK_SIDECHAIN_TAG, M3_PROPOSE_BUNDLE_TAG,
+        M4_ACK_BUNDLES_TAG, M7_BMM_ACCEPT_TAG,
+    };
+
+    let tag = derive_tag("drivechain/m9-example");
+    assert_eq!(tag, [0x24, 0xA3, 0xF4, 0x71]);
+    assert_eq!(tag, sha256d(b"drivechain/m9-example")[..4]);
+    assert_ne!(
+        derive_tag("drivechain/m9-example"),
+        derive_tag("drivechain/m10-example")
+    );
+    for standard in [
+        M1_PROPOSE_SIDECHAIN_TAG,
+        M2_ACK_SIDECHAIN_TAG,
+        M3_PROPOSE_BUNDLE_TAG,
+        M4_ACK_BUNDLES_TAG,
+        M7_BMM_ACCEPT_TAG,
+    ] {
+        assert_ne!(tag.as_slice(), standard);
+    }
+}
+
+#[test]
+fn spec_versions() {
+    use bip300301_messages::{parse_coinbase_script_versioned, Bip300Error, SpecVersion};
+
+    let one_byte = CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+        upvotes: vec![Vote::Upvote(0), Vote::Abstain].into(),
+    });
+    let script = one_byte.to_script().unwrap();
+    for spec_version in [SpecVersion::V1, SpecVersion::V2] {
+        let (_, parsed) = parse_coinbase_script_versioned(&script, spec_version).unwrap();
+        assert_eq!(parsed, one_byte);
+    }
+
+    let leading_by_50 = CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50);
+    let script = leading_by_50.to_script().unwrap();
+    let (_, parsed) = parse_coinbase_script_versioned(&script, SpecVersion::V2).unwrap();
+    assert_eq!(parsed, leading_by_50);
+    assert_eq!(
+        parse_coinbase_script_versioned(&script, SpecVersion::V1),
+        Err(Bip300Error::UnknownM4Subtype {
+            offset: 5,
+            subtype: 0x03,
+        })
+    );
+}
+
+#[test]
+fn test_vectors() {
+    bip300301_messages::test_vectors::verify_test_vectors().unwrap();
+}
+
+#[test]
+fn ctip_rollback() {
+    use bip300301_messages::{
+        scenario::{sidechain_lifecycle, LifecycleParams},
+        CtipTracker,
+    };
+
+    let params = LifecycleParams::default();
+    let blocks = sidechain_lifecycle(&params);
+    let mut tracker = CtipTracker::new();
+    let mut ctips = Vec::new();
+    for block in &blocks {
+        ctips.push(tracker.ctip(params.sidechain_number).copied());
+        tracker.connect_block(block).unwrap();
+    }
+    for (block, ctip) in blocks.iter().zip(ctips).rev() {
+        tracker.disconnect_block(block).unwrap();
+        assert_eq!(tracker.ctip(params.sidechain_number).copied(), ctip);
+    }
+    assert!(tracker.disconnect_block(&blocks[0]).is_err());
+}
+
+#[test]
+fn lifecycle_under_regtest_params() {
+    use bip300301_messages::{
+        scenario::{sidechain_lifecycle, LifecycleParams},
+        Bip300State, Params, ParseConfig, Retention,
+    };
+
+    let cases = [
+        (Params::regtest(), LifecycleParams::default()),
+        (
+            Params::regtest()
+                .with_activation_threshold(2)
+                .with_bundle_threshold(3),
+            LifecycleParams {
+                proposal_acks: 2,
+                bundle_acks: 3,
+                ..LifecycleParams::default()
+            },
+        ),
+    ];
+    for (params, lifecycle) in cases {
+        let mut state =
+            Bip300State::with_params(params, ParseConfig::default(), Retention::default());
+        for (height, block) in sidechain_lifecycle(&lifecycle).iter().enumerate() {
+            state.connect_block(block, height as u32 + 1).unwrap();
+        }
+        let sidechain_number = lifecycle.sidechain_number;
+        assert!(state
+            .proposals()
+            .active_sidechains()
+            .contains(sidechain_number));
+        assert!(state.bundles().bundles(sidechain_number).is_empty());
+        assert_eq!(
+            state.ctips().ctip(sidechain_number).unwrap().value,
+            lifecycle.deposit - lifecycle.payout - lifecycle.fee
+        );
+    }
+}
+
+#[test]
+fn proposal_threshold_vectors() {
+    use bip300301_messages::{
+        proposal_acks_needed, proposal_status, proposal_thresholds, Params, ProposalStatus,
+    };
+
+    // Regtest: 6 acks in 10 blocks for an unused slot, 12 in 20 for a used one.
+    let params = Params::regtest();
+    assert_eq!(proposal_thresholds(false, &params), (6, 10));
+    assert_eq!(proposal_thresholds(true, &params), (12, 20));
+    for (acks, slot_used, needed) in [(0, false, 6), (5, false, 1), (7, false, 0), (0, true, 12)] {
+        assert_eq!(
+            proposal_acks_needed(acks, slot_used, &params),
+            needed,
+            "{acks} acks, slot used: {slot_used}"
+        );
+    }
+
+    // A proposal made at height 100.
+    let cases = [
+        (6, 100, false, ProposalStatus::Activated),
+        (5, 100, false, ProposalStatus::Pending),
+        // A height before the proposal counts as age 0.
+        (0, 99, false, ProposalStatus::Pending),
+        // Exactly enough blocks left to collect every ack, and one too few.
+        (0, 104, false, ProposalStatus::Pending),
+        (0, 105, false, ProposalStatus::Failed),
+        (5, 109, false, ProposalStatus::Pending),
+        (5, 110, false, ProposalStatus::Failed),
+        (6, 110, false, ProposalStatus::Activated),
+        // A used slot takes the higher threshold and the longer age.
+        (6, 100, true, ProposalStatus::Pending),
+        (0, 108, true, ProposalStatus::Pending),
+        (0, 109, true, ProposalStatus::Failed),
+        (12, 130, true, ProposalStatus::Activated),
+    ];
+    for (acks, height, slot_used, status) in cases {
+        assert_eq!(
+            proposal_status(acks, 100, height, slot_used, &params),
+            status,
+            "{acks} acks at height {height}, slot used: {slot_used}"
+        );
+    }
+
+    let mainnet = Params::mainnet();
+    assert_eq!(
+        proposal_status(1814, 0, 2015, false, &mainnet),
+        ProposalStatus::Pending
+    );
+    assert_eq!(
+        proposal_status(1814, 0, 2016, false, &mainnet),
+        ProposalStatus::Failed
+    );
+    assert_eq!(
+        proposal_status(1815, 0, 2016, false, &mainnet),
+        ProposalStatus::Activated
+    );
+}
+
+#[test]
+fn bundle_threshold_vectors() {
+    use bip300301_messages::{
+        bundle_acks_needed, bundle_blocks_remaining, bundle_can_still_pass, bundle_is_expired,
+        score_history_is_spendable, Params,
+    };
+
+    // Regtest: a score of 6 within 10 blocks.
+    let params = Params::regtest();
+    for (score, needed) in [(0, 6), (5, 1), (6, 0), (7, 0)] {
+        assert_eq!(bundle_acks_needed(score, &params), needed, "score {score}");
+    }
+
+    // A bundle proposed at height 100.
+    for (height, remaining) in [(99, 10), (100, 10), (105, 5), (110, 0), (200, 0)] {
+        assert_eq!(
+            bundle_blocks_remaining(100, height, &params),
+            remaining,
+            "height {height}"
+        );
+    }
+    let cases = [
+        // (score, height, expired, can still pass)
+        (0, 100, false, true),
+        (0, 104, false, true),
+        (0, 105, false, false),
+        (5, 109, false, true),
+        (5, 110, true, false),
+        (6, 110, false, true),
+        (6, 200, false, true),
+    ];
+    for (score, height, expired, can_pass) in cases {
+        assert_eq!(
+            bundle_is_expired(score, 100, height, &params),
+            expired,
+            "score {score} at height {height}"
+        );
+        assert_eq!(
+            bundle_can_still_pass(score, 100, height, &params),
+            can_pass,
+            "score {score} at height {height}"
+        );
+    }
+
+    let histories: [(&[u16], bool); 7] = [
+        (&[], false),
+        (&[1, 2, 3, 4, 5], false),
+        (&[1, 2, 3, 4, 5, 6], true),
+        // Dropping back under the threshold.
+        (&[1, 2, 3, 4, 5, 6, 5], false),
+        // Reaching it in the last block before the maximum age, and at it.
+        (&[1, 2, 3, 4, 5, 5, 5, 5, 5, 6], true),
+        (&[0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6], true),
+        // Under the threshold at the maximum age expires it for good.
+        (&[1, 2, 3, 4, 5, 5, 5, 5, 5, 5, 5, 6], false),
+    ];
+    for (scores, spendable) in histories {
+        assert_eq!(
+            score_history_is_spendable(scores, &params),
+            spendable,
+            "{scores:?}"
+        );
+    }
+}
+
+#[test]
+fn validate_m6_checks_bundle_and_values() {
+    use bip300301_messages::{
+        bitcoin::{Amount, OutPoint, TxOut},
+        build_m6_withdrawal, m6_to_id, validate_m6, Ctip, M6Error, TreasuryTxError,
+    };
+
+    let ctip = Ctip {
+        outpoint: OutPoint::null(),
+        value: Amount::from_sat(100_000),
+    };
+    let payout = TxOut {
+        value: Amount::from_sat(40_000),
+        script_pubkey: ScriptBuf::new(),
+    };
+    let fee = Amount::from_sat(1_000);
+    let m6 = build_m6_withdrawal(SidechainNumber(1), &ctip, vec![payout], fee).unwrap();
+    let bundle_hash = m6_to_id(&m6, ctip.value.to_sat()).unwrap();
+    let withdrawal = validate_m6(&m6, ctip.value, &bundle_hash).unwrap();
+    assert_eq!(withdrawal.fee, fee);
+    assert!(matches!(
+        validate_m6(&m6, ctip.value, &[0; 32]),
+        Err(TreasuryTxError::BundleMismatch { .. })
+    ));
+    assert!(matches!(
+        validate_m6(&m6, Amount::from_sat(60_000), &bundle_hash),
+        Err(TreasuryTxError::InsufficientTreasury { .. })
+    ));
+    let mut no_outputs = m6.clone();
+    no_outputs.output.clear();
+    assert_eq!(
+        m6_to_id(&no_outputs, ctip.value.to_sat()),
+        Err(M6Error::NoOutputs)
+    );
+    assert!(matches!(
+        m6_to_id(&m6, 60_000),
+        Err(M6Error::InsufficientTreasury { .. })
+    ));
+}
+
+#[test]
+fn m6_id_vectors() {
+    bip300301_messages::verify_m6_id_vectors().unwrap();
+}
+
+#[test]
+fn op_drivechain_script() {
+    use bip300301_messages::{
+        create_op_drivechain_script, parse_op_drivechain, try_parse_op_drivechain,
+    };
+
+    for sidechain_number in [0, 1, 255].map(SidechainNumber) {
+        let script = create_op_drivechain_script(sidechain_number);
+        let (rest, parsed) = parse_op_drivechain(script.as_bytes()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, sidechain_number);
+        assert_eq!(try_parse_op_drivechain(&script), Some(sidechain_number));
+        let mut bytes = script.into_bytes();
+        bytes.push(0x00);
+        assert!(parse_op_drivechain(&bytes).is_err());
+        assert_eq!(try_parse_op_drivechain(&ScriptBuf::from_bytes(bytes)), None);
+    }
+}
+
+#[test]
+fn find_bmm_request_in_transaction() {
+    use bip300301_messages::{
+        bitcoin::{Amount, OutPoint, TxOut},
+        build_bmm_request_tx, find_bmm_request, FindBmmRequestError,
+    };
+
+    let request = M8BmmRequest {
+        sidechain_number: SidechainNumber(3),
+        sidechain_block_hash: [0x33; 32],
+        prev_mainchain_block_hash: [0x44; 32],
+    };
+    let funding = [(OutPoint::null(), Amount::from_sat(10_000))];
+    let mut tx = build_bmm_request_tx(
+        &request,
+        &funding,
+        Amount::from_sat(1_000),
+        ScriptBuf::new(),
+    )
+    .unwrap();
+    assert_eq!(find_bmm_request(&tx), Ok(Some((0, request.clone()))));
+    tx.output.push(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: request.encode(),
+    });
+    assert_eq!(
+        find_bmm_request(&tx),
+        Err(FindBmmRequestError::MultipleRequests { vouts: vec![0, 2] })
+    );
+    tx.output.clear();
+    assert_eq!(find_bmm_request(&tx), Ok(None));
+}
+
+#[test]
+fn bmm_proof_round_trip() {
+    use bip300301_messages::{
+        bitcoin::{absolute::LockTime, hashes::Hash, transaction, Transaction},
+        scenario::ChainBuilder,
+        BmmProof, BmmProofError, CoinbaseBuilder, SidechainBlockHash,
+    };
+
+    let sidechain_block_hash = [0x77; 32];
+    for tx_count in [0, 1, 2, 4, 5] {
+        let txs = (0..tx_count)
+            .map(|i| Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::from_consensus(i),
+                input: vec![],
+                output: vec![],
+            })
+            .collect();
+        let mut chain = ChainBuilder::new();
+        let coinbase_outputs = CoinbaseBuilder::new()
+            .bmm_accept(SidechainNumber(2), sidechain_block_hash)
+            .build()
+            .unwrap();
+        let block = chain.mine(coinbase_outputs, txs);
+        let proof = BmmProof::from_block(block).unwrap();
+        assert_eq!(
+            proof.verify(SidechainNumber(2)),
+            Ok(SidechainBlockHash::from_byte_array(sidechain_block_hash))
+        );
+        assert_eq!(
+            proof.verify(SidechainNumber(3)),
+            Err(BmmProofError::NoAccept {
+                sidechain_number: SidechainNumber(3)
+            })
+        );
+        let mut tampered = proof.clone();
+        tampered.coinbase.lock_time = LockTime::from_consensus(1);
+        assert!(matches!(
+            tampered.verify(SidechainNumber(2)),
+            Err(BmmProofError::MerkleRootMismatch { .. })
+        ));
+    }
+}
+
+#[test]
+fn assemble_coinbase_outputs_drives_activation_and_bundle() {
+    use bip300301_messages::{
+        scenario::ChainBuilder,
+        template::{assemble_coinbase_messages, assemble_coinbase_outputs, MinerPolicy},
+        Bip300State, CoinbaseBuilder, Params, ParseConfig, Retention, SidechainProposalId,
+    };
+
+    let params = Params::regtest();
+    let sidechain_number = SidechainNumber(4);
+    let bundle_txid = [0x66; 32];
+    let mut state = Bip300State::with_params(params, ParseConfig::default(), Retention::default());
+    let mut chain = ChainBuilder::new();
+    let data = vec![0xAA, 0xBB];
+    let proposal_id = SidechainProposalId::from_data(&data);
+    let proposal = CoinbaseBuilder::new()
+        .propose_sidechain(sidechain_number, data)
+        .build()
+        .unwrap();
+    let height = chain.next_height();
+    state
+        .connect_block(chain.mine(proposal, vec![]), height)
+        .unwrap();
+
+    let mut policy = MinerPolicy::new();
+    policy.ack_proposals.insert((sidechain_number, proposal_id));
+    policy.propose_bundles.push((sidechain_number, bundle_txid));
+    policy.upvote_bundles.insert(sidechain_number, bundle_txid);
+    // Nothing but the M2 is valid until the sidechain activates.
+    assert_eq!(assemble_coinbase_messages(&state, &policy).len(), 1);
+
+    while !state.bundles().is_spendable(sidechain_number, &bundle_txid) {
+        assert!(chain.next_height() < 30, "bundle never became spendable");
+        let outputs = assemble_coinbase_outputs(&state, &policy);
+        let height = chain.next_height();
+        state
+            .connect_block(chain.mine(outputs, vec![]), height)
+            .unwrap();
+    }
+    assert!(state
+        .proposals()
+        .active_sidechains()
+        .contains(sidechain_number));
+    // The proposal is no longer pending and the bundle is, so only the M4 is
+    // left to emit.
+    let messages = assemble_coinbase_messages(&state, &policy);
+    assert!(matches!(
+        messages.as_slice(),
+        [CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)]
+    ));
+}
+
+#[test]
+fn block_template_coinbase_and_audit() {
+    use bip300301_messages::{
+        bitcoin::{
+            hashes::Hash, script::PushBytes, Amount, BlockHash, CompactTarget, OutPoint, TxOut,
+        },
+        build_bmm_request_tx,
+        gbt::{BlockTemplate, TemplateError, TemplateTransaction},
+        Bip300State, CoinbaseBuilder, ParseConfig, Violation,
+    };
+
+    let request = M8BmmRequest {
+        sidechain_number: SidechainNumber(1),
+        sidechain_block_hash: std::array::from_fn(|i| i as u8),
+        prev_mainchain_block_hash: std::array::from_fn(|i| 0x80 | i as u8),
+    };
+    let funding = [(OutPoint::null(), Amount::from_sat(10_000))];
+    let bmm_tx = build_bmm_request_tx(
+        &request,
+        &funding,
+        Amount::from_sat(1_000),
+        ScriptBuf::new(),
+    )
+    .unwrap();
+    let mut template = BlockTemplate {
+        version: 0x2000_0000,
+        previous_block_hash: BlockHash::all_zeros(),
+        transactions: vec![TemplateTransaction {
+            txid: bmm_tx.compute_txid(),
+            hash: bmm_tx.compute_wtxid(),
+            depends: vec![],
+            fee: Amount::from_sat(1_000),
+            sigops: 4,
+            weight: bmm_tx.weight().to_wu(),
+            data: bmm_tx,
+        }],
+        coinbase_value: Amount::from_sat(50_000),
+        sigop_limit: 80_000,
+        weight_limit: 4_000_000,
+        time: 1_700_000_000,
+        bits: CompactTarget::from_consensus(0x207fffff),
+        height: 1,
+        default_witness_commitment: None,
+    };
+
+    let data = template.drivechain_data();
+    assert_eq!(data.bmm_requests.len(), 1);
+    assert_eq!(data.bmm_requests[0].2, request);
+    assert!(data.treasury_outputs.is_empty());
+
+    let outputs = CoinbaseBuilder::new()
+        .propose_sidechain(SidechainNumber(1), vec![1, 2, 3])
+        .build()
+        .unwrap();
+    let extra_nonce = <&PushBytes>::try_from(&[0u8; 4][..]).unwrap();
+    let coinbase = template
+        .build_coinbase(ScriptBuf::new(), outputs.clone(), extra_nonce)
+        .unwrap();
+    assert_eq!(coinbase.output[0].value, template.coinbase_value);
+    assert_eq!(coinbase.output[1..], outputs[..]);
+    template.check_coinbase(&coinbase).unwrap();
+    let block = template.to_block(coinbase.clone());
+    assert!(block.check_merkle_root());
+    assert_eq!(template.block_weight(&coinbase), block.weight().to_wu());
+
+    let mut too_valuable = outputs.clone();
+    too_valuable.push(TxOut {
+        value: Amount::from_sat(60_000),
+        script_pubkey: ScriptBuf::new(),
+    });
+    assert!(matches!(
+        template.build_coinbase(ScriptBuf::new(), too_valuable, extra_nonce),
+        Err(TemplateError::CoinbaseValue { .. })
+    ));
+
+    template.weight_limit = template.block_weight(&coinbase) - 1;
+    assert!(matches!(
+        template.check_coinbase(&coinbase),
+        Err(TemplateError::Weight { .. })
+    ));
+    template.weight_limit = 4_000_000;
+
+    // Proposing a sidechain and requesting BMM are valid on an empty chain.
+    let mut state = Bip300State::new(ParseConfig::default());
+    template.audit(coinbase.clone(), &state).unwrap();
+
+    // A stale template is for the height of the tip and builds on its parent.
+    state.connect_block(&block, template.height).unwrap();
+    assert_eq!(
+        template.audit(coinbase, &state).unwrap_err().violations,
+        [
+            Violation::UnexpectedHeight {
+                expected: 2,
+                height: 1,
+            },
+            Violation::NotOnTip {
+                tip: block.block_hash(),
+                prev_blockhash: BlockHash::all_zeros(),
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "sv2")]
+#[test]
+fn sv2_coinbase_outputs() {
+    use bip300301_messages::{
+        bitcoin::{Amount, TxOut},
+        sv2::{Sv2CoinbaseOutputs, Sv2Error},
+        CoinbaseBuilder,
+    };
+
+    let template_output = TxOut {
+        value: Amount::from_sat(50_000),
+        script_pubkey: ScriptBuf::new(),
+    };
+    let drivechain_outputs = CoinbaseBuilder::new()
+        .propose_sidechain(SidechainNumber(1), vec![1, 2, 3])
+        .bmm_accept(SidechainNumber(2), [0x22; 32])
+        .build()
+        .unwrap();
+    let mut outputs = Sv2CoinbaseOutputs::new(std::slice::from_ref(&template_output)).unwrap();
+    let template_size = outputs.serialized.len();
+    let additions = Sv2CoinbaseOutputs::new(&drivechain_outputs).unwrap();
+    outputs.extend(&drivechain_outputs).unwrap();
+    assert_eq!(outputs.count, 3);
+    assert_eq!(
+        outputs.serialized.len(),
+        template_size + additions.serialized.len()
+    );
+    assert_eq!(
+        additions.additional_size(1),
+        additions.serialized.len() as u32
+    );
+    assert_eq!(
+        additions.additional_size(252),
+        additions.serialized.len() as u32 + 2
+    );
+    let mut expected = vec![template_output];
+    expected.extend(drivechain_outputs);
+    assert_eq!(outputs.outputs(), expected);
+    assert_eq!(
+        outputs.value_remaining(Amount::from_sat(60_000)),
+        Ok(Amount::from_sat(10_000))
+    );
+    assert!(outputs.value_remaining(Amount::from_sat(40_000)).is_err());
+    assert!(matches!(
+        Sv2CoinbaseOutputs::decode(4, &outputs.serialized),
+        Err(Sv2Error::MalformedOutput { index: 3, .. })
+    ));
+}
+
+#[test]
+fn m5_deposit_rejects_zero_amount() {
+    use bip300301_messages::{bitcoin::Amount, build_m5_deposit, TreasuryTxError};
+
+    assert_eq!(
+        build_m5_deposit(SidechainNumber(1), None, Amount::ZERO),
+        Err(TreasuryTxError::ZeroDeposit)
+    );
+    assert!(build_m5_deposit(SidechainNumber(1), None, Amount::ONE_SAT).is_ok());
+}
+
+#[cfg(feature = "rpc")]
+#[test]
+fn mempool_data_classifies_transactions() {
+    use bip300301_messages::{
+        bitcoin::{Amount, OutPoint},
+        build_bmm_request_tx, build_m5_deposit,
+        rpc::MempoolData,
+        CtipTracker,
+    };
+
+    let request = M8BmmRequest {
+        sidechain_number: SidechainNumber(1),
+        sidechain_block_hash: [0x11; 32],
+        prev_mainchain_block_hash: [0x22; 32],
+    };
+    let funding = [(OutPoint::null(), Amount::from_sat(10_000))];
+    let bmm_tx = build_bmm_request_tx(
+        &request,
+        &funding,
+        Amount::from_sat(1_000),
+        ScriptBuf::new(),
+    )
+    .unwrap();
+    let deposit_tx = build_m5_deposit(SidechainNumber(1), None, Amount::from_sat(5_000)).unwrap();
+
+    let data = MempoolData::new([&bmm_tx, &deposit_tx], &CtipTracker::new());
+    assert_eq!(data.bmm_requests, vec![(bmm_tx.compute_txid(), 0, request)]);
+    assert_eq!(data.deposits.len(), 1);
+    assert_eq!(data.deposits[0].0, deposit_tx.compute_txid());
+    assert_eq!(data.deposits[0].1.amount, Amount::from_sat(5_000));
+    assert!(data.withdrawals.is_empty());
+}
+
+#[test]
+fn spoofed_messages_outside_coinbase_do_not_stall_state() {
+    use bip300301_messages::{
+        bitcoin::{absolute::LockTime, transaction, Amount, Transaction, TxOut},
+        scenario::ChainBuilder,
+        Bip300State, MessageKind, ParseConfig, ParseMode, Severity, Violation,
+    };
+
+    let spoofed_m2: ScriptBuf = CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(1),
+        data_hash: std::array::from_fn(|i| i as u8),
+    }
+    .to_script()
+    .unwrap();
+    let request = M8BmmRequest {
+        sidechain_number: SidechainNumber(1),
+        sidechain_block_hash: std::array::from_fn(|i| i as u8),
+        prev_mainchain_block_hash: std::array::from_fn(|i| 0x80 | i as u8),
+    };
+    let tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: spoofed_m2,
+            },
+            TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: request.encode(),
+            },
+        ],
+    };
+    let mut chain = ChainBuilder::new();
+    let block = chain.mine(vec![], vec![tx]).clone();
+
+    for mode in [ParseMode::Lenient, ParseMode::Strict] {
+        let config = ParseConfig {
+            mode,
+            ..ParseConfig::default()
+        };
+        let mut state = Bip300State::new(config);
+        let violations = bip300301_messages::check_block(&block, 1, &state);
+        assert!(violations.iter().any(|violation| matches!(
+            violation,
+            Violation::MisplacedMessage {
+                kind: MessageKind::M2AckSidechain,
+                ..
+            }
+        )));
+        assert!(violations.iter().any(|violation| matches!(
+            violation,
+            Violation::NonZeroValue {
+                kind: MessageKind::M8BmmRequest,
+                ..
+            }
+        )));
+        assert!(violations
+            .iter()
+            .all(|violation| violation.severity(mode) == Severity::Warning));
+        state.connect_block(&block, 1).unwrap();
+    }
+}
+
+#[test]
+fn obfuscated_block_file() {
+    use bip300301_messages::blk::{
+        deobfuscate, deobfuscate_at, raw_block_ranges, raw_blocks, BlkError,
+    };
+
+    let magic = [0xFA, 0xBF, 0xB5, 0xDA];
+    let mut data = magic.to_vec();
+    data.extend_from_slice(&3u32.to_le_bytes());
+    data.extend_from_slice(&[1, 2, 3]);
+    let key = [0x5A, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+
+    let mut obfuscated = data.clone();
+    deobfuscate(&mut obfuscated, key);
+    assert!(matches!(
+        raw_blocks(&obfuscated, magic),
+        Err(BlkError::BadMagic { offset: 0 })
+    ));
+    // The frames can be found without de-obfuscating the blocks, and a part
+    // de-obfuscates on its own given where it starts.
+    let block = 8..11;
+    assert_eq!(
+        raw_block_ranges(&obfuscated, magic, Some(key)).unwrap(),
+        std::slice::from_ref(&block)
+    );
+    for start in 0..data.len() {
+        let mut part = obfuscated[start..].to_vec();
+        deobfuscate_at(&mut part, key, start);
+        assert_eq!(part, data[start..]);
+    }
+    deobfuscate(&mut obfuscated, key);
+    assert_eq!(obfuscated, data);
+    assert_eq!(raw_blocks(&obfuscated, magic).unwrap(), [&[1u8, 2, 3][..]]);
+    assert_eq!(raw_block_ranges(&data, magic, None).unwrap(), [block]);
+}
+
+/// A block file holding `blocks`, as Bitcoin Core writes it before padding.
+#[allow(dead_code)]
+fn blk_file(blocks: &[bip300301_messages::bitcoin::Block], magic: [u8; 4]) -> Vec<u8> {
+    let mut data = vec![];
+    for block in blocks {
+        let block = serialize(block);
+        data.extend_from_slice(&magic);
+        data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        data.extend_from_slice(&block);
+    }
+    // Core preallocates block files, so they end in zeroes.
+    data.extend_from_slice(&[0; 64]);
+    data
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_scan_matches_read_scan() {
+    use std::num::NonZeroUsize;
+
+    use bip300301_messages::{
+        blk::{deobfuscate, scan_blk_file, scan_blk_file_mmap, XOR_KEY_FILE},
+        ParseConfig,
+    };
+
+    let (blocks, _) = short_lifecycle(ParseConfig::default());
+    let magic = [0xFA, 0xBF, 0xB5, 0xDA];
+    let data = blk_file(&blocks, magic);
+    let dir = std::env::temp_dir().join(format!("bip300301-blk-{}", std::process::id()));
+    let path = dir.join("blk00000.dat");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Unobfuscated, with an all-zero key and with a real key.
+    for key in [None, Some([0; 8]), Some([0x5A, 1, 2, 3, 4, 5, 6, 7])] {
+        let mut file = data.clone();
+        match key {
+            Some(key) => {
+                deobfuscate(&mut file, key);
+                std::fs::write(dir.join(XOR_KEY_FILE), key).unwrap();
+            }
+            None => {
+                let _ = std::fs::remove_file(dir.join(XOR_KEY_FILE));
+            }
+        }
+        std::fs::write(&path, &file).unwrap();
+        let read = scan_blk_file(&path, magic).unwrap();
+        assert_eq!(read.len(), blocks.len());
+        for (scanned, block) in read.iter().zip(&blocks) {
+            assert_eq!(scanned.block_hash, block.block_hash());
+        }
+        assert!(read
+            .iter()
+            .any(|scanned| !scanned.coinbase_messages.is_empty()));
+        for threads in [1, 3, 64] {
+            let mapped =
+                scan_blk_file_mmap(&path, magic, NonZeroUsize::new(threads).unwrap()).unwrap();
+            assert_eq!(format!("{mapped:?}"), format!("{read:?}"), "{key:?}");
+        }
+        // The file itself is left as it was.
+        assert_eq!(std::fs::read(&path).unwrap(), file);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn strict_parsers_reject_trailing_bytes() {
+    use bip300301_messages::{
+        parse_coinbase_script_strict, parse_m8_bmm_request_strict, Bip300Error,
+    };
+
+    let message = CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(1),
+        data_hash: [0x11; 32],
+    };
+    let mut bytes = message.to_script().unwrap().into_bytes();
+    bytes.push(0xAA);
+    let script = ScriptBuf::from_bytes(bytes);
+    let (rest, parsed) = parse_coinbase_script(&script).unwrap();
+    assert_eq!((rest, parsed), (&[0xAA][..], message));
+    assert_eq!(
+        parse_coinbase_script_strict(&script),
+        Err(Bip300Error::TrailingBytes {
+            offset: script.len() - 1,
+            len: 1,
+        })
+    );
+
+    let request = M8BmmRequest::new(SidechainNumber(1), [0x11; 32], [0x22; 32]);
+    let mut bytes = request.encode().into_bytes();
+    bytes.extend_from_slice(&[0xAA, 0xBB]);
+    let (rest, parsed) = parse_m8_bmm_request(&bytes).unwrap();
+    assert_eq!((rest, parsed), (&[0xAA, 0xBB][..], request));
+    assert_eq!(
+        parse_m8_bmm_request_strict(&bytes),
+        Err(Bip300Error::TrailingBytes {
+            offset: bytes.len() - 2,
+            len: 2,
+        })
+    );
+}
+
+/// The lifecycle of [`bip300301_messages::scenario::sidechain_lifecycle`] with
+/// thresholds low enough to keep it short, and a state enforcing them.
+fn short_lifecycle(
+    config: bip300301_messages::ParseConfig,
+) -> (
+    Vec<bip300301_messages::bitcoin::Block>,
+    bip300301_messages::Bip300State,
+) {
+    use bip300301_messages::{
+        scenario::{sidechain_lifecycle, LifecycleParams},
+        Bip300State, Params, Retention,
+    };
+
+    let params = Params::regtest()
+        .with_activation_threshold(2)
+        .with_bundle_threshold(3);
+    let blocks = sidechain_lifecycle(&LifecycleParams {
+        proposal_acks: 2,
+        bundle_acks: 3,
+        ..LifecycleParams::default()
+    });
+    (
+        blocks,
+        Bip300State::with_params(params, config, Retention::default()),
+    )
+}
+
+#[test]
+fn connect_disconnect_round_trips() {
+    use bip300301_messages::ParseConfig;
+
+    let (blocks, mut state) = short_lifecycle(ParseConfig::default());
+    state.connect_block(&blocks[0], 1).unwrap();
+    let first = format!("{state:?}");
+    for (height, block) in (2..).zip(&blocks[1..]) {
+        let before = format!("{state:?}");
+        state.connect_block(block, height).unwrap();
+        state.disconnect_block(block).unwrap();
+        assert_eq!(format!("{state:?}"), before, "block {height}");
+        state.connect_block(block, height).unwrap();
+    }
+    // Disconnecting anything but the tip changes nothing.
+    let tip = format!("{state:?}");
+    assert!(state.disconnect_block(&blocks[1]).is_err());
+    assert_eq!(format!("{state:?}"), tip);
+
+    for block in blocks[1..].iter().rev() {
+        state.disconnect_block(block).unwrap();
+    }
+    assert_eq!(format!("{state:?}"), first);
+    state.disconnect_block(&blocks[0]).unwrap();
+    assert_eq!(state.tip(), None);
+    assert_eq!(state.retained_blocks(), 0);
+}
+
+/// A block on top of the tip of `state` whose coinbase has `coinbase_outputs`
+/// after a reward output, followed by `txs`.
+fn candidate_block(
+    state: &bip300301_messages::Bip300State,
+    coinbase_outputs: Vec<bip300301_messages::bitcoin::TxOut>,
+    txs: Vec<bip300301_messages::bitcoin::Transaction>,
+) -> bip300301_messages::bitcoin::Block {
+    use bip300301_messages::bitcoin::{
+        absolute::LockTime,
+        block::{Header, Version},
+        hashes::Hash,
+        script::Builder,
+        transaction, Amount, Block, CompactTarget, OutPoint, Sequence, Transaction, TxIn,
+        TxMerkleNode, TxOut, Witness,
+    };
+
+    let (height, tip) = state.tip().unwrap();
+    let mut output = vec![TxOut {
+        value: Amount::from_sat(50 * 100_000_000),
+        script_pubkey: ScriptBuf::new(),
+    }];
+    output.extend(coinbase_outputs);
+    let coinbase = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_int(i64::from(height + 1)).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output,
+    };
+    let mut block = Block {
+        header: Header {
+            version: Version::TWO,
+            prev_blockhash: tip,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: std::iter::once(coinbase).chain(txs).collect(),
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    block
+}
+
+#[test]
+fn check_block_reports_every_violation() {
+    use bip300301_messages::{
+        bitcoin::{hashes::Hash, Amount, BlockHash, TxOut},
+        build_m5_deposit, build_m6_withdrawal, check_block, m6_to_id, Bip300Error, BundleVoteError,
+        CtipViolation, MessageKind, ParseConfig, ParseMode, ProposalError, Severity,
+        SidechainProposalId, TreasuryTxError, Violation, M1_PROPOSE_SIDECHAIN_PREFIX,
+    };
+
+    let config = ParseConfig {
+        require_bmm_request: true,
+        check_hashes: true,
+        ..ParseConfig::default()
+    };
+    let (blocks, mut state) = short_lifecycle(config);
+    // The proposal, its acks and the first deposit: sidechain 0 is active and
+    // has a treasury.
+    for (height, block) in (1..).zip(&blocks[..4]) {
+        state.connect_block(block, height).unwrap();
+    }
+    let active = SidechainNumber(0);
+    let inactive = SidechainNumber(5);
+    let ctip = *state.ctips().ctip(active).unwrap();
+    let hash: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let m7 = |sidechain_number, sidechain_block_hash| {
+        CoinbaseMessage::M7BmmAccept {
+            sidechain_number,
+            sidechain_block_hash,
+        }
+        .to_script()
+        .unwrap()
+    };
+    let output = |script_pubkey| TxOut {
+        value: Amount::ZERO,
+        script_pubkey,
+    };
+    let check = |coinbase_outputs, txs| {
+        let block = candidate_block(&state, coinbase_outputs, txs);
+        let violations = check_block(&block, state.next_height(), &state);
+        (block, violations)
+    };
+
+    let (block, violations) = check(
+        vec![TxOut {
+            value: Amount::ONE_SAT,
+            script_pubkey: m7(active, hash),
+        }],
+        vec![],
+    );
+    assert!(violations.contains(&Violation::NonZeroValue {
+        txid: block.txdata[0].compute_txid(),
+        vout: 1,
+        kind: MessageKind::M7BmmAccept,
+        value: Amount::ONE_SAT,
+    }));
+
+    let (_, violations) = check(vec![output(m7(active, hash))], vec![]);
+    assert_eq!(
+        violations,
+        [Violation::UnrequestedBmmAccept {
+            sidechain_number: active,
+            sidechain_block_hash: hash,
+        }]
+    );
+
+    let (block, violations) = check(vec![output(m7(active, [0; 32]))], vec![]);
+    let suspicious = Violation::SuspiciousHash {
+        txid: block.txdata[0].compute_txid(),
+        vout: 1,
+        field: "sidechain_block_hash",
+        hash: [0; 32],
+    };
+    assert!(violations.contains(&suspicious));
+    assert_eq!(suspicious.severity(ParseMode::Lenient), Severity::Warning);
+    assert_eq!(suspicious.severity(ParseMode::Strict), Severity::Error);
+
+    let mut spoofed = build_m5_deposit(inactive, None, Amount::ONE_SAT).unwrap();
+    spoofed.output = vec![output(
+        CoinbaseMessage::M2AckSidechain {
+            sidechain_number: active,
+            data_hash: hash,
+        }
+        .to_script()
+        .unwrap(),
+    )];
+    let (_, violations) = check(vec![], vec![spoofed.clone()]);
+    assert_eq!(
+        violations,
+        [Violation::MisplacedMessage {
+            txid: spoofed.compute_txid(),
+            vout: 0,
+            kind: MessageKind::M2AckSidechain,
+        }]
+    );
+
+    let unchained = build_m5_deposit(active, None, ctip.value + Amount::ONE_SAT).unwrap();
+    let (_, violations) = check(vec![], vec![unchained.clone()]);
+    assert!(
+        violations.contains(&Violation::Treasury(
+            CtipViolation::UnchainedTreasuryOutput {
+                sidechain_number: active,
+                txid: unchained.compute_txid(),
+                vout: 0,
+                ctip: ctip.outpoint,
+            }
+        )),
+        "{violations:?}"
+    );
+
+    let (mut block, _) = check(vec![], vec![]);
+    assert_eq!(
+        check_block(&block, state.next_height() + 1, &state),
+        [Violation::UnexpectedHeight {
+            expected: state.next_height(),
+            height: state.next_height() + 1,
+        }]
+    );
+    block.header.prev_blockhash = BlockHash::all_zeros();
+    assert_eq!(
+        check_block(&block, state.next_height(), &state),
+        [Violation::NotOnTip {
+            tip: state.tip().unwrap().1,
+            prev_blockhash: BlockHash::all_zeros(),
+        }]
+    );
+
+    let (_, violations) = check(
+        vec![output(ScriptBuf::from_bytes(
+            M1_PROPOSE_SIDECHAIN_PREFIX.to_vec(),
+        ))],
+        vec![],
+    );
+    assert!(
+        violations.iter().any(|violation| matches!(
+            violation,
+            Violation::MalformedMessage {
+                vout: 1,
+                error: Bip300Error::Truncated { .. },
+            }
+        )),
+        "{violations:?}"
+    );
+
+    let (_, violations) = check(
+        vec![output(
+            CoinbaseMessage::M2AckSidechain {
+                sidechain_number: inactive,
+                data_hash: hash,
+            }
+            .to_script()
+            .unwrap(),
+        )],
+        vec![],
+    );
+    assert_eq!(
+        violations,
+        [Violation::Proposal(ProposalError::UnknownProposal {
+            sidechain_number: inactive,
+            proposal_id: SidechainProposalId::from_byte_array(hash),
+        })]
+    );
+
+    let (_, violations) = check(
+        vec![output(
+            CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: inactive,
+                bundle_txid: hash,
+            }
+            .to_script()
+            .unwrap(),
+        )],
+        vec![],
+    );
+    assert_eq!(
+        violations,
+        [Violation::BundleVote(BundleVoteError::InactiveSidechain {
+            sidechain_number: inactive,
+        })]
+    );
+
+    let mut not_a_deposit = build_m5_deposit(active, Some(&ctip), Amount::ONE_SAT).unwrap();
+    not_a_deposit.output[0].value = ctip.value;
+    let (_, violations) = check(vec![], vec![not_a_deposit.clone()]);
+    assert_eq!(
+        violations,
+        [Violation::TreasuryTx {
+            txid: not_a_deposit.compute_txid(),
+            sidechain_number: active,
+            error: TreasuryTxError::NotADeposit {
+                previous: ctip.value,
+                new: ctip.value,
+            },
+        }]
+    );
+
+    let payout = TxOut {
+        value: Amount::from_sat(1_000),
+        script_pubkey: ScriptBuf::new(),
+    };
+    let withdrawal =
+        build_m6_withdrawal(active, &ctip, vec![payout], Amount::from_sat(1_000)).unwrap();
+    let (_, violations) = check(vec![], vec![withdrawal.clone()]);
+    assert_eq!(
+        violations,
+        [Violation::UnackedWithdrawal {
+            txid: withdrawal.compute_txid(),
+            sidechain_number: active,
+            bundle_txid: m6_to_id(&withdrawal, ctip.value.to_sat()).unwrap(),
+        }]
+    );
+
+    let deposit = build_m5_deposit(inactive, None, Amount::ONE_SAT).unwrap();
+    let (_, violations) = check(vec![], vec![deposit.clone()]);
+    assert_eq!(
+        violations,
+        [Violation::InactiveSidechainDeposit {
+            txid: deposit.compute_txid(),
+            sidechain_number: inactive,
+        }]
+    );
+
+    let (_, violations) = check(vec![output(m7(inactive, hash))], vec![]);
+    assert!(
+        violations.contains(&Violation::InactiveSidechainBmmAccept {
+            sidechain_number: inactive,
+        }),
+        "{violations:?}"
+    );
+
+    let (_, violations) = check(
+        vec![output(m7(active, hash)), output(m7(active, hash))],
+        vec![],
+    );
+    assert!(
+        violations.contains(&Violation::DuplicateBmmAccept {
+            sidechain_number: active,
+        }),
+        "{violations:?}"
+    );
+}
+
+#[test]
+fn zero_value_rule() {
+    use bip300301_messages::{
+        bitcoin::{Amount, OutPoint, TxOut},
+        build_bmm_request_tx, check_block_rules, MessageKind, ParseConfig, ParseMode, Severity,
+        Violation,
+    };
+
+    let (blocks, mut state) = short_lifecycle(ParseConfig::default());
+    state.connect_block(&blocks[0], 1).unwrap();
+    let request = M8BmmRequest::new(
+        SidechainNumber(0),
+        std::array::from_fn(|i| i as u8),
+        std::array::from_fn(|i| 0x80 | i as u8),
+    );
+    let proposal = CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(1),
+        data: vec![1, 2, 3].into(),
+    }
+    .to_script()
+    .unwrap();
+    let funding = [(
+        OutPoint {
+            txid: blocks[0].txdata[0].compute_txid(),
+            vout: 0,
+        },
+        Amount::from_sat(10_000),
+    )];
+    let mut request_tx = build_bmm_request_tx(
+        &request,
+        &funding,
+        Amount::from_sat(1_000),
+        ScriptBuf::new(),
+    )
+    .unwrap();
+    request_tx.output[0].value = Amount::ONE_SAT;
+    let proposal = TxOut {
+        value: Amount::ONE_SAT,
+        script_pubkey: proposal,
+    };
+    let block = candidate_block(&state, vec![proposal], vec![request_tx.clone()]);
+
+    let violations = check_block_rules(&block, &ParseConfig::default());
+    let m1 = Violation::NonZeroValue {
+        txid: block.txdata[0].compute_txid(),
+        vout: 1,
+        kind: MessageKind::M1ProposeSidechain,
+        value: Amount::ONE_SAT,
+    };
+    let m8 = Violation::NonZeroValue {
+        txid: request_tx.compute_txid(),
+        vout: 0,
+        kind: MessageKind::M8BmmRequest,
+        value: Amount::ONE_SAT,
+    };
+    assert_eq!(violations, [m1.clone(), m8.clone()]);
+    for mode in [ParseMode::Lenient, ParseMode::Strict] {
+        assert_eq!(m1.severity(mode), Severity::Error);
+        assert_eq!(m8.severity(mode), Severity::Warning);
+    }
+
+    let config = ParseConfig {
+        require_zero_value: false,
+        ..ParseConfig::default()
+    };
+    assert!(check_block_rules(&block, &config).is_empty());
+}
+
+#[test]
+fn bounded_retention_keeps_memory_flat() {
+    use bip300301_messages::{
+        scenario::ChainBuilder, Bip300State, CoinbaseBuilder, DisconnectError, Params, ParseConfig,
+        Retention, SidechainProposalId,
+    };
+
+    let params = Params::regtest().with_activation_threshold(1);
+    let sidechain_number = SidechainNumber(1);
+    let data = vec![0x01];
+    let mut chain = ChainBuilder::new();
+    chain.mine(
+        CoinbaseBuilder::new()
+            .propose_sidechain(sidechain_number, data.clone())
+            .build()
+            .unwrap(),
+        vec![],
+    );
+    chain.mine(
+        CoinbaseBuilder::new()
+            .ack_sidechain(sidechain_number, SidechainProposalId::from_data(&data))
+            .build()
+            .unwrap(),
+        vec![],
+    );
+    // Every later block proposes a sidechain nobody acks and a bundle nobody
+    // upvotes, so both keep failing and expiring.
+    for _ in 0..200 {
+        let mut filler = [0; 32];
+        filler[..4].copy_from_slice(&chain.next_height().to_le_bytes());
+        let outputs = CoinbaseBuilder::new()
+            .propose_sidechain(SidechainNumber(2), filler.to_vec())
+            .propose_bundle(sidechain_number, filler)
+            .build()
+            .unwrap();
+        chain.mine(outputs, vec![]);
+    }
+    let blocks = chain.into_blocks();
+
+    let window = Retention::Window { blocks: 5 };
+    let mut state = Bip300State::with_params(params, ParseConfig::default(), window);
+    let mut full = Bip300State::with_params(params, ParseConfig::default(), Retention::Full);
+    for (height, block) in (1..).zip(&blocks) {
+        state.connect_block(block, height).unwrap();
+        full.connect_block(block, height).unwrap();
+        assert!(state.retained_blocks() <= 5);
+        assert!(state.ctips().history().count() <= 5);
+        assert!(state.proposals().history().count() <= 5);
+        assert!(state.bundles().history().count() <= 5);
+        // Settled proposals and bundles are pruned from the current state.
+        assert!(state.proposals().proposals().count() <= 11);
+        assert!(state.bundles().bundles(sidechain_number).len() <= 11);
+    }
+    assert_eq!(full.retained_blocks(), blocks.len());
+    assert_eq!(full.proposals().history().count(), blocks.len());
+    assert_eq!(
+        full.proposals().proposals().count(),
+        state.proposals().proposals().count()
+    );
+
+    for block in blocks.iter().rev().take(5) {
+        state.disconnect_block(block).unwrap();
+    }
+    let block = &blocks[blocks.len() - 6];
+    assert_eq!(
+        state.disconnect_block(block),
+        Err(DisconnectError::NoHistory {
+            block_hash: block.block_hash()
+        })
+    );
+}
+
+#[test]
+fn retention_defaults_to_a_window() {
+    use bip300301_messages::{Bip300State, CtipTracker, ParseConfig, Retention};
+
+    let window = Retention::Window {
+        blocks: Retention::DEFAULT_WINDOW,
+    };
+    assert_eq!(Retention::default(), window);
+    assert_eq!(CtipTracker::new().retention(), window);
+    assert_eq!(Bip300State::new(ParseConfig::default()).retention(), window);
+}
+
+#[test]
+fn hash_interner() {
+    use bip300301_messages::scan::{HashInterner, InternedMessage};
+
+    assert!(std::mem::size_of::<InternedMessage>() < std::mem::size_of::<CoinbaseMessage>());
+    assert!(std::mem::size_of::<InternedMessage>() <= 16);
+
+    let mut interner = HashInterner::new();
+    assert!(interner.is_empty());
+    let first = interner.intern([0x11; 32]);
+    // Same first 8 bytes, different hash.
+    let mut colliding = [0x11; 32];
+    colliding[31] = 0x22;
+    let second = interner.intern(colliding);
+    assert_ne!(first, second);
+    assert_eq!(interner.intern([0x11; 32]), first);
+    assert_eq!(interner.intern(colliding), second);
+    assert_eq!(interner.len(), 2);
+    assert_eq!(interner.get(&colliding), Some(second));
+    assert_eq!(interner.get(&[0x33; 32]), None);
+    assert_eq!(interner.resolve(first), &[0x11; 32]);
+    assert_eq!(interner.resolve(second), &colliding);
+
+    let messages = [
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: SidechainNumber(1),
+            data: vec![1, 2, 3].into(),
+        },
+        CoinbaseMessage::M2AckSidechain {
+            sidechain_number: SidechainNumber(1),
+            data_hash: [0x11; 32],
+        },
+        CoinbaseMessage::M3ProposeBundle {
+            sidechain_number: SidechainNumber(2),
+            bundle_txid: [0x44; 32],
+        },
+        CoinbaseMessage::M4AckBundles(M4AckBundles::TwoBytes {
+            upvotes: vec![Vote::Upvote(300), Vote::Abstain].into(),
+        }),
+        CoinbaseMessage::M7BmmAccept {
+            sidechain_number: SidechainNumber(2),
+            sidechain_block_hash: [0x11; 32],
+        },
+    ];
+    for message in messages {
+        let interned = interner.intern_message(message.clone());
+        assert_eq!(interner.resolve_message(&interned), message);
+    }
+    // The M2 and the M7 share a hash with the first one interned.
+    assert_eq!(interner.len(), 3);
+}
+
+#[test]
+fn ack_sidechain_from_proposal_hashes_the_payload() {
+    use bip300301_messages::{CoinbaseBuilder, M1Data, SidechainProposalId};
+
+    let data = M1Data::from(vec![0xAA, 0xBB]);
+    let outputs = CoinbaseBuilder::new()
+        .ack_sidechain_from_proposal(SidechainNumber(3), &data)
+        .build()
+        .unwrap();
+    let expected = CoinbaseBuilder::new()
+        .ack_sidechain(SidechainNumber(3), SidechainProposalId::from_data(&data))
+        .build()
+        .unwrap();
+    assert_eq!(outputs, expected);
+}
+
+#[test]
+fn declaration_rejects_oversized_fields() {
+    use bip300301_messages::{CoinbaseBuilder, DeclarationError, SidechainDeclaration};
+
+    let mut declaration = SidechainDeclaration {
+        sidechain_number: SidechainNumber(1),
+        title: "a".repeat(usize::from(u16::MAX)),
+        description: String::new(),
+        hash_id_1: [0; 32],
+        hash_id_2: [0; 20],
+    };
+    let data = declaration.to_bytes().unwrap();
+    assert_eq!(
+        SidechainDeclaration::from_bytes(SidechainNumber(1), &data),
+        Ok(declaration.clone())
+    );
+
+    declaration.description = "b".repeat(usize::from(u16::MAX) + 1);
+    let error = DeclarationError::FieldTooLong {
+        field: "description",
+        len: usize::from(u16::MAX) + 1,
+    };
+    assert_eq!(declaration.to_bytes(), Err(error.clone()));
+    assert_eq!(declaration.proposal_id(), Err(error.clone()));
+    assert_eq!(CoinbaseMessage::try_from(&declaration), Err(error.clone()));
+    assert!(matches!(
+        CoinbaseBuilder::new().propose_and_track(&declaration),
+        Err(e) if e == error
+    ));
+}
+
+#[test]
+fn verify_acks_is_the_same_on_any_number_of_threads() {
+    use bip300301_messages::{sha256d, verify_acks, verify_acks_with_threads};
+
+    // Every tenth entry is an M4, which is skipped on both sides; data repeats
+    // every 500 proposals, so an ack matches the first of the two.
+    let proposals: Vec<CoinbaseMessage> = (0..1_000u32)
+        .map(|i| match i % 10 {
+            9 => CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious),
+            _ => CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number: SidechainNumber((i % 4) as u8),
+                data: (i % 500).to_le_bytes().to_vec().into(),
+            },
+        })
+        .collect();
+    let mut acks: Vec<CoinbaseMessage> = (0..1_200u32)
+        .map(|i| {
+            let proposal = i % 1_000;
+            // Past the proposals, ack each on the wrong sidechain.
+            let sidechain_number = SidechainNumber(((proposal % 4) + i / 1_000) as u8);
+            match i % 7 {
+                6 => CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50),
+                _ => CoinbaseMessage::M2AckSidechain {
+                    sidechain_number,
+                    data_hash: sha256d(&(proposal % 500).to_le_bytes()),
+                },
+            }
+        })
+        .collect();
+    acks.push(CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(0),
+        data_hash: [0xEE; 32],
+    });
+
+    let expected: Vec<Option<usize>> = acks
+        .iter()
+        .map(|ack| match ack {
+            CoinbaseMessage::M2AckSidechain {
+                sidechain_number,
+                data_hash,
+            } => proposals.iter().position(|proposal| {
+                matches!(proposal, CoinbaseMessage::M1ProposeSidechain {
+                    sidechain_number: number,
+                    data,
+                } if number == sidechain_number && sha256d(data) == *data_hash)
+            }),
+            _ => None,
+        })
+        .collect();
+    assert!(expected.iter().filter(|index| index.is_some()).count() > 500);
+    assert!(expected.iter().filter(|index| index.is_none()).count() > 100);
+    assert_eq!(expected[600], Some(100));
+
+    for threads in [1, 2, 3, 8, 64] {
+        assert_eq!(
+            verify_acks_with_threads(&acks, &proposals, threads),
+            expected,
+            "{threads} threads"
+        );
+    }
+    assert_eq!(verify_acks(&acks, &proposals), expected);
+}
+
+/// Serves the blocks of [`short_lifecycle`], slower the lower the height so that
+/// workers finish out of order, and fails at `fail_at`.
+struct SlowSource {
+    blocks: Vec<bip300301_messages::bitcoin::Block>,
+    fail_at: Option<u32>,
+    fetched: std::sync::atomic::AtomicU32,
+}
+
+impl bip300301_messages::sync::BlockSource for SlowSource {
+    type Error = u32;
+
+    fn block(&self, height: u32) -> Result<bip300301_messages::bitcoin::Block, u32> {
+        self.fetched
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let delay = self.blocks.len().saturating_sub(height as usize) as u64;
+        std::thread::sleep(std::time::Duration::from_millis(delay));
+        if self.fail_at == Some(height) {
+            return Err(height);
+        }
+        Ok(self.blocks[height as usize - 1].clone())
+    }
+}
+
+#[test]
+fn sync_range_connects_in_order() {
+    use std::num::NonZeroUsize;
+
+    use bip300301_messages::{
+        sync::{sync_range, sync_range_with, SyncError, SyncOptions},
+        ParseConfig,
+    };
+
+    let (blocks, empty) = short_lifecycle(ParseConfig::default());
+    let end = blocks.len() as u32 + 1;
+    let mut serial = empty.clone();
+    let mut serial_states = vec![format!("{serial:?}")];
+    for (height, block) in (1..).zip(&blocks) {
+        serial.connect_block(block, height).unwrap();
+        serial_states.push(format!("{serial:?}"));
+    }
+    let source = |fail_at| SlowSource {
+        blocks: blocks.clone(),
+        fail_at,
+        fetched: 0.into(),
+    };
+    let options = |workers, window| SyncOptions {
+        workers: NonZeroUsize::new(workers).unwrap(),
+        window: NonZeroUsize::new(window).unwrap(),
+    };
+
+    // Workers finish out of order but blocks are connected in height order.
+    for (workers, window) in [(1, 1), (4, 2), (4, 16)] {
+        let mut state = empty.clone();
+        sync_range_with(&source(None), 1..end, &mut state, options(workers, window)).unwrap();
+        assert_eq!(format!("{state:?}"), serial_states[blocks.len()]);
+    }
+    let mut state = empty.clone();
+    sync_range(&source(None), 1..end, &mut state).unwrap();
+    assert_eq!(format!("{state:?}"), serial_states[blocks.len()]);
+
+    // A range shorter than the window, and an empty one.
+    let mut state = empty.clone();
+    sync_range_with(&source(None), 1..3, &mut state, options(8, 32)).unwrap();
+    assert_eq!(format!("{state:?}"), serial_states[2]);
+    sync_range_with(&source(None), 3..3, &mut state, options(8, 32)).unwrap();
+    assert_eq!(format!("{state:?}"), serial_states[2]);
+
+    // A failed fetch stops the sync with every earlier block connected, and the
+    // workers don't run more than the window past it.
+    let fail_at = 4;
+    let failing = source(Some(fail_at));
+    let mut state = empty.clone();
+    let err = sync_range_with(&failing, 1..end, &mut state, options(2, 2)).unwrap_err();
+    assert!(matches!(
+        err,
+        SyncError::Source {
+            height: 4,
+            source: 4
+        }
+    ));
+    assert_eq!(format!("{state:?}"), serial_states[fail_at as usize - 1]);
+    let fetched = failing.fetched.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(fetched <= fail_at + 2, "fetched {fetched} blocks");
+
+    // So does a block that can't be connected: here the lifecycle's second
+    // block, served at the first height.
+    let mut state = empty.clone();
+    let swapped = SlowSource {
+        blocks: blocks[1..].to_vec(),
+        fail_at: None,
+        fetched: 0.into(),
+    };
+    let err = sync_range_with(&swapped, 1..3, &mut state, options(2, 4)).unwrap_err();
+    assert!(
+        matches!(err, SyncError::Invalid { height: 1, .. }),
+        "{err:?}"
+    );
+    assert_eq!(format!("{state:?}"), serial_states[0]);
+}
+
+/// The messages `builder` encodes, in output order.
+fn built_messages(builder: bip300301_messages::CoinbaseBuilder) -> Vec<CoinbaseMessage> {
+    builder
+        .build()
+        .unwrap()
+        .iter()
+        .map(|output| CoinbaseMessage::try_from(output).unwrap())
+        .collect()
+}
+
+#[test]
+fn coinbase_builder_edits() {
+    use bip300301_messages::{CoinbaseBuilder, MessageKind};
+
+    let builder = || {
+        CoinbaseBuilder::default()
+            .propose_sidechain(SidechainNumber(1), vec![0x01])
+            .ack_bundles(M4AckBundles::RepeatPrevious)
+            .propose_bundle(SidechainNumber(2), [0x02; 32])
+            .ack_bundles(M4AckBundles::LeadingBy50)
+            .bmm_accept(SidechainNumber(1), [0x03; 32])
+    };
+    let propose = CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(1),
+        data: vec![0x01].into(),
+    };
+    let bundle = CoinbaseMessage::M3ProposeBundle {
+        sidechain_number: SidechainNumber(2),
+        bundle_txid: [0x02; 32],
+    };
+    let accept = CoinbaseMessage::M7BmmAccept {
+        sidechain_number: SidechainNumber(1),
+        sidechain_block_hash: [0x03; 32],
+    };
+    let m4 = |m4_ack_bundles| CoinbaseMessage::M4AckBundles(m4_ack_bundles);
+    let upvotes = M4AckBundles::OneByte {
+        upvotes: vec![Vote::Upvote(0)].into(),
+    };
+
+    // The first M4 keeps its position and the second is dropped.
+    assert_eq!(
+        built_messages(builder().replace_m4(upvotes.clone())),
+        [
+            propose.clone(),
+            m4(upvotes.clone()),
+            bundle.clone(),
+            accept.clone()
+        ]
+    );
+    // Without an M4, the replacement is appended.
+    assert_eq!(
+        built_messages(
+            builder()
+                .remove_kind(MessageKind::M4AckBundles)
+                .replace_m4(upvotes.clone())
+        ),
+        [propose.clone(), bundle.clone(), accept.clone(), m4(upvotes)]
+    );
+
+    // M4s don't name a sidechain, so they survive removing one.
+    assert_eq!(
+        built_messages(builder().remove_for_sidechain(SidechainNumber(1))),
+        [
+            m4(M4AckBundles::RepeatPrevious),
+            bundle.clone(),
+            m4(M4AckBundles::LeadingBy50)
+        ]
+    );
+    assert_eq!(
+        built_messages(builder().remove_for_sidechain(SidechainNumber(9))),
+        built_messages(builder())
+    );
+
+    assert_eq!(
+        built_messages(builder().remove_kind(MessageKind::M4AckBundles)),
+        [propose.clone(), bundle.clone(), accept.clone()]
+    );
+    assert_eq!(
+        built_messages(builder().remove_kind(MessageKind::M8BmmRequest)),
+        built_messages(builder())
+    );
+    assert_eq!(
+        built_messages(
+            builder()
+                .remove_kind(MessageKind::M1ProposeSidechain)
+                .remove_kind(MessageKind::M7BmmAccept)
+        ),
+        [
+            m4(M4AckBundles::RepeatPrevious),
+            bundle,
+            m4(M4AckBundles::LeadingBy50)
+        ]
+    );
+}
+
+#[test]
+fn m4_from_votes_vectors() {
+    use std::collections::BTreeMap;
+
+    use bip300301_messages::{InactiveSidechainVote, M4EncodeError, SidechainSet};
+
+    let active: SidechainSet = [0, 2, 5].map(SidechainNumber).into_iter().collect();
+    let from_votes = |votes: &[(u8, Vote)]| {
+        let votes: BTreeMap<_, _> = votes
+            .iter()
+            .map(|(sidechain_number, vote)| (SidechainNumber(*sidechain_number), *vote))
+            .collect();
+        M4AckBundles::from_votes(&votes, &active)
+    };
+
+    // Votes land at their sidechain's position, the rest abstain.
+    assert_eq!(
+        from_votes(&[(5, Vote::Upvote(3))]),
+        Ok(M4AckBundles::OneByte {
+            upvotes: vec![Vote::Abstain, Vote::Abstain, Vote::Upvote(3)].into()
+        })
+    );
+    assert_eq!(
+        from_votes(&[]),
+        Ok(M4AckBundles::OneByte {
+            upvotes: vec![Vote::Abstain; 3].into()
+        })
+    );
+    // The largest index one byte holds, and the smallest it doesn't.
+    assert_eq!(
+        from_votes(&[(0, Vote::Upvote(253)), (2, Vote::Alarm)]),
+        Ok(M4AckBundles::OneByte {
+            upvotes: vec![Vote::Upvote(253), Vote::Alarm, Vote::Abstain].into()
+        })
+    );
+    assert_eq!(
+        from_votes(&[(0, Vote::Upvote(254))]),
+        Ok(M4AckBundles::TwoBytes {
+            upvotes: vec![Vote::Upvote(254), Vote::Abstain, Vote::Abstain].into()
+        })
+    );
+    assert_eq!(
+        from_votes(&[(1, Vote::Upvote(0))]),
+        Err(InactiveSidechainVote {
+            sidechain_number: SidechainNumber(1)
+        })
+    );
+
+    // An index that collides with the two-byte alarm value is kept, and only
+    // fails once the M4 is encoded.
+    let unencodable = from_votes(&[(2, Vote::Upvote(0xFFFE))]).unwrap();
+    assert_eq!(
+        CoinbaseMessage::M4AckBundles(unencodable).to_script(),
+        Err(M4EncodeError {
+            index: 1,
+            vote: Vote::Upvote(0xFFFE),
+            width: 2,
+        })
+    );
+}
+
+#[test]
+fn m4_cheapest_vectors() {
+    use bip300301_messages::Votes;
+
+    let votes: Votes = vec![Vote::Upvote(1), Vote::Abstain].into();
+    let other: Votes = vec![Vote::Abstain, Vote::Abstain].into();
+    let cheapest = M4AckBundles::cheapest;
+
+    assert_eq!(
+        cheapest(&votes, None, None),
+        M4AckBundles::OneByte {
+            upvotes: votes.clone()
+        }
+    );
+    assert_eq!(
+        cheapest(&votes, Some(&votes), None),
+        M4AckBundles::RepeatPrevious
+    );
+    assert_eq!(
+        cheapest(&votes, Some(&other), Some(&votes)),
+        M4AckBundles::LeadingBy50
+    );
+    // Both subtype-only forms fit, and repeating is preferred.
+    assert_eq!(
+        cheapest(&votes, Some(&votes), Some(&votes)),
+        M4AckBundles::RepeatPrevious
+    );
+    assert_eq!(
+        cheapest(&votes, Some(&other), Some(&other)),
+        M4AckBundles::OneByte {
+            upvotes: votes.clone()
+        }
+    );
+
+    let wide: Votes = vec![Vote::Upvote(254), Vote::Alarm].into();
+    assert_eq!(
+        cheapest(&wide, None, None),
+        M4AckBundles::TwoBytes {
+            upvotes: wide.clone()
+        }
+    );
+    assert_eq!(
+        cheapest(&wide, Some(&wide), None),
+        M4AckBundles::RepeatPrevious
+    );
+
+    // Each pick is no longer than any other encoding of the same votes.
+    for (votes, previous, leading) in [
+        (&votes, None, None),
+        (&votes, Some(&votes), None),
+        (&wide, None, Some(&wide)),
+        (&wide, None, None),
+    ] {
+        let len = CoinbaseMessage::M4AckBundles(cheapest(votes, previous, leading))
+            .to_script()
+            .unwrap()
+            .len();
+        for alternative in [
+            M4AckBundles::OneByte {
+                upvotes: votes.clone(),
+            },
+            M4AckBundles::TwoBytes {
+                upvotes: votes.clone(),
+            },
+        ] {
+            if let Ok(script) = CoinbaseMessage::M4AckBundles(alternative).to_script() {
+                assert!(len <= script.len());
+            }
+        }
+    }
+}
+
+#[test]
+fn m4_resolution_vectors() {
+    use bip300301_messages::{resolve_leading_by_50, resolve_repeat_previous, Votes};
+
+    let previous: Votes = vec![Vote::Upvote(2), Vote::Alarm].into();
+    let explicit = M4AckBundles::OneByte {
+        upvotes: vec![Vote::Abstain, Vote::Upvote(0)].into(),
+    };
+    assert_eq!(
+        resolve_repeat_previous(&M4AckBundles::RepeatPrevious, Some(&previous)),
+        Some(previous.clone())
+    );
+    assert_eq!(
+        resolve_repeat_previous(&M4AckBundles::RepeatPrevious, None),
+        None
+    );
+    assert_eq!(
+        resolve_repeat_previous(&explicit, Some(&previous)),
+        explicit.votes().cloned()
+    );
+    assert_eq!(
+        resolve_repeat_previous(&M4AckBundles::LeadingBy50, Some(&previous)),
+        None
+    );
+    // A chain of repeats resolves one step at a time to the last explicit M4.
+    let mut resolved = resolve_repeat_previous(&explicit, None);
+    for _ in 0..3 {
+        resolved = resolve_repeat_previous(&M4AckBundles::RepeatPrevious, resolved.as_ref());
+    }
+    assert_eq!(resolved.as_ref(), explicit.votes());
+
+    let standings: [&[u16]; 7] = [
+        // No pending bundles.
+        &[],
+        // A lone bundle needs a score of 50.
+        &[49],
+        &[50],
+        // The leader has to be 50 ahead of the runner-up.
+        &[10, 59],
+        &[10, 60, 0],
+        // Tied leaders.
+        &[80, 80],
+        // Scores near the top of the range.
+        &[u16::MAX, u16::MAX - 50],
+    ];
+    assert_eq!(
+        resolve_leading_by_50(&standings),
+        Votes::from(vec![
+            Vote::Abstain,
+            Vote::Abstain,
+            Vote::Upvote(0),
+            Vote::Abstain,
+            Vote::Upvote(1),
+            Vote::Abstain,
+            Vote::Upvote(0),
+        ])
+    );
+}
+
+#[test]
+fn message_proofs() {
+    use bip300301_messages::{
+        bitcoin::{
+            absolute::LockTime, transaction, Amount, OutPoint, Sequence, Transaction, TxIn, TxOut,
+            Witness,
+        },
+        commitment::{coinbase_message_root, message_leaf, MessageProof},
+        CoinbaseBuilder,
+    };
+
+    let coinbase = |messages: Vec<TxOut>| {
+        let mut output = vec![TxOut {
+            value: Amount::from_sat(50 * 100_000_000),
+            script_pubkey: ScriptBuf::new(),
+        }];
+        output.extend(messages);
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output,
+        }
+    };
+    // Five messages, so the last node of two levels is paired with itself.
+    let tx = coinbase(
+        CoinbaseBuilder::new()
+            .propose_sidechain(SidechainNumber(1), vec![0x01])
+            .ack_sidechain(SidechainNumber(2), [0x02; 32])
+            .propose_bundle(SidechainNumber(3), [0x03; 32])
+            .ack_bundles(M4AckBundles::RepeatPrevious)
+            .bmm_accept(SidechainNumber(4), [0x04; 32])
+            .build()
+            .unwrap(),
+    );
+    let root = coinbase_message_root(&tx).unwrap();
+    let script = |vout: u32| tx.output[vout as usize].script_pubkey.as_script();
+
+    assert_eq!(MessageProof::new(&tx, 0), None);
+    assert_eq!(MessageProof::new(&tx, 6), None);
+    for vout in 1..=5 {
+        let proof = MessageProof::new(&tx, vout).unwrap();
+        assert_eq!(proof.index, vout as usize - 1);
+        assert_eq!(proof.siblings.len(), 3);
+        assert!(proof.verify(script(vout), &root), "vout {vout}");
+
+        // Another message's script, or this one at another output.
+        let other = if vout == 1 { 2 } else { 1 };
+        assert!(!proof.verify(script(other), &root));
+        let moved = MessageProof {
+            vout: other,
+            ..proof.clone()
+        };
+        assert!(!moved.verify(script(vout), &root));
+        for sibling in 0..proof.siblings.len() {
+            let mut tampered = proof.clone();
+            tampered.siblings[sibling][0] ^= 1;
+            assert!(!tampered.verify(script(vout), &root), "sibling {sibling}");
+        }
+        // The last leaf is paired with itself, so only its vout, which the leaf
+        // commits to, says where it is.
+        if proof.siblings[0] != message_leaf(vout, script(vout)) {
+            let mut shifted = proof.clone();
+            shifted.index ^= 1;
+            assert!(!shifted.verify(script(vout), &root));
+        }
+        let mut extended = proof.clone();
+        extended.siblings.push(root);
+        assert!(!extended.verify(script(vout), &root));
+        let mut flipped_root = root;
+        flipped_root[31] ^= 1;
+        assert!(!proof.verify(script(vout), &flipped_root));
+    }
+
+    // A lone message is its own root.
+    let single = coinbase(
+        CoinbaseBuilder::new()
+            .ack_bundles(M4AckBundles::LeadingBy50)
+            .build()
+            .unwrap(),
+    );
+    let root = coinbase_message_root(&single).unwrap();
+    assert_eq!(root, message_leaf(1, &single.output[1].script_pubkey));
+    let proof = MessageProof::new(&single, 1).unwrap();
+    assert!(proof.siblings.is_empty());
+    assert!(proof.verify(&single.output[1].script_pubkey, &root));
+    assert_eq!(coinbase_message_root(&coinbase(vec![])), None);
+}
+
+#[test]
+fn bmm_request_pool() {
+    use bip300301_messages::{
+        bitcoin::{hashes::Hash, Amount, BlockHash, Txid},
+        policy::{BmmPolicyError, BmmRequestPool},
+    };
+
+    let tip = BlockHash::from_byte_array([0x01; 32]);
+    let next_tip = BlockHash::from_byte_array([0x02; 32]);
+    let txid = |n: u8| Txid::from_byte_array([n; 32]);
+    let request = |sidechain_number: u8, tip: BlockHash, n: u8| M8BmmRequest {
+        sidechain_number: SidechainNumber(sidechain_number),
+        sidechain_block_hash: [n; 32],
+        prev_mainchain_block_hash: tip.to_byte_array(),
+    };
+    let mut pool = BmmRequestPool::new(tip);
+
+    // Only requests on the tip are admitted.
+    assert_eq!(
+        pool.insert(txid(9), request(1, next_tip, 9)),
+        Err(BmmPolicyError::NotOnTip {
+            sidechain_number: SidechainNumber(1),
+            prev_mainchain_block_hash: next_tip,
+            tip,
+        })
+    );
+
+    // One request per sidechain.
+    pool.insert(txid(1), request(1, tip, 1)).unwrap();
+    pool.insert(txid(2), request(2, tip, 2)).unwrap();
+    assert_eq!(
+        pool.insert(txid(3), request(1, tip, 3)),
+        Err(BmmPolicyError::SidechainTaken {
+            sidechain_number: SidechainNumber(1),
+            txid: txid(1),
+        })
+    );
+    assert_eq!(pool.requests().count(), 2);
+    assert_eq!(pool.remove(txid(1)), Some(request(1, tip, 1)));
+    assert_eq!(pool.remove(txid(1)), None);
+    pool.insert(txid(3), request(1, tip, 3)).unwrap();
+    assert_eq!(pool.request(SidechainNumber(1)).unwrap().0, txid(3));
+
+    // The highest bid wins; ties keep the first.
+    assert_eq!(
+        pool.offer(txid(4), request(1, tip, 4), Amount::from_sat(100)),
+        Ok(Some(txid(3)))
+    );
+    assert_eq!(
+        pool.offer(txid(5), request(1, tip, 5), Amount::from_sat(100)),
+        Err(BmmPolicyError::Outbid {
+            sidechain_number: SidechainNumber(1),
+            bid: Amount::from_sat(100),
+            txid: txid(4),
+            current: Amount::from_sat(100),
+        })
+    );
+    assert!(pool
+        .offer(txid(6), request(1, tip, 6), Amount::from_sat(50))
+        .is_err());
+    assert_eq!(
+        pool.offer(txid(7), request(1, tip, 7), Amount::from_sat(101)),
+        Ok(Some(txid(4)))
+    );
+    assert_eq!(
+        pool.offer(txid(8), request(3, tip, 8), Amount::from_sat(1)),
+        Ok(None)
+    );
+    assert!(pool
+        .offer(txid(9), request(4, next_tip, 9), Amount::from_sat(1_000))
+        .is_err());
+    assert_eq!(
+        pool.request(SidechainNumber(1)),
+        Some((txid(7), &request(1, tip, 7)))
+    );
+    assert_eq!(pool.bid(SidechainNumber(1)), Some(Amount::from_sat(101)));
+    assert_eq!(pool.bid(SidechainNumber(2)), Some(Amount::ZERO));
+    assert_eq!(pool.bid(SidechainNumber(4)), None);
+
+    // Staying on the tip keeps everything; moving evicts everything.
+    assert!(pool.set_tip(tip).is_empty());
+    assert_eq!(pool.requests().count(), 3);
+    assert_eq!(pool.set_tip(next_tip), vec![txid(7), txid(2), txid(8)]);
+    assert_eq!(pool.tip(), next_tip);
+    assert_eq!(pool.requests().count(), 0);
+    pool.insert(txid(1), request(1, next_tip, 1)).unwrap();
+}
+
+#[test]
+fn lazy_message_decodes_once_on_access() {
+    use bip300301_messages::LazyMessage;
+
+    let message = CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(2),
+        data_hash: [0xAB; 32],
+    };
+    let script = message.to_script().unwrap();
+    let lazy = LazyMessage::new(script.clone());
+    assert!(!lazy.is_parsed());
+    assert_eq!(lazy.script(), script.as_script());
+    assert!(!lazy.is_parsed());
+    assert_eq!(lazy.message(), Some(&message));
+    assert!(lazy.is_parsed());
+    assert!(std::ptr::eq(
+        lazy.message().unwrap(),
+        lazy.message().unwrap()
+    ));
+    assert_eq!(lazy.into_script(), script);
+
+    // Scripts that aren't messages, or are corrupt ones, decode to nothing.
+    for script in [
+        ScriptBuf::new(),
+        ScriptBuf::from_bytes(vec![0x6a, 0x01, 0x02]),
+        ScriptBuf::from_bytes(script.as_bytes()[..10].to_vec()),
+    ] {
+        let lazy = LazyMessage::new(script);
+        assert_eq!(lazy.message(), None);
+        assert!(lazy.is_parsed());
+    }
+}
+
+#[test]
+fn sidechain_set() {
+    use bip300301_messages::SidechainSet;
+
+    let mut set = SidechainSet::new();
+    assert!(set.is_empty());
+    assert_eq!(set.len(), 0);
+    // The edges of each 64-bit word.
+    let members = [0, 63, 64, 127, 128, 191, 192, 255].map(SidechainNumber);
+    for sidechain_number in members {
+        assert!(!set.contains(sidechain_number));
+        assert!(set.insert(sidechain_number));
+        assert!(!set.insert(sidechain_number));
+        assert!(set.contains(sidechain_number));
+    }
+    assert_eq!(set.len(), members.len());
+    assert!(set.iter().eq(members));
+    for neighbour in [1, 62, 65, 126, 129, 190, 193, 254].map(SidechainNumber) {
+        assert!(!set.contains(neighbour));
+    }
+
+    assert!(set.remove(SidechainNumber(64)));
+    assert!(!set.remove(SidechainNumber(64)));
+    assert_eq!(set.len(), members.len() - 1);
+    assert!(!set
+        .iter()
+        .any(|sidechain_number| sidechain_number == SidechainNumber(64)));
+
+    // Insertion order and duplicates don't matter.
+    let reversed: SidechainSet = members.iter().rev().chain(&members).copied().collect();
+    let mut extended = SidechainSet::default();
+    extended.extend(members);
+    assert_eq!(reversed, extended);
+    assert_ne!(reversed, set);
+
+    let full = SidechainSet::full();
+    assert_eq!(full.len(), 256);
+    assert!(full.iter().eq(SidechainNumber::all()));
+    for sidechain_number in SidechainNumber::all() {
+        assert!(full.contains(sidechain_number));
+    }
+}
+
+#[test]
+fn activity_filter() {
+    use bip300301_messages::{
+        bitcoin::{absolute::LockTime, transaction, Amount, Transaction, TxOut},
+        create_op_drivechain_script,
+        scenario::ChainBuilder,
+        ActivityFilter, CoinbaseBuilder, MessageKind,
+    };
+
+    let tx = |outputs: Vec<ScriptBuf>| Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![],
+        output: outputs
+            .into_iter()
+            .map(|script_pubkey| TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey,
+            })
+            .collect(),
+    };
+    let mut chain = ChainBuilder::new();
+    chain.mine_empty();
+    chain.mine(
+        CoinbaseBuilder::new()
+            .propose_sidechain(SidechainNumber(3), vec![0x01])
+            .bmm_accept(SidechainNumber(200), [0x02; 32])
+            .build()
+            .unwrap(),
+        vec![tx(vec![
+            create_op_drivechain_script(SidechainNumber(64)),
+            M8BmmRequest {
+                sidechain_number: SidechainNumber(9),
+                sidechain_block_hash: [0x03; 32],
+                prev_mainchain_block_hash: [0x04; 32],
+            }
+            .into(),
+        ])],
+    );
+    chain.mine(
+        CoinbaseBuilder::new()
+            .ack_bundles(M4AckBundles::RepeatPrevious)
+            .build()
+            .unwrap(),
+        vec![],
+    );
+    let blocks = chain.into_blocks();
+
+    let empty = ActivityFilter::from_block(&blocks[0]);
+    assert!(empty.is_empty());
+    assert_eq!(empty.kinds().count(), 0);
+    assert!(!empty.may_touch(SidechainNumber(0)));
+    assert_eq!(empty, ActivityFilter::default());
+
+    let filter = ActivityFilter::from_block(&blocks[1]);
+    assert!(!filter.is_empty());
+    assert!(filter.has_treasury_output());
+    assert_eq!(
+        filter.kinds().collect::<Vec<_>>(),
+        [
+            MessageKind::M1ProposeSidechain,
+            MessageKind::M7BmmAccept,
+            MessageKind::M8BmmRequest
+        ]
+    );
+    let touched: Vec<_> = SidechainNumber::all()
+        .filter(|sidechain_number| filter.may_touch(*sidechain_number))
+        .collect();
+    assert_eq!(touched, [3, 9, 64, 200].map(SidechainNumber));
+
+    // An M4 votes on every sidechain, but names none.
+    let m4 = ActivityFilter::from_block(&blocks[2]);
+    assert!(!m4.has_treasury_output());
+    assert!(m4.contains_kind(MessageKind::M4AckBundles));
+    assert!(SidechainNumber::all().all(|sidechain_number| m4.may_touch(sidechain_number)));
+
+    for filter in [empty, filter, m4] {
+        let bytes = filter.to_bytes();
+        assert_eq!(bytes.len(), ActivityFilter::ENCODED_LEN);
+        assert_eq!(ActivityFilter::from_bytes(&bytes), filter);
+    }
+    assert_eq!(filter.to_bytes()[0] & 0x80, 0x80);
+    assert_eq!(filter.to_bytes()[1 + 64 / 8], 1);
+}
+
+#[cfg(feature = "signed")]
+#[test]
+fn signed_messages() {
+    use bip300301_messages::{
+        bitcoin::secp256k1::{Keypair, Secp256k1},
+        signed::{SignedMessage, SignedMessageError, SIGNED_TRAILER_LEN},
+        MessageKind,
+    };
+
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_seckey_slice(&secp, &[0x01; 32]).unwrap();
+    let other = Keypair::from_seckey_slice(&secp, &[0x02; 32]).unwrap();
+    let proposal = CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(1),
+        data: vec![0x01, 0x02, 0x03].into(),
+    };
+    let bundle = CoinbaseMessage::M3ProposeBundle {
+        sidechain_number: SidechainNumber(1),
+        bundle_txid: [0xCD; 32],
+    };
+
+    for message in [proposal.clone(), bundle] {
+        let signed = SignedMessage::sign(&secp, message.clone(), &keypair).unwrap();
+        signed.verify(&secp).unwrap();
+        let bytes = signed.to_bytes().unwrap();
+        let body = message.to_script().unwrap();
+        assert_eq!(bytes.len(), body.len() + SIGNED_TRAILER_LEN);
+        assert_eq!(&bytes[..body.len()], body.as_bytes());
+        let decoded = SignedMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, signed);
+        decoded.verify(&secp).unwrap();
+
+        // Another key, or a changed body, doesn't verify.
+        let forged = SignedMessage {
+            public_key: other.x_only_public_key().0,
+            ..signed.clone()
+        };
+        assert_eq!(forged.verify(&secp), Err(SignedMessageError::BadSignature));
+        let mut tampered = bytes.clone();
+        tampered[body.len() - 1] ^= 1;
+        let tampered = SignedMessage::from_bytes(&tampered).unwrap();
+        assert_eq!(
+            tampered.verify(&secp),
+            Err(SignedMessageError::BadSignature)
+        );
+    }
+
+    let ack = CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(1),
+        data_hash: [0xAB; 32],
+    };
+    assert_eq!(
+        SignedMessage::sign(&secp, ack.clone(), &keypair),
+        Err(SignedMessageError::NotSignable(MessageKind::M2AckSidechain))
+    );
+    // A valid trailer on a message that can't be signed.
+    let signed = SignedMessage::sign(&secp, proposal, &keypair)
+        .unwrap()
+        .to_bytes()
+        .unwrap();
+    let trailer = &signed[signed.len() - SIGNED_TRAILER_LEN..];
+    let mut bytes = ack.to_script().unwrap().into_bytes();
+    bytes.extend_from_slice(trailer);
+    assert_eq!(
+        SignedMessage::from_bytes(&bytes),
+        Err(SignedMessageError::NotSignable(MessageKind::M2AckSidechain))
+    );
+
+    assert_eq!(
+        SignedMessage::from_bytes(&[0; SIGNED_TRAILER_LEN - 1]),
+        Err(SignedMessageError::MissingTrailer)
+    );
+    let mut bad_key = signed.clone();
+    let key_start = signed.len() - SIGNED_TRAILER_LEN;
+    bad_key[key_start..key_start + 32].fill(0xFF);
+    assert_eq!(
+        SignedMessage::from_bytes(&bad_key),
+        Err(SignedMessageError::InvalidPublicKey)
+    );
+    assert_eq!(
+        SignedMessage::from_bytes(trailer),
+        Err(SignedMessageError::InvalidBody)
+    );
+}
+
+#[test]
+fn extension_registry() {
+    use bip300301_messages::{
+        derive_tag,
+        extension::{parse_with_extensions, AnyMessage, ExtensionError, ExtensionRegistry},
+        SpecVersion, M2_ACK_SIDECHAIN_TAG,
+    };
+
+    #[derive(Debug, PartialEq)]
+    struct Beacon(u32);
+
+    let tag = derive_tag("example/beacon");
+    let mut registry = ExtensionRegistry::new();
+    registry
+        .register(
+            tag,
+            "beacon",
+            |payload: &[u8]| Some(Beacon(u32::from_le_bytes(payload.try_into().ok()?))),
+            |beacon: &Beacon| beacon.0.to_le_bytes().to_vec(),
+        )
+        .unwrap();
+    assert!(registry.contains(tag));
+    assert_eq!(registry.name(tag), Some("beacon"));
+    assert_eq!(
+        registry.register(tag, "again", |_| Some(()), |_| vec![]),
+        Err(ExtensionError::AlreadyRegistered(tag))
+    );
+    let standard: [u8; 4] = M2_ACK_SIDECHAIN_TAG.try_into().unwrap();
+    assert_eq!(
+        registry.register(standard, "m2", |_| Some(()), |_| vec![]),
+        Err(ExtensionError::StandardTag(standard))
+    );
+    assert!(!registry.contains(standard));
+
+    let script = registry.encode(tag, &Beacon(7)).unwrap();
+    assert_eq!(script.as_bytes()[0], 0x6a);
+    assert_eq!(script.as_bytes()[1..5], tag);
+    let message = registry.decode(&script).unwrap();
+    assert_eq!(message.tag, tag);
+    assert_eq!(message.downcast_ref::<Beacon>(), Some(&Beacon(7)));
+    assert_eq!(message.downcast_ref::<u32>(), None);
+    let message = message.downcast::<u32>().unwrap_err();
+    assert_eq!(message.downcast::<Beacon>().unwrap(), Beacon(7));
+
+    // The wrong type, an unknown tag, or a payload the decoder rejects.
+    assert_eq!(registry.encode(tag, &7u32), None);
+    assert_eq!(
+        registry.encode(derive_tag("example/other"), &Beacon(7)),
+        None
+    );
+    let mut truncated = script.clone().into_bytes();
+    truncated.pop();
+    assert!(registry.decode(&ScriptBuf::from_bytes(truncated)).is_none());
+    let mut no_op_return = script.clone().into_bytes();
+    no_op_return[0] = 0x00;
+    assert!(registry
+        .decode(&ScriptBuf::from_bytes(no_op_return))
+        .is_none());
+
+    // Standard messages take precedence, and the registry only sees the rest.
+    let ack = CoinbaseMessage::M2AckSidechain {
+        sidechain_number: SidechainNumber(1),
+        data_hash: [0xAB; 32],
+    };
+    let version = SpecVersion::default();
+    assert!(matches!(
+        parse_with_extensions(&ack.to_script().unwrap(), version, &registry),
+        Some(AnyMessage::Standard(message)) if message == ack
+    ));
+    assert!(matches!(
+        parse_with_extensions(&script, version, &registry),
+        Some(AnyMessage::Extension(message)) if message.downcast_ref() == Some(&Beacon(7))
+    ));
+    assert!(parse_with_extensions(&script, version, &ExtensionRegistry::new()).is_none());
+}
+
+#[test]
+fn declaration_decoders() {
+    use bip300301_messages::{
+        decode_proposal, DeclarationDecoder, DeclarationError, DefaultDeclarationDecoder,
+        SidechainDeclaration, SidechainProposalId,
+    };
+
+    // A sidechain whose version 1 payload is just a name.
+    struct NameDecoder;
+
+    impl DeclarationDecoder for NameDecoder {
+        type Declaration = String;
+        type Error = DeclarationError;
+
+        fn decode(
+            &self,
+            _sidechain_number: SidechainNumber,
+            version: u8,
+            body: &[u8],
+        ) -> Result<String, DeclarationError> {
+            if version != 1 {
+                return Err(DeclarationError::UnsupportedVersion(version));
+            }
+            String::from_utf8(body.to_vec())
+                .map_err(|_| DeclarationError::InvalidUtf8 { field: "name" })
+        }
+    }
+
+    let m1 = |data: Vec<u8>| CoinbaseMessage::M1ProposeSidechain {
+        sidechain_number: SidechainNumber(4),
+        data: data.into(),
+    };
+    let named = m1(b"\x01thunder".to_vec());
+    let decoded = decode_proposal(&NameDecoder, &named).unwrap().unwrap();
+    assert_eq!(decoded.sidechain_number, SidechainNumber(4));
+    assert_eq!(decoded.declaration, "thunder");
+    assert_eq!(
+        decoded.proposal_id,
+        SidechainProposalId::from_data(b"\x01thunder")
+    );
+    assert_eq!(
+        decode_proposal(&NameDecoder, &m1(vec![0x01, 0xFF])),
+        Some(Err(DeclarationError::InvalidUtf8 { field: "name" }))
+    );
+    assert_eq!(
+        decode_proposal(&NameDecoder, &m1(vec![])),
+        Some(Err(DeclarationError::Empty))
+    );
+    assert_eq!(
+        decode_proposal(
+            &NameDecoder,
+            &CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: SidechainNumber(4),
+                bundle_txid: [0; 32],
+            }
+        ),
+        None
+    );
+
+    // The default decoder reads version 0 and nothing else.
+    let declaration = SidechainDeclaration {
+        sidechain_number: SidechainNumber(4),
+        title: "Thunder".to_owned(),
+        description: "A sidechain".to_owned(),
+        hash_id_1: [0x01; 32],
+        hash_id_2: [0x02; 20],
+    };
+    let data = declaration.to_bytes().unwrap();
+    let decoded = decode_proposal(&DefaultDeclarationDecoder, &m1(data.clone()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded.declaration, declaration);
+    assert_eq!(decoded.proposal_id, declaration.proposal_id().unwrap());
+    assert_eq!(
+        m1(data.clone()).sidechain_declaration(),
+        Some(Ok(declaration))
+    );
+    assert_eq!(
+        decode_proposal(&DefaultDeclarationDecoder, &named),
+        Some(Err(DeclarationError::UnsupportedVersion(1)))
+    );
+    assert_eq!(
+        decode_proposal(&NameDecoder, &m1(data.clone())),
+        Some(Err(DeclarationError::UnsupportedVersion(0)))
+    );
+    let mut truncated = data.clone();
+    truncated.pop();
+    assert_eq!(
+        decode_proposal(&DefaultDeclarationDecoder, &m1(truncated)),
+        Some(Err(DeclarationError::Truncated {
+            offset: data.len() - 20
+        }))
+    );
+    let mut trailing = data;
+    trailing.push(0);
+    assert_eq!(
+        decode_proposal(&DefaultDeclarationDecoder, &m1(trailing)),
+        Some(Err(DeclarationError::TrailingBytes { len: 1 }))
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_json_round_trip() {
+    use bip300301_messages::Votes;
+
+    for message in sample_messages() {
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            serde_json::from_str::<CoinbaseMessage>(&json).unwrap(),
+            message,
+            "{json}"
+        );
+    }
+    let request = sample_request();
+    let json = serde_json::to_string(&request).unwrap();
+    assert_eq!(
+        serde_json::from_str::<M8BmmRequest>(&json).unwrap(),
+        request
+    );
+
+    // Byte strings are hex, sidechain numbers plain integers and votes a list.
+    assert_eq!(
+        serde_json::to_value(&sample_messages()[0]).unwrap(),
+        serde_json::json!({
+            "M1ProposeSidechain": { "sidechain_number": 1, "data": "00010203" }
+        })
+    );
+    assert_eq!(
+        serde_json::to_value(&sample_messages()[4]).unwrap(),
+        serde_json::json!({
+            "M4AckBundles": {
+                "OneByte": { "upvotes": [{ "Upvote": 0 }, "Abstain", "Alarm"] }
+            }
+        })
+    );
+    let votes: Votes = vec![Vote::Upvote(2)].into();
+    assert_eq!(serde_json::to_string(&votes).unwrap(), r#"[{"Upvote":2}]"#);
+
+    // Hashes have to be 32 bytes of valid hex.
+    for data_hash in ["ab".repeat(31), "zz".repeat(32)] {
+        let json =
+            format!(r#"{{"M2AckSidechain":{{"sidechain_number":2,"data_hash":"{data_hash}"}}}}"#);
+        assert!(serde_json::from_str::<CoinbaseMessage>(&json).is_err());
+    }
+}
+
+#[cfg(feature = "borsh")]
+#[test]
+fn borsh_round_trip() {
+    for message in sample_messages() {
+        let bytes = borsh::to_vec(&message).unwrap();
+        assert_eq!(
+            borsh::from_slice::<CoinbaseMessage>(&bytes).unwrap(),
+            message
+        );
+        // Leftover bytes are an error, not ignored.
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(borsh::from_slice::<CoinbaseMessage>(&trailing).is_err());
+    }
+    let request = sample_request();
+    let bytes = borsh::to_vec(&request).unwrap();
+    assert_eq!(borsh::from_slice::<M8BmmRequest>(&bytes).unwrap(), request);
+
+    // The variant index, then the fields in order, with byte strings prefixed by
+    // their length and hashes without.
+    let mut m1 = vec![0, 1];
+    m1.extend_from_slice(&4u32.to_le_bytes());
+    m1.extend_from_slice(&[0, 1, 2, 3]);
+    assert_eq!(borsh::to_vec(&sample_messages()[0]).unwrap(), m1);
+    let mut m2 = vec![1, 2];
+    m2.extend_from_slice(&[0xAB; 32]);
+    assert_eq!(borsh::to_vec(&sample_messages()[1]).unwrap(), m2);
+}
+
+#[test]
+fn consensus_encoding() {
+    use bip300301_messages::bitcoin::consensus::{deserialize_partial, encode::Error};
+
+    // A message is its script with a compact size length, so it sits next to
+    // other consensus types in a stream.
+    let mut stream = vec![];
+    for message in sample_messages() {
+        let encoded = serialize(&message);
+        assert_eq!(encoded, serialize(&message.to_script().unwrap()));
+        stream.extend_from_slice(&encoded);
+    }
+    stream.extend_from_slice(&serialize(&sample_request()));
+    stream.extend_from_slice(&serialize(&7u32));
+    let mut rest = &stream[..];
+    for message in sample_messages() {
+        let (decoded, read) = deserialize_partial::<CoinbaseMessage>(rest).unwrap();
+        assert_eq!(decoded, message);
+        rest = &rest[read..];
+    }
+    let (request, read) = deserialize_partial::<M8BmmRequest>(rest).unwrap();
+    assert_eq!(request, sample_request());
+    assert_eq!(deserialize::<u32>(&rest[read..]).unwrap(), 7);
+
+    // The script inside has to be exactly one message.
+    let mut script = sample_messages()[1].to_script().unwrap().into_bytes();
+    script.push(0x00);
+    assert!(matches!(
+        deserialize::<CoinbaseMessage>(&serialize(&ScriptBuf::from_bytes(script))),
+        Err(Error::ParseFailed(_))
+    ));
+    assert!(matches!(
+        deserialize::<CoinbaseMessage>(&serialize(&ScriptBuf::new())),
+        Err(Error::ParseFailed(_))
+    ));
+    assert!(matches!(
+        deserialize::<M8BmmRequest>(&serialize(&sample_messages()[1])),
+        Err(Error::ParseFailed(_))
+    ));
+    // A length running past the end of the data.
+    let encoded = serialize(&sample_messages()[1]);
+    assert!(deserialize::<CoinbaseMessage>(&encoded[..encoded.len() - 1]).is_err());
+}
+
+/// Whether `value` matches `schema`, for the subset of JSON Schema that
+/// `schemars` generates for this crate's types.
+#[cfg(feature = "schemars")]
+fn matches_schema(
+    value: &serde_json::Value,
+    schema: &serde_json::Value,
+    root: &serde_json::Value,
+) -> bool {
+    use serde_json::Value;
+
+    if let Some(Value::String(reference)) = schema.get("$ref") {
+        let name = reference.trim_start_matches("#/definitions/");
+        return matches_schema(value, &root["definitions"][name], root);
+    }
+    if let Some(Value::Array(variants)) = schema.get("oneOf") {
+        return variants
+            .iter()
+            .filter(|variant| matches_schema(value, variant, root))
+            .count()
+            == 1;
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        return options.contains(value);
+    }
+    match schema.get("type").and_then(Value::as_str) {
+        Some("string") => value.is_string(),
+        Some("integer") => {
+            let max = match schema.get("format").and_then(Value::as_str) {
+                Some("uint8") => u64::from(u8::MAX),
+                Some("uint16") => u64::from(u16::MAX),
+                _ => u64::MAX,
+            };
+            value.as_u64().is_some_and(|value| value <= max)
+        }
+        Some("array") => value.as_array().is_some_and(|items| {
+            items
+                .iter()
+                .all(|item| matches_schema(item, &schema["items"], root))
+        }),
+        Some("object") => {
+            let Some(object) = value.as_object() else {
+                return false;
+            };
+            let properties = schema["properties"].as_object().unwrap();
+            let required = schema["required"].as_array().unwrap();
+            required
+                .iter()
+                .all(|name| object.contains_key(name.as_str().unwrap()))
+                && object
+                    .iter()
+                    .all(|(name, value)| match properties.get(name) {
+                        Some(property) => matches_schema(value, property, root),
+                        None => schema.get("additionalProperties") != Some(&Value::Bool(false)),
+                    })
+        }
+        _ => true,
+    }
+}
+
+#[cfg(feature = "schemars")]
+#[test]
+fn json_schema() {
+    let schema = serde_json::to_value(schemars::schema_for!(CoinbaseMessage)).unwrap();
+    for message in sample_messages() {
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(matches_schema(&json, &schema, &schema), "{json}");
+    }
+    let request_schema = serde_json::to_value(schemars::schema_for!(M8BmmRequest)).unwrap();
+    let request = serde_json::to_value(sample_request()).unwrap();
+    assert!(matches_schema(&request, &request_schema, &request_schema));
+
+    // The schema is tight enough to reject what serde would.
+    for invalid in [
+        serde_json::json!({ "M2AckSidechain": { "sidechain_number": 256, "data_hash": "00" } }),
+        serde_json::json!({ "M2AckSidechain": { "data_hash": "00" } }),
+        serde_json::json!({ "M2AckSidechain": { "sidechain_number": 1, "data_hash": [0] } }),
+        serde_json::json!({ "M5Deposit": {} }),
+        serde_json::json!({ "M4AckBundles": { "OneByte": { "upvotes": ["Maybe"] } } }),
+        serde_json::json!({ "M4AckBundles": { "TwoBytes": { "upvotes": [{ "Upvote": 70000 }] } } }),
+        serde_json::json!("RepeatPrevious"),
+    ] {
+        assert!(!matches_schema(&invalid, &schema, &schema), "{invalid}");
+        assert!(serde_json::from_value::<CoinbaseMessage>(invalid).is_err());
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_messages_round_trip() {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    // Deterministic pseudo-random input, so a failure reproduces.
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut input = vec![0u8; 1 << 16];
+    for byte in &mut input {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *byte = state as u8;
+    }
+    let mut kinds = std::collections::BTreeSet::new();
+    for start in (0..input.len() - 512).step_by(97) {
+        let mut u = Unstructured::new(&input[start..start + 512]);
+        let message = CoinbaseMessage::arbitrary(&mut u).unwrap();
+        kinds.insert(format!("{:?}", message.kind()));
+        round_trip(message);
+        let request = M8BmmRequest::arbitrary(&mut u).unwrap();
+        let script: ScriptBuf = request.clone().into();
+        assert_eq!(parse_m8_bmm_request(script.as_bytes()).unwrap().1, request);
+    }
+    assert_eq!(kinds.len(), 5, "{kinds:?}");
+
+    // Even the shortest input gives a message that encodes.
+    round_trip(CoinbaseMessage::arbitrary(&mut Unstructured::new(&[])).unwrap());
+}
+
+#[cfg(feature = "proptest")]
+proptest::proptest! {
+    #[test]
+    fn proptest_messages_round_trip(
+        message in bip300301_messages::strategies::coinbase_message(),
+    ) {
+        round_trip(message);
+    }
+
+    #[test]
+    fn proptest_scripts_parse_back(
+        script in bip300301_messages::strategies::coinbase_script(),
+    ) {
+        let message = CoinbaseMessage::try_from(script.as_script()).unwrap();
+        proptest::prop_assert_eq!(message.to_script().unwrap(), script);
+    }
+
+    #[test]
+    fn proptest_requests_round_trip(
+        request in bip300301_messages::strategies::m8_bmm_request(),
+    ) {
+        let script: ScriptBuf = request.clone().into();
+        proptest::prop_assert_eq!(parse_m8_bmm_request(script.as_bytes()).unwrap().1, request);
+    }
+
+    /// Whatever a parser makes of a broken script, it doesn't panic, and what it
+    /// accepts encodes back to the bytes it read.
+    #[test]
+    fn proptest_adversarial_scripts(
+        script in bip300301_messages::strategies::adversarial_script(),
+    ) {
+        if let Ok((rest, message)) = parse_coinbase_script(&script) {
+            let read = script.len() - rest.len();
+            let encoded = message.to_script().unwrap();
+            proptest::prop_assert_eq!(encoded.as_bytes(), &script.as_bytes()[..read]);
+        }
+        let _ = parse_m8_bmm_request(script.as_bytes());
+    }
+}
+
+#[cfg(feature = "proto")]
+#[test]
+fn proto_round_trip() {
+    use bip300301_messages::proto::{self, coinbase_message::Message, ProtoError};
+    use prost::Message as _;
+
+    for message in sample_messages() {
+        let encoded = proto::CoinbaseMessage::from(message.clone()).encode_to_vec();
+        let decoded = proto::CoinbaseMessage::decode(encoded.as_slice()).unwrap();
+        assert_eq!(CoinbaseMessage::try_from(decoded), Ok(message));
+    }
+    let encoded = proto::M8BmmRequest::from(sample_request()).encode_to_vec();
+    let decoded = proto::M8BmmRequest::decode(encoded.as_slice()).unwrap();
+    assert_eq!(M8BmmRequest::try_from(decoded), Ok(sample_request()));
+
+    // The wire format other implementations read: the M2 in field 2, holding
+    // the sidechain number in field 1 and the hash in field 2.
+    let mut m2 = vec![0x12, 0x24, 0x08, 0x02, 0x12, 0x20];
+    m2.extend_from_slice(&[0xAB; 32]);
+    assert_eq!(
+        proto::CoinbaseMessage::from(sample_messages()[1].clone()).encode_to_vec(),
+        m2
+    );
+    let m4 = proto::M4AckBundles::from(M4AckBundles::OneByte {
+        upvotes: vec![Vote::Upvote(3), Vote::Abstain, Vote::Alarm].into(),
+    });
+    assert_eq!(m4.kind, proto::M4Kind::OneByte as i32);
+    assert_eq!(m4.votes, [3, proto::ABSTAIN, proto::ALARM]);
+
+    let m2 = |sidechain_number, data_hash: Vec<u8>| proto::CoinbaseMessage {
+        message: Some(Message::M2AckSidechain(proto::M2AckSidechain {
+            sidechain_number,
+            data_hash,
+        })),
+    };
+    let m4 = |kind, votes| proto::CoinbaseMessage {
+        message: Some(Message::M4AckBundles(proto::M4AckBundles { kind, votes })),
+    };
+    for (message, err) in [
+        (
+            proto::CoinbaseMessage { message: None },
+            ProtoError::MissingMessage,
+        ),
+        (m2(256, vec![0; 32]), ProtoError::SidechainNumber(256)),
+        (m2(1, vec![0; 31]), ProtoError::HashLength { len: 31 }),
+        (m4(9, vec![]), ProtoError::M4Kind(9)),
+        (
+            m4(proto::M4Kind::TwoBytes as i32, vec![70_000]),
+            ProtoError::Vote(70_000),
+        ),
+    ] {
+        assert_eq!(CoinbaseMessage::try_from(message), Err(err));
+    }
+    assert_eq!(
+        M8BmmRequest::try_from(proto::M8BmmRequest {
+            sidechain_number: 1,
+            sidechain_block_hash: vec![0; 32],
+            prev_mainchain_block_hash: vec![],
+        }),
+        Err(ProtoError::HashLength { len: 0 })
+    );
+}
+
+/// Run a future that never waits, as the `MessageService` handlers don't.
+#[cfg(feature = "grpc")]
+fn ready<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Waker};
+
+    let mut future = std::pin::pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("handler waited"),
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_service() {
+    use bip300301_messages::{
+        bitcoin::{consensus, Amount, OutPoint, TxOut},
+        build_m6_withdrawal,
+        grpc::{
+            pb::{self, message_service_server::MessageService as _},
+            MessageService,
+        },
+        m6_to_id, proto, Ctip,
+    };
+    use tonic::{Code, Request};
+
+    let service = MessageService;
+    for message in sample_messages() {
+        let script = message.to_script().unwrap();
+        let decoded = ready(service.decode_coinbase_script(Request::new(
+            pb::DecodeCoinbaseScriptRequest {
+                script: script.to_bytes(),
+            },
+        )))
+        .unwrap()
+        .into_inner();
+        assert_eq!(decoded.message, Some(proto::CoinbaseMessage::from(message)));
+        let encoded = ready(service.encode_coinbase_message(Request::new(
+            pb::EncodeCoinbaseMessageRequest {
+                message: decoded.message,
+            },
+        )))
+        .unwrap()
+        .into_inner();
+        assert_eq!(encoded.script, script.into_bytes());
+    }
+
+    // Bad input is an invalid argument, never a panic.
+    let mut trailing = sample_messages()[1].to_script().unwrap().into_bytes();
+    trailing.push(0);
+    for script in [vec![], trailing] {
+        let status = ready(
+            service
+                .decode_coinbase_script(Request::new(pb::DecodeCoinbaseScriptRequest { script })),
+        )
+        .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+    let unencodable =
+        proto::CoinbaseMessage::from(CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+            upvotes: vec![Vote::Upvote(300)].into(),
+        }));
+    for message in [None, Some(unencodable)] {
+        let status =
+            ready(service.encode_coinbase_message(Request::new(
+                pb::EncodeCoinbaseMessageRequest { message },
+            )))
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    let ctip = Ctip {
+        outpoint: OutPoint::null(),
+        value: Amount::from_sat(100_000),
+    };
+    let payout = TxOut {
+        value: Amount::from_sat(40_000),
+        script_pubkey: ScriptBuf::new(),
+    };
+    let m6 = build_m6_withdrawal(
+        SidechainNumber(1),
+        &ctip,
+        vec![payout],
+        Amount::from_sat(1_000),
+    )
+    .unwrap();
+    let response = ready(service.compute_m6_id(Request::new(pb::ComputeM6IdRequest {
+        transaction: consensus::serialize(&m6),
+        previous_treasury_utxo_total: ctip.value.to_sat(),
+    })))
+    .unwrap()
+    .into_inner();
+    assert_eq!(
+        response.m6_id,
+        m6_to_id(&m6, ctip.value.to_sat()).unwrap().to_vec()
+    );
+    let status = ready(service.compute_m6_id(Request::new(pb::ComputeM6IdRequest {
+        transaction: consensus::serialize(&m6),
+        previous_treasury_utxo_total: 60_000,
+    })))
+    .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = ready(service.compute_m6_id(Request::new(pb::ComputeM6IdRequest {
+        transaction: vec![0x01],
+        previous_treasury_utxo_total: 0,
+    })))
+    .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}